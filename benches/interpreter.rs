@@ -0,0 +1,204 @@
+//! Compares the reference interpreter with the threaded code engine, and with the
+//! machine code compiled by the `jit` feature if enabled.
+//! Run with `cargo bench`, optionally followed by a filter on the benchmark names.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use bm::{Instruction, BM};
+
+/// Time spent measuring each engine on each benchmark.
+const MEASURE_TIME: Duration = Duration::from_secs(1);
+
+/// Count down from `n` to 0.
+fn countdown(n: i64) -> Vec<Instruction> {
+    vec![
+        Instruction::Push(n),
+        Instruction::Push(1),
+        Instruction::Minus,
+        Instruction::Dup(0),
+        Instruction::JumpIf(Some(1)),
+        Instruction::Halt,
+    ]
+}
+
+/// Sum of the squares of 1..=n, squaring in a subroutine.
+fn sum_of_squares(n: i64) -> Vec<Instruction> {
+    vec![
+        Instruction::Push(0),
+        Instruction::Push(n),
+        // loop: sum i
+        Instruction::Dup(0),
+        Instruction::Call(Some(12)),
+        Instruction::Rot,
+        Instruction::Plus,
+        Instruction::Swap(1),
+        Instruction::Push(1),
+        Instruction::Minus,
+        Instruction::Dup(0),
+        Instruction::JumpIf(Some(2)),
+        Instruction::Halt,
+        // square:
+        Instruction::Dup(0),
+        Instruction::Mult,
+        Instruction::Ret,
+    ]
+}
+
+/// Follow the Collatz sequence of every number from `n` down to 2, counting the steps.
+fn collatz(n: i64) -> Vec<Instruction> {
+    vec![
+        Instruction::Push(0),
+        Instruction::Push(n),
+        // outer: steps i
+        Instruction::Dup(0),
+        // inner: steps i x
+        Instruction::Dup(0),
+        Instruction::Push(1),
+        Instruction::Eq,
+        Instruction::JumpIf(Some(28)),
+        // Count the step: steps+1 i x
+        Instruction::Rot,
+        Instruction::Push(1),
+        Instruction::Plus,
+        Instruction::Rot,
+        Instruction::Rot,
+        // Check if x is even
+        Instruction::Dup(0),
+        Instruction::Push(2),
+        Instruction::Div,
+        Instruction::Push(2),
+        Instruction::Mult,
+        Instruction::Over,
+        Instruction::Eq,
+        Instruction::JumpIf(Some(25)),
+        // odd: 3x + 1
+        Instruction::Push(3),
+        Instruction::Mult,
+        Instruction::Push(1),
+        Instruction::Plus,
+        Instruction::Jump(Some(3)),
+        // even: x / 2
+        Instruction::Push(2),
+        Instruction::Div,
+        Instruction::Jump(Some(3)),
+        // done: steps i 1
+        Instruction::Drop,
+        Instruction::Push(1),
+        Instruction::Minus,
+        Instruction::Dup(0),
+        Instruction::Push(1),
+        Instruction::Gt,
+        Instruction::JumpIf(Some(2)),
+        Instruction::Halt,
+    ]
+}
+
+/// Run `f` on a fresh virtual machine until `MEASURE_TIME` is spent, returning the
+/// nanoseconds spent per executed instruction.
+fn measure<F>(program: &[Instruction], mut f: F) -> f64
+where
+    F: FnMut(&mut BM),
+{
+    let mut spent = Duration::ZERO;
+    let mut executed = 0;
+    while spent < MEASURE_TIME {
+        let mut bm: BM = Default::default();
+        bm.load_program_from_memory(program)
+            .expect("benchmark program should load");
+        let start = Instant::now();
+        f(&mut bm);
+        spent += start.elapsed();
+        assert!(bm.is_halted(), "benchmark program should halt");
+        executed += black_box(bm.executed());
+    }
+    spent.as_nanos() as f64 / executed as f64
+}
+
+/// Run `program` on every engine once, panicking unless they all end in the state the
+/// reference interpreter ends in. Timings of engines that disagree would mean nothing.
+fn assert_engines_agree(name: &str, program: &[Instruction]) {
+    let machine = || {
+        let mut bm: BM = Default::default();
+        bm.load_program_from_memory(program)
+            .expect("benchmark program should load");
+        bm
+    };
+    let mut reference = machine();
+    reference
+        .execute_program(None)
+        .expect("benchmark should run");
+    let expected = reference.snapshot().state;
+
+    let mut threaded = machine();
+    let code = threaded.compile().expect("benchmark program should verify");
+    threaded
+        .execute_compiled(&code, None)
+        .expect("benchmark should run");
+    assert_eq!(
+        threaded.snapshot().state,
+        expected,
+        "{}: threaded code disagrees with the interpreter",
+        name
+    );
+
+    #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+    {
+        let mut jit = machine();
+        let code = jit.compile_jit().expect("benchmark program should compile");
+        jit.execute_jit(&code, None).expect("benchmark should run");
+        assert_eq!(
+            jit.snapshot().state,
+            expected,
+            "{}: jit disagrees with the interpreter",
+            name
+        );
+    }
+}
+
+fn main() {
+    let filter = std::env::args().skip(1).find(|a| !a.starts_with('-'));
+    let benches = [
+        ("countdown", countdown(1_000_000)),
+        ("sum_of_squares", sum_of_squares(100_000)),
+        ("collatz", collatz(10_000)),
+    ];
+
+    print!(
+        "{:<16} {:>15} {:>15} {:>8}",
+        "benchmark", "interpreter", "threaded", "speedup"
+    );
+    #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+    print!(" {:>15} {:>8}", "jit", "speedup");
+    println!();
+    for (name, program) in &benches {
+        if filter.as_ref().is_some_and(|f| !name.contains(f.as_str())) {
+            continue;
+        }
+        assert_engines_agree(name, program);
+        let reference = measure(program, |bm| {
+            bm.execute_program(None).expect("benchmark should run");
+        });
+        let threaded = measure(program, |bm| {
+            let code = bm.compile().expect("benchmark program should verify");
+            bm.execute_compiled(&code, None)
+                .expect("benchmark should run");
+        });
+        print!(
+            "{:<16} {:>9.2} ns/op {:>9.2} ns/op {:>7.2}x",
+            name,
+            reference,
+            threaded,
+            reference / threaded
+        );
+        #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+        {
+            let jit = measure(program, |bm| {
+                let code = bm.compile_jit().expect("benchmark program should compile");
+                bm.execute_jit(&code, None).expect("benchmark should run");
+            });
+            print!(" {:>9.2} ns/op {:>7.2}x", jit, reference / jit);
+        }
+        println!();
+    }
+}
@@ -1,31 +1,80 @@
-use bm::{serialize_deserialize::BasmCtx, BM};
-use std::fs::File;
-static USAGE: &'static str = "Usage: ./basm <input_file>.basm <output_file>.bm";
+use bm::{serialize_deserialize::BasmCtx, BMBuilder, Error};
+use std::{fs::File, process};
+static USAGE: &str = "Usage: ./basm [-c] [-O] [-I <dir>]... [--program-capacity <n>] <input_file>.basm <output_file>.bm
+  -c  assemble into a relocatable object to be linked with bmld
+  -O  optimize the program
+  --program-capacity  maximum number of instructions, as given to bme";
 
 fn main() {
     let mut args = std::env::args();
     args.next().expect("Should work");
-    let input_file = File::options()
-        .read(true)
-        .open(
-            args.next()
-                .expect(format!("Expected Input File: \n{}", USAGE).as_str()),
-        )
-        .expect("Could not read input file.");
 
-    let mut bm: BM = Default::default();
     let mut ctx: BasmCtx = Default::default();
-    bm.program_from_asm(&input_file, &mut ctx);
+    let mut paths = Vec::new();
+    let mut optimize = false;
+    let mut builder = BMBuilder::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-c" => ctx.set_relocatable(true),
+            "-O" => optimize = true,
+            "-I" => ctx.add_include_path(
+                args.next()
+                    .unwrap_or_else(|| panic!("Expected a directory after -I: \n{}", USAGE)),
+            ),
+            dir if dir.starts_with("-I") => ctx.add_include_path(&dir[2..]),
+            "--program-capacity" => {
+                builder = builder.program_capacity(
+                    args.next().and_then(|n| n.parse().ok()).unwrap_or_else(|| {
+                        panic!("Expected a number after --program-capacity: \n{}", USAGE)
+                    }),
+                )
+            }
+            _ => paths.push(arg),
+        }
+    }
+    let mut paths = paths.into_iter();
+    let input_path = paths
+        .next()
+        .unwrap_or_else(|| panic!("Expected Input File: \n{}", USAGE));
+
+    let mut bm = builder.build();
+    match bm.program_from_asm_file(&input_path, &mut ctx) {
+        Ok(()) => {}
+        Err(Error::Assemble(diagnostics)) => {
+            let mut stderr = std::io::stderr();
+            for d in &diagnostics {
+                ctx.render_diagnostic(d, &mut stderr)
+                    .expect("Could not write to stderr");
+                eprintln!();
+            }
+            let errors = diagnostics.iter().filter(|d| d.is_error()).count();
+            eprintln!(
+                "basm: could not assemble {} due to {} error(s)",
+                input_path, errors
+            );
+            process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("basm: could not assemble {}: {}", input_path, e);
+            process::exit(1);
+        }
+    }
+
+    if optimize {
+        bm.optimize_program(&mut ctx);
+    }
 
     let output_file = File::options()
         .create(true)
         .write(true)
         .truncate(true)
         .open(
-            args.next()
-                .expect(format!("Expected Output File: \n{}", USAGE).as_str()),
+            paths
+                .next()
+                .unwrap_or_else(|| panic!("Expected Output File: \n{}", USAGE)),
         )
         .expect("Could not open or create output file");
 
-    bm.serialize_program_into(&output_file);
+    bm.serialize_program_with_ctx_into(&output_file, &ctx)
+        .expect("Could not write output file");
 }
@@ -1,7 +1,69 @@
-use bm::BM;
-use std::{fs::File, process};
+use bm::{
+    debugger::Debugger,
+    format::BmFile,
+    profiler::{ProfileFormat, Profiler},
+    snapshot::Snapshot,
+    stream::SharedInput,
+    trace::{TraceFormat, Tracer},
+    BMBuilder, WordFormat,
+};
+use std::{fs::File, io::Write, process};
 
-static USAGE: &'static str = "Usage: ./bme <input_file>.bm [-l <limit>]";
+static USAGE: &str = "Usage: ./bme (-i <input_file>.bm | --resume <snapshot>.bm) [-l <limit>] [-m] [-f int|float|hex] [--verify] [--threaded] [--jit] [--debug] [--trace <file> [--trace-format human|json]]
+  [--profile <file> [--profile-format table|folded]]
+  [--stack-capacity <n>] [--program-capacity <n>] [--memory-size <bytes>] [--call-depth <n>] [--budget <n>]
+  [--snapshot <file> [--snapshot-on exit,error,limit]]
+A resumed program keeps the limits it was snapshotted with, they can not be set along with --resume.
+--debug runs the program in the debugger, and can not be combined with --trace, --profile, --threaded, --jit or --snapshot.
+--threaded runs a verified program on the faster threaded code engine, and can not be combined with --trace or --profile.
+--jit compiles the program to machine code, bme has to be built with the jit feature. It can not be combined with --trace, --profile or --threaded.
+--profile counts the instructions executed by the interpreter, and can not be combined with --trace, --threaded or --jit.";
+
+/// Execute the program compiled to machine code.
+#[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+fn execute_jit(bm: &mut bm::BM, limit: Option<usize>) -> Result<(), bm::Error> {
+    let code = bm.compile_jit()?;
+    bm.execute_jit(&code, limit)
+}
+
+#[cfg(not(all(feature = "jit", target_arch = "x86_64", target_os = "linux")))]
+fn execute_jit(_: &mut bm::BM, _: Option<usize>) -> Result<(), bm::Error> {
+    eprintln!("--jit needs bme built with the jit feature on x86-64 Linux");
+    process::exit(1);
+}
+
+/// When execution stopped, to decide whether a snapshot is written.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Stop {
+    /// The program halted
+    Exit,
+    /// The program failed
+    Error,
+    /// The instruction limit given with -l was reached
+    Limit,
+}
+
+/// Parse the comma separated list of events following --snapshot-on.
+fn parse_stops(value: Option<String>) -> Vec<Stop> {
+    value
+        .unwrap_or_else(|| panic!("Expected events after --snapshot-on\n {}", USAGE))
+        .split(',')
+        .map(|event| match event {
+            "exit" => Stop::Exit,
+            "error" => Stop::Error,
+            "limit" => Stop::Limit,
+            _ => panic!("snapshot events must be exit, error or limit\n {}", USAGE),
+        })
+        .collect()
+}
+
+/// Parse the unsigned integer following `flag`.
+fn parse_count(flag: &str, value: Option<String>) -> usize {
+    value
+        .unwrap_or_else(|| panic!("Expected a number after {}\n {}", flag, USAGE))
+        .parse::<usize>()
+        .unwrap_or_else(|_| panic!("{} must be an usigned integer\n {}", flag, USAGE))
+}
 
 fn main() {
     let mut args = std::env::args();
@@ -9,6 +71,22 @@ fn main() {
 
     let mut input_file = None;
     let mut limit = None;
+    let mut dump_memory = false;
+    let mut stack_format = WordFormat::Int;
+    let mut debug = false;
+    let mut verify = false;
+    let mut threaded = false;
+    let mut jit = false;
+    let mut trace_file = None;
+    let mut trace_format = TraceFormat::Human;
+    let mut profile_file = None;
+    let mut profile_format = ProfileFormat::Table;
+    let mut builder = BMBuilder::new();
+    // Last flag setting a limit, a resumed program keeps the limits of its snapshot
+    let mut limit_flag = None;
+    let mut resume = false;
+    let mut snapshot_file = None;
+    let mut snapshot_on = vec![Stop::Exit, Stop::Error, Stop::Limit];
 
     // parsing flag
     while args.len() != 0 {
@@ -17,20 +95,92 @@ fn main() {
                 input_file = Some(
                     File::options()
                         .read(true)
-                        .open(args.next().expect(
-                            format!("Expected Input File with flag -i: \n{}", USAGE).as_str(),
-                        ))
+                        .open(args.next().unwrap_or_else(|| {
+                            panic!("Expected Input File with flag -i: \n{}", USAGE)
+                        }))
                         .expect("Could not read input file."),
                 );
             }
+            Some(l) if l == "--resume" => {
+                resume = true;
+                input_file = Some(
+                    File::open(args.next().unwrap_or_else(|| {
+                        panic!("Expected Snapshot File with flag --resume: \n{}", USAGE)
+                    }))
+                    .expect("Could not read snapshot file."),
+                );
+            }
+            Some(l) if l == "--snapshot" => {
+                snapshot_file = Some(args.next().unwrap_or_else(|| {
+                    panic!("Expected Snapshot File with flag --snapshot: \n{}", USAGE)
+                }));
+            }
+            Some(l) if l == "--snapshot-on" => snapshot_on = parse_stops(args.next()),
             Some(l) if l == "-l" => {
                 limit = Some(
                     args.next()
-                        .expect(format!("Expected a limit after -l\n {}", USAGE).as_str())
+                        .unwrap_or_else(|| panic!("Expected a limit after -l\n {}", USAGE))
                         .parse::<usize>()
-                        .expect(format!("limit must be an usigned integer\n {}", USAGE).as_str()),
+                        .unwrap_or_else(|_| panic!("limit must be an usigned integer\n {}", USAGE)),
                 );
             }
+            Some(l) if l == "-m" => dump_memory = true,
+            Some(l) if l == "-f" => {
+                stack_format = match args.next().as_deref() {
+                    Some("int") => WordFormat::Int,
+                    Some("float") => WordFormat::Float,
+                    Some("hex") => WordFormat::Hex,
+                    _ => panic!("stack format must be int, float or hex\n {}", USAGE),
+                };
+            }
+            Some(l) if l == "--debug" => debug = true,
+            Some(l) if l == "--verify" => verify = true,
+            Some(l) if l == "--threaded" => threaded = true,
+            Some(l) if l == "--jit" => jit = true,
+            Some(l) if l == "--trace" => {
+                trace_file = Some(args.next().unwrap_or_else(|| {
+                    panic!("Expected Trace File with flag --trace: \n{}", USAGE)
+                }));
+            }
+            Some(l) if l == "--trace-format" => {
+                trace_format = match args.next().as_deref() {
+                    Some("human") => TraceFormat::Human,
+                    Some("json") => TraceFormat::JsonLines,
+                    _ => panic!("trace format must be human or json\n {}", USAGE),
+                };
+            }
+            Some(l) if l == "--profile" => {
+                profile_file = Some(args.next().unwrap_or_else(|| {
+                    panic!("Expected Profile File with flag --profile: \n{}", USAGE)
+                }));
+            }
+            Some(l) if l == "--profile-format" => {
+                profile_format = match args.next().as_deref() {
+                    Some("table") => ProfileFormat::Table,
+                    Some("folded") => ProfileFormat::Folded,
+                    _ => panic!("profile format must be table or folded\n {}", USAGE),
+                };
+            }
+            Some(l) if l == "--stack-capacity" => {
+                builder = builder.stack_capacity(parse_count(&l, args.next()));
+                limit_flag = Some(l);
+            }
+            Some(l) if l == "--program-capacity" => {
+                builder = builder.program_capacity(parse_count(&l, args.next()));
+                limit_flag = Some(l);
+            }
+            Some(l) if l == "--memory-size" => {
+                builder = builder.memory_size(parse_count(&l, args.next()));
+                limit_flag = Some(l);
+            }
+            Some(l) if l == "--call-depth" => {
+                builder = builder.call_depth(parse_count(&l, args.next()));
+                limit_flag = Some(l);
+            }
+            Some(l) if l == "--budget" => {
+                builder = builder.instruction_budget(parse_count(&l, args.next()));
+                limit_flag = Some(l);
+            }
             Some(l) if l == "-h" => {
                 println!("{}", USAGE);
                 process::exit(0);
@@ -40,17 +190,166 @@ fn main() {
         }
     }
 
-    let program = BM::deserialize_program_from(
-        input_file.expect(format!("Expected a input file: {}\n", USAGE).as_str()),
-    );
-    let mut bm: BM = Default::default();
-    bm.load_program_from_memory(program.as_slice());
-    bm.program_to_asm(&mut std::io::stdout()).unwrap();
-    match bm.execute_program(limit) {
-        Ok(()) => bm.dump_stack(&mut std::io::stdout()).expect("should work"),
+    if let Some(flag) = limit_flag.filter(|_| resume) {
+        panic!("{} and --resume can not be combined\n {}", flag, USAGE);
+    }
+    if debug {
+        let conflicting = [
+            ("--trace", trace_file.is_some()),
+            ("--profile", profile_file.is_some()),
+            ("--threaded", threaded),
+            ("--jit", jit),
+            ("--snapshot", snapshot_file.is_some()),
+        ];
+        if let Some((flag, _)) = conflicting.iter().find(|(_, given)| *given) {
+            panic!("--debug and {} can not be combined\n {}", flag, USAGE);
+        }
+    }
+    if trace_file.is_some() && profile_file.is_some() {
+        panic!("--trace and --profile can not be combined\n {}", USAGE);
+    }
+    if threaded && trace_file.is_some() {
+        panic!("--threaded and --trace can not be combined\n {}", USAGE);
+    }
+    if threaded && profile_file.is_some() {
+        panic!("--threaded and --profile can not be combined\n {}", USAGE);
+    }
+    if jit && trace_file.is_some() {
+        panic!("--jit and --trace can not be combined\n {}", USAGE);
+    }
+    if jit && profile_file.is_some() {
+        panic!("--jit and --profile can not be combined\n {}", USAGE);
+    }
+    if jit && threaded {
+        panic!("--jit and --threaded can not be combined\n {}", USAGE);
+    }
+
+    // Output files are only created once the flags are known to work together
+    let trace_file =
+        trace_file.map(|path| File::create(path).expect("Could not open or create trace file"));
+    let profile_file =
+        profile_file.map(|path| File::create(path).expect("Could not open or create profile file"));
+
+    let file = match BmFile::read_from(
+        input_file.unwrap_or_else(|| panic!("Expected a input file: {}\n", USAGE)),
+    ) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("Could not load program: {}", e);
+            process::exit(1);
+        }
+    };
+    if file.is_object() {
+        eprintln!("Could not load program: relocatable objects have to be linked with bmld first");
+        process::exit(1);
+    }
+    let mut bm = builder.build();
+    let symbols = file.symbols.clone();
+    let loaded = match (resume, file.state.is_some()) {
+        (true, _) => Snapshot::from_file(file).and_then(|snapshot| bm.restore(snapshot)),
+        (false, true) => {
+            eprintln!("Could not load program: the file is a snapshot, use --resume to run it");
+            process::exit(1);
+        }
+        (false, false) => bm.load_program_from_memory(file.code.as_slice()),
+    };
+    if let Err(e) = loaded {
+        eprintln!("Could not load program: {}", e);
+        process::exit(1);
+    }
+    if verify {
+        let report = bm.verify();
+        for addr in &report.unverified {
+            eprintln!("warning: {}: stack effect can not be verified", addr);
+        }
+        if !report.is_ok() {
+            for e in &report.errors {
+                eprintln!("error: {}", e);
+            }
+            eprintln!("Program failed verification");
+            process::exit(1);
+        }
+    }
+    if debug {
+        // Commands and program input come from the same stdin
+        let input = SharedInput::new(std::io::stdin().lock());
+        bm.set_input(input.clone());
+        let mut debugger = Debugger::new(symbols);
+        debugger
+            .run(
+                &mut bm,
+                std::io::BufReader::new(input),
+                &mut std::io::stdout(),
+            )
+            .expect("should work");
+        return;
+    }
+    bm.program_to_asm_with_symbols(&mut std::io::stdout(), &symbols)
+        .unwrap();
+    let result = match (trace_file, profile_file) {
+        (Some(trace_file), _) => {
+            let mut tracer = Tracer::new(std::io::BufWriter::new(trace_file), trace_format);
+            let result = bm.execute_program_traced(limit, &mut tracer);
+            if let Err(e) = tracer.finish() {
+                eprintln!("Could not write trace: {}", e);
+            }
+            result
+        }
+        (None, Some(profile_file)) => {
+            let mut profiler = Profiler::new(symbols);
+            let result = bm.execute_program_profiled(limit, &mut profiler);
+            let mut w = std::io::BufWriter::new(profile_file);
+            let written = profiler
+                .write(bm.program(), profile_format, &mut w)
+                .and_then(|_| w.flush());
+            if let Err(e) = written {
+                eprintln!("Could not write profile: {}", e);
+            }
+            result
+        }
+        (None, None) if threaded => match bm.compile() {
+            Ok(code) => bm.execute_compiled(&code, limit),
+            Err(bm::Error::Verify(errors)) => {
+                for e in &errors {
+                    eprintln!("error: {}", e);
+                }
+                eprintln!("Program failed verification, it can not run with --threaded");
+                process::exit(1);
+            }
+            Err(e) => Err(e),
+        },
+        (None, None) if jit => execute_jit(&mut bm, limit),
+        (None, None) => bm.execute_program(limit),
+    };
+    bm.flush_output().expect("should work");
+    let stop = match result {
+        Ok(()) if bm.is_halted() => Stop::Exit,
+        Ok(()) => Stop::Limit,
+        Err(_) => Stop::Error,
+    };
+    if let Some(path) = snapshot_file.filter(|_| snapshot_on.contains(&stop)) {
+        let written = File::create(&path)
+            .map_err(bm::Error::from)
+            .and_then(|f| bm.snapshot().write_to(f));
+        if let Err(e) = written {
+            eprintln!("Could not write snapshot {}: {}", path, e);
+        }
+    }
+    match result {
+        Ok(()) => {
+            bm.dump_stack_as(&mut std::io::stdout(), stack_format)
+                .expect("should work");
+            if dump_memory {
+                bm.dump_memory(&mut std::io::stdout()).expect("should work");
+            }
+        }
         Err(e) => {
             eprintln!("{}", e);
-            bm.dump_stack(&mut std::io::stderr()).expect("should work");
+            bm.dump_stack_as(&mut std::io::stderr(), stack_format)
+                .expect("should work");
+            if dump_memory {
+                bm.dump_memory(&mut std::io::stderr()).expect("should work");
+            }
             process::exit(1);
         }
     };
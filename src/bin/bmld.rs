@@ -0,0 +1,66 @@
+use std::{fs::File, process};
+
+use bm::format::BmFile;
+use bm::linker::{link, Object};
+
+static USAGE: &str = "Usage: ./bmld -o <output_file>.bm <object_file>.bm...
+  The program starts at the beginning of the first object.";
+
+fn main() {
+    let mut args = std::env::args();
+    args.next().expect("Should work");
+
+    let mut output_path = None;
+    let mut objects = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => {
+                output_path = Some(
+                    args.next()
+                        .unwrap_or_else(|| panic!("Expected Output File: \n{}", USAGE)),
+                )
+            }
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ => {
+                let file = File::open(&arg)
+                    .map_err(|e| e.to_string())
+                    .and_then(|f| BmFile::read_from(f).map_err(|e| e.to_string()));
+                match file {
+                    Ok(file) => objects.push(Object { name: arg, file }),
+                    Err(e) => {
+                        eprintln!("bmld: could not read {}: {}", arg, e);
+                        process::exit(1);
+                    }
+                }
+            }
+        }
+    }
+    let output_path = output_path.unwrap_or_else(|| panic!("Expected Output File: \n{}", USAGE));
+    if objects.is_empty() {
+        panic!("Expected Object Files: \n{}", USAGE);
+    }
+
+    let program = match link(&objects) {
+        Ok(program) => program,
+        Err(errors) => {
+            for e in &errors {
+                eprintln!("bmld: error: {}", e);
+            }
+            eprintln!("bmld: linking failed due to {} error(s)", errors.len());
+            process::exit(1);
+        }
+    };
+
+    let output_file = File::options()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(output_path)
+        .expect("Could not open or create output file");
+    program
+        .write_to(&output_file)
+        .expect("Could not write output file");
+}
@@ -0,0 +1,315 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufRead, Read, Write};
+use std::rc::Rc;
+
+use bm::format::{BmFile, Symbol};
+use bm::instruction::InstructionParseErr;
+use bm::serialize_deserialize::BasmCtx;
+use bm::stream::SharedBuffer;
+use bm::{Error, Instruction, Word, BM};
+
+static USAGE: &str = "Usage: ./bmrepl";
+
+static HELP: &str = "Every line is assembled, appended to the program and executed right away.
+Commands:
+   :reset          start over with an empty program
+   :undo           remove the last line entered
+   :load <file>    replace the program with a .basm or .bm file and run it
+   :save <file>    write the program as basm
+   :input <text>   feed a line of text to the input instructions
+   :stack          print the stack
+   :list           print the program
+   :help           print this message
+   :quit           leave the repl";
+
+/// Maximum number of instructions a single line may execute, so loops can not hang the repl.
+const STEP_LIMIT: usize = 1_000_000;
+
+/// Input of the virtual machine, fed by the `:input` command.
+#[derive(Clone, Default)]
+struct Input(Rc<RefCell<VecDeque<u8>>>);
+
+impl Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut queue = self.0.borrow_mut();
+        let n = buf.len().min(queue.len());
+        for (b, byte) in buf.iter_mut().zip(queue.drain(..n)) {
+            *b = byte;
+        }
+        Ok(n)
+    }
+}
+
+/// Everything needed to rebuild the virtual machine from scratch, which is how lines
+/// are undone.
+#[derive(Default)]
+struct Session {
+    /// Program loaded with `:load`
+    base: Vec<Instruction>,
+    base_symbols: Vec<Symbol>,
+    /// Lines entered on top of the loaded program
+    lines: Vec<String>,
+    /// Everything fed to the input so far
+    input: Vec<u8>,
+    bm: BM,
+    ctx: BasmCtx,
+    queue: Input,
+    output: SharedBuffer,
+}
+
+impl Session {
+    fn new() -> Self {
+        let mut session = Session::default();
+        session.rebuild();
+        session
+    }
+
+    /// Recreate the virtual machine and replay the session without printing anything.
+    fn rebuild(&mut self) {
+        self.bm = Default::default();
+        self.ctx = Default::default();
+        self.queue = Input::default();
+        self.queue.0.borrow_mut().extend(&self.input);
+        self.bm.set_input(self.queue.clone());
+        self.bm.set_output(std::io::sink());
+
+        // Replaying a session that used to work can not fail
+        let _ = self.bm.load_program_from_memory(&self.base);
+        let _ = self.run();
+        for line in std::mem::take(&mut self.lines) {
+            let _ = self.enter(&line);
+        }
+        self.output = SharedBuffer::default();
+        self.bm.set_output(self.output.clone());
+    }
+
+    /// Everything the program wrote since the last call.
+    fn take_output(&mut self) -> Vec<u8> {
+        let _ = self.bm.flush_output();
+        let output = self.output.contents();
+        self.output = SharedBuffer::default();
+        self.bm.set_output(self.output.clone());
+        output
+    }
+
+    /// Execute until the end of the program is reached.
+    fn run(&mut self) -> Result<(), String> {
+        let mut steps = 0;
+        while !self.bm.is_halted() && (self.bm.ip() as usize) < self.bm.program().len() {
+            if steps == STEP_LIMIT {
+                return Err(format!("stopped after {} instructions", STEP_LIMIT));
+            }
+            self.bm.execute_instruction().map_err(|e| e.to_string())?;
+            steps += 1;
+        }
+        if self.bm.ip() as usize > self.bm.program().len() {
+            return Err(format!(
+                "jumped past the end of the program to {}",
+                self.bm.ip()
+            ));
+        }
+        Ok(())
+    }
+
+    fn resolve(&self, label: &str) -> Option<Word> {
+        self.ctx.get_addr_for(label).or_else(|| {
+            self.base_symbols
+                .iter()
+                .find(|s| s.name == label)
+                .map(|s| s.addr)
+        })
+    }
+
+    /// Assemble a line, append it to the program and execute it. The line is only kept
+    /// if it succeeds.
+    fn enter(&mut self, line: &str) -> Result<(), String> {
+        let reported = self.ctx.diagnostics().len();
+        let inst = Instruction::from_asm(line, &self.bm, &mut self.ctx);
+        if let Some(d) = self.ctx.diagnostics()[reported..]
+            .iter()
+            .find(|d| d.is_error())
+        {
+            let d = d.to_string();
+            self.rebuild();
+            return Err(d);
+        }
+        let mut inst = match inst {
+            Ok(inst) => inst,
+            // Blank and label-only lines
+            Err(InstructionParseErr::EmptyLine) => {
+                self.lines.push(line.to_string());
+                return Ok(());
+            }
+            Err(e) => return Err(format!("error: {}", e)),
+        };
+        // Labels can only be used once they are defined, since the line runs right away
+        if let Some(target @ None) = inst.target_mut() {
+            let code = line.split_once('#').map_or(line, |(code, _)| code);
+            let label = code.split_whitespace().last().unwrap_or_default();
+            match self.resolve(label) {
+                Some(addr) => *target = Some(addr),
+                None => return Err(format!("error: undefined label `{}`", label)),
+            }
+        }
+
+        self.bm
+            .push_inst(inst)
+            .map_err(|e| format!("error: {}", e))?;
+        self.lines.push(line.to_string());
+        if let Err(e) = self.run() {
+            self.lines.pop();
+            self.rebuild();
+            return Err(format!("error: {}", e));
+        }
+        Ok(())
+    }
+
+    fn undo(&mut self) -> Option<String> {
+        let line = self.lines.pop()?;
+        self.rebuild();
+        Some(line)
+    }
+
+    fn load(&mut self, path: &str) -> Result<(), String> {
+        let (program, symbols) = if path.ends_with(".basm") {
+            let mut bm: BM = Default::default();
+            let mut ctx = BasmCtx::default();
+            match bm.program_from_asm_file(path, &mut ctx) {
+                Ok(()) => {}
+                Err(Error::Assemble(diagnostics)) => {
+                    let mut out = Vec::new();
+                    for d in &diagnostics {
+                        ctx.render_diagnostic(d, &mut out)
+                            .map_err(|e| e.to_string())?;
+                    }
+                    return Err(String::from_utf8_lossy(&out).into_owned());
+                }
+                Err(e) => return Err(e.to_string()),
+            }
+            (bm.program().to_vec(), ctx.symbols())
+        } else {
+            let file = File::open(path)
+                .map_err(|e| e.to_string())
+                .and_then(|f| BmFile::read_from(f).map_err(|e| e.to_string()))
+                .map_err(|e| format!("could not load {}: {}", path, e))?;
+            if file.is_object() {
+                return Err(format!("{} is a relocatable object", path));
+            }
+            (file.code, file.symbols)
+        };
+
+        self.base = program;
+        // Lines entered afterwards are appended after the program
+        if let Some(Instruction::Halt) = self.base.last() {
+            self.base.pop();
+        }
+        self.base_symbols = symbols;
+        self.lines.clear();
+        // Input that was not read yet is dropped along with the previous program
+        self.input.clear();
+        self.queue = Input::default();
+        self.bm = Default::default();
+        self.ctx = Default::default();
+        self.bm.set_input(self.queue.clone());
+        self.bm.set_output(self.output.clone());
+        self.bm
+            .load_program_from_memory(&self.base)
+            .map_err(|e| e.to_string())?;
+        self.run()
+    }
+
+    /// Write the program as basm, with the labels of the loaded program and the session.
+    fn write_asm<W>(&self, w: &mut W) -> std::io::Result<()>
+    where
+        W: Write,
+    {
+        let mut symbols = self.base_symbols.clone();
+        symbols.extend(self.ctx.symbols());
+        self.bm.program_to_asm_with_symbols(w, &symbols)
+    }
+
+    fn feed(&mut self, text: &str) {
+        let mut bytes = text.as_bytes().to_vec();
+        bytes.push(b'\n');
+        self.queue.0.borrow_mut().extend(&bytes);
+        self.input.extend(bytes);
+    }
+}
+
+fn main() {
+    let mut args = std::env::args();
+    args.next().expect("Should work");
+    if args.next().is_some() {
+        eprintln!("{}", USAGE);
+        std::process::exit(1);
+    }
+
+    let mut session = Session::new();
+    let mut out = std::io::stdout();
+    println!("bm repl, type `:help` for the list of commands");
+    let stdin = std::io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("bm> ");
+        out.flush().expect("Could not write to stdout");
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            _ => break,
+        };
+
+        let (command, arg) = line.trim().split_once(' ').unwrap_or((line.trim(), ""));
+        let arg = arg.trim();
+        let result = match command {
+            ":quit" | ":q" => break,
+            ":help" => {
+                println!("{}", HELP);
+                Ok(())
+            }
+            ":reset" => {
+                session = Session::new();
+                Ok(())
+            }
+            ":undo" => match session.undo() {
+                Some(line) => {
+                    println!("removed `{}`", line.trim());
+                    Ok(())
+                }
+                None => Err("nothing to undo".to_string()),
+            },
+            ":load" if !arg.is_empty() => session.load(arg),
+            ":save" if !arg.is_empty() => File::create(arg)
+                .and_then(|mut f| session.write_asm(&mut f))
+                .map_err(|e| format!("could not save {}: {}", arg, e)),
+            ":input" => {
+                session.feed(arg);
+                Ok(())
+            }
+            ":stack" => Ok(()),
+            ":list" => session.write_asm(&mut out).map_err(|e| e.to_string()),
+            c if c.starts_with(':') => Err(format!("unknown command `{}`, try `:help`", line)),
+            _ => session.enter(&line),
+        };
+        let output = session.take_output();
+        if !output.is_empty() {
+            out.write_all(&output).expect("Could not write to stdout");
+            if !output.ends_with(b"\n") {
+                writeln!(out).expect("Could not write to stdout");
+            }
+        }
+        match result {
+            Ok(()) if command == ":list" || command == ":help" => {}
+            Ok(()) => {
+                if session.bm.is_halted() {
+                    println!("program halted, `:undo` or `:reset` to go on");
+                }
+                session
+                    .bm
+                    .dump_stack(&mut out)
+                    .expect("Could not write to stdout");
+            }
+            Err(e) => eprintln!("{}", e.trim_end()),
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use std::process;
+
+use bm::test_runner::{discover, TestRunner, DEFAULT_TEST_BUDGET};
+use bm::BMConfig;
+
+static USAGE: &str = "Usage: ./bmtest [--budget <n>] [-I <dir>] [<file>.basm | <dir>]...
+  Directories are searched for .basm files with `# expect-...` directives,
+  the current directory if none is given.";
+
+fn main() {
+    let mut args = std::env::args();
+    args.next().expect("Should work");
+
+    let mut budget = DEFAULT_TEST_BUDGET;
+    let mut include_paths = Vec::new();
+    let mut paths = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--budget" => {
+                budget = args
+                    .next()
+                    .unwrap_or_else(|| panic!("Expected a number after --budget\n {}", USAGE))
+                    .parse::<usize>()
+                    .unwrap_or_else(|_| panic!("--budget must be an usigned integer\n {}", USAGE))
+            }
+            "-I" => include_paths.push(
+                args.next()
+                    .unwrap_or_else(|| panic!("Expected Include Directory: \n{}", USAGE)),
+            ),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ => match arg.strip_prefix("-I") {
+                Some(dir) => include_paths.push(dir.to_string()),
+                None => paths.push(arg),
+            },
+        }
+    }
+    if paths.is_empty() {
+        paths.push(".".to_string());
+    }
+
+    let mut runner = TestRunner::with_config(BMConfig {
+        instruction_budget: Some(budget),
+        ..Default::default()
+    });
+    for dir in include_paths {
+        runner.add_include_path(dir);
+    }
+    let mut tests = Vec::new();
+    for path in &paths {
+        match discover(path) {
+            Ok(found) => tests.extend(found),
+            Err(e) => {
+                eprintln!("bmtest: could not search {}: {}", path, e);
+                process::exit(1);
+            }
+        }
+    }
+
+    println!("running {} test(s)", tests.len());
+    let mut failed = Vec::new();
+    for test in &tests {
+        let report = runner.run_file(test);
+        if report.passed() {
+            println!("test {} ... ok", report.path.display());
+        } else {
+            println!("test {} ... FAILED", report.path.display());
+            failed.push(report);
+        }
+    }
+
+    if !failed.is_empty() {
+        println!("\nfailures:");
+        for report in &failed {
+            println!("\n---- {} ----", report.path.display());
+            for failure in &report.failures {
+                println!("{}", failure.to_string().trim_end());
+            }
+        }
+    }
+    println!(
+        "\ntest result: {}. {} passed; {} failed",
+        if failed.is_empty() { "ok" } else { "FAILED" },
+        tests.len() - failed.len(),
+        failed.len()
+    );
+    if !failed.is_empty() {
+        process::exit(1);
+    }
+}
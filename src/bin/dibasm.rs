@@ -1,8 +1,8 @@
 use std::fs::File;
 
-use bm::BM;
+use bm::{format::BmFile, serialize_deserialize::write_asm};
 
-static USAGE: &'static str = "Usage: ./bm <input_file>.basm";
+static USAGE: &str = "Usage: ./dibasm <input_file>.bm";
 
 fn main() {
     let mut args = std::env::args();
@@ -11,13 +11,16 @@ fn main() {
         .read(true)
         .open(
             args.next()
-                .expect(format!("Expected Input File: \n{}", USAGE).as_str()),
+                .unwrap_or_else(|| panic!("Expected Input File: \n{}", USAGE)),
         )
         .expect("Could not read input file.");
 
-    let program = BM::deserialize_program_from(&input_file);
-    let mut bm: BM = Default::default();
-    bm.load_program_from_memory(&program);
-    bm.program_to_asm(&mut std::io::stdout())
-        .expect("Could not serialize basm");
+    let file = match BmFile::read_from(&input_file) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("Could not load program: {}", e);
+            std::process::exit(1);
+        }
+    };
+    write_asm(&file.code, &file.symbols, &mut std::io::stdout()).expect("Could not serialize basm");
 }
@@ -0,0 +1,91 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    BM, BM_CALL_STACK_CAPACITY, BM_MEMORY_CAPACITY, BM_PROGRAM_CAPACITY, BM_STACK_CAPACITY,
+};
+
+/// Limits of a virtual machine instance.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BMConfig {
+    /// Maximum number of elements on the evaluation stack
+    pub stack_capacity: usize,
+    /// Maximum number of instructions in the program
+    pub program_capacity: usize,
+    /// Size of the data memory in bytes
+    pub memory_size: usize,
+    /// Maximum depth of nested calls
+    pub call_depth: usize,
+    /// Maximum number of instructions executed over the lifetime of the instance,
+    /// `None` for no limit
+    pub instruction_budget: Option<usize>,
+}
+
+impl Default for BMConfig {
+    fn default() -> Self {
+        Self {
+            stack_capacity: BM_STACK_CAPACITY,
+            program_capacity: BM_PROGRAM_CAPACITY,
+            memory_size: BM_MEMORY_CAPACITY,
+            call_depth: BM_CALL_STACK_CAPACITY,
+            instruction_budget: None,
+        }
+    }
+}
+
+/// Builds a virtual machine with custom limits. Limits that are not set keep their
+/// default value.
+/// ```
+/// use bm::{interpreter::InterpreterErr, BMBuilder, Instruction};
+/// let mut bm = BMBuilder::new()
+///     .stack_capacity(4096)
+///     .instruction_budget(100)
+///     .build();
+/// bm.load_program_from_memory(&[Instruction::Jump(Some(0))]).unwrap();
+/// let e = bm.execute_program(None).unwrap_err();
+/// assert_eq!(e.as_runtime(), Some(&InterpreterErr::BudgetExhausted));
+/// assert_eq!(bm.executed(), 100);
+/// ```
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BMBuilder {
+    config: BMConfig,
+}
+
+impl BMBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn stack_capacity(mut self, capacity: usize) -> Self {
+        self.config.stack_capacity = capacity;
+        self
+    }
+
+    pub fn program_capacity(mut self, capacity: usize) -> Self {
+        self.config.program_capacity = capacity;
+        self
+    }
+
+    pub fn memory_size(mut self, size: usize) -> Self {
+        self.config.memory_size = size;
+        self
+    }
+
+    pub fn call_depth(mut self, depth: usize) -> Self {
+        self.config.call_depth = depth;
+        self
+    }
+
+    pub fn instruction_budget(mut self, budget: usize) -> Self {
+        self.config.instruction_budget = Some(budget);
+        self
+    }
+
+    /// The configuration built so far.
+    pub fn config(&self) -> BMConfig {
+        self.config
+    }
+
+    pub fn build(self) -> BM {
+        BM::with_config(self.config)
+    }
+}
@@ -0,0 +1,404 @@
+use std::collections::BTreeSet;
+use std::fmt::Display;
+use std::io::{BufRead, Write};
+
+use crate::format::Symbol;
+use crate::{Word, BM};
+
+static HELP: &str = "Commands:
+   s, step [n]            execute the next n instructions (default 1)
+   c, continue            run until a breakpoint is hit or the program stops
+   b, break <addr|label>  set a breakpoint
+   d, delete <addr|label> clear a breakpoint
+   l, list                list the breakpoints
+   p, stack               print the stack
+   i, inst                print the instruction to be executed next
+   u, until <op> <value>  run until the top of the stack satisfies the condition,
+                          where <op> is one of == != < <= > >=
+   q, quit                stop debugging
+   h, help                print this message
+An empty line repeats the previous command.";
+
+/// Comparison used by the `until` command against the top of the stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    Eq(Word),
+    Ne(Word),
+    Lt(Word),
+    Le(Word),
+    Gt(Word),
+    Ge(Word),
+}
+
+impl Condition {
+    /// Checks if the top of the stack satisfies the condition. An empty stack never does.
+    pub fn holds(&self, stack: &[Word]) -> bool {
+        let top = match stack.last() {
+            Some(top) => *top,
+            None => return false,
+        };
+        match *self {
+            Condition::Eq(v) => top == v,
+            Condition::Ne(v) => top != v,
+            Condition::Lt(v) => top < v,
+            Condition::Le(v) => top <= v,
+            Condition::Gt(v) => top > v,
+            Condition::Ge(v) => top >= v,
+        }
+    }
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Condition::Eq(v) => write!(f, "top == {}", v),
+            Condition::Ne(v) => write!(f, "top != {}", v),
+            Condition::Lt(v) => write!(f, "top < {}", v),
+            Condition::Le(v) => write!(f, "top <= {}", v),
+            Condition::Gt(v) => write!(f, "top > {}", v),
+            Condition::Ge(v) => write!(f, "top >= {}", v),
+        }
+    }
+}
+
+/// A single debugger command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Step(usize),
+    Continue,
+    Break(String),
+    Delete(String),
+    List,
+    Stack,
+    Inst,
+    Until(Condition),
+    Quit,
+    Help,
+}
+
+impl Command {
+    /// Parse a command line typed by the user.
+    pub fn parse(line: &str) -> Result<Command, String> {
+        let mut words = line.split_whitespace();
+        let name = words.next().ok_or("empty command")?;
+        let arg = words.next();
+        match name {
+            "s" | "step" => match arg {
+                Some(n) => n
+                    .parse()
+                    .map(Command::Step)
+                    .map_err(|_| format!("invalid step count `{}`", n)),
+                None => Ok(Command::Step(1)),
+            },
+            "c" | "continue" => Ok(Command::Continue),
+            "b" | "break" => arg
+                .map(|a| Command::Break(a.to_string()))
+                .ok_or_else(|| "expected an address or a label".to_string()),
+            "d" | "delete" => arg
+                .map(|a| Command::Delete(a.to_string()))
+                .ok_or_else(|| "expected an address or a label".to_string()),
+            "l" | "list" => Ok(Command::List),
+            "p" | "stack" => Ok(Command::Stack),
+            "i" | "inst" => Ok(Command::Inst),
+            "u" | "until" => {
+                let value = words
+                    .next()
+                    .ok_or("expected a condition like `until == 0`")?;
+                let value = value
+                    .parse::<Word>()
+                    .map_err(|_| format!("invalid value `{}`", value))?;
+                match arg {
+                    Some("==") => Ok(Command::Until(Condition::Eq(value))),
+                    Some("!=") => Ok(Command::Until(Condition::Ne(value))),
+                    Some("<") => Ok(Command::Until(Condition::Lt(value))),
+                    Some("<=") => Ok(Command::Until(Condition::Le(value))),
+                    Some(">") => Ok(Command::Until(Condition::Gt(value))),
+                    Some(">=") => Ok(Command::Until(Condition::Ge(value))),
+                    Some(op) => Err(format!("unknown comparison `{}`", op)),
+                    None => Err("expected a comparison".to_string()),
+                }
+            }
+            "q" | "quit" => Ok(Command::Quit),
+            "h" | "help" => Ok(Command::Help),
+            _ => Err(format!("unknown command `{}`, try `help`", name)),
+        }
+    }
+}
+
+/// Why running the program stopped.
+enum Stop {
+    Breakpoint,
+    Condition,
+    Halted,
+    Error,
+}
+
+/// Interactive step debugger driving a virtual machine one instruction at a time.
+#[derive(Debug, Default)]
+pub struct Debugger {
+    breakpoints: BTreeSet<Word>,
+    symbols: Vec<Symbol>,
+}
+
+impl Debugger {
+    /// Creates a debugger that can refer to addresses by the given symbols.
+    pub fn new(symbols: Vec<Symbol>) -> Self {
+        Self {
+            breakpoints: Default::default(),
+            symbols,
+        }
+    }
+
+    /// Resolve an address or a label to an address.
+    pub fn resolve(&self, target: &str) -> Option<Word> {
+        target.parse::<Word>().ok().or_else(|| {
+            self.symbols
+                .iter()
+                .find(|s| s.name == target)
+                .map(|s| s.addr)
+        })
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = Word> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// Read commands from `input` and execute them until the user quits or the input ends.
+    pub fn run<R, W>(&mut self, bm: &mut BM, input: R, out: &mut W) -> std::io::Result<()>
+    where
+        R: BufRead,
+        W: Write,
+    {
+        writeln!(out, "bm debugger, type `help` for the list of commands")?;
+        self.print_location(bm, out)?;
+        let mut lines = input.lines();
+        let mut last = None;
+        loop {
+            write!(out, "(bmdb) ")?;
+            out.flush()?;
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => return Ok(()),
+            };
+            let command = if line.trim().is_empty() {
+                match &last {
+                    Some(command) => Ok(Command::clone(command)),
+                    None => continue,
+                }
+            } else {
+                Command::parse(&line)
+            };
+            match command {
+                Ok(Command::Quit) => return Ok(()),
+                Ok(command) => {
+                    self.execute(bm, &command, out)?;
+                    last = Some(command);
+                }
+                Err(e) => writeln!(out, "{}", e)?,
+            }
+        }
+    }
+
+    /// Execute a single command.
+    pub fn execute<W>(&mut self, bm: &mut BM, command: &Command, out: &mut W) -> std::io::Result<()>
+    where
+        W: Write,
+    {
+        match command {
+            Command::Step(n) => {
+                for _ in 0..*n {
+                    if self.step(bm, out)?.is_some() {
+                        break;
+                    }
+                }
+                self.print_location(bm, out)?;
+            }
+            Command::Continue => {
+                self.run_until(bm, None, out)?;
+                self.print_location(bm, out)?;
+            }
+            Command::Until(condition) => {
+                if let Stop::Condition = self.run_until(bm, Some(*condition), out)? {
+                    writeln!(out, "{} holds", condition)?;
+                }
+                self.print_location(bm, out)?;
+            }
+            Command::Break(target) => match self.resolve(target) {
+                Some(addr) if addr >= 0 && (addr as usize) < bm.program().len() => {
+                    self.breakpoints.insert(addr);
+                    writeln!(out, "breakpoint set at {}", self.describe(addr))?;
+                }
+                Some(addr) => writeln!(out, "address {} is outside of the program", addr)?,
+                None => writeln!(out, "unknown label `{}`", target)?,
+            },
+            Command::Delete(target) => match self.resolve(target) {
+                Some(addr) if self.breakpoints.remove(&addr) => {
+                    writeln!(out, "breakpoint at {} cleared", self.describe(addr))?
+                }
+                Some(addr) => writeln!(out, "no breakpoint at {}", addr)?,
+                None => writeln!(out, "unknown label `{}`", target)?,
+            },
+            Command::List => {
+                if self.breakpoints.is_empty() {
+                    writeln!(out, "no breakpoints")?;
+                }
+                for addr in &self.breakpoints {
+                    writeln!(out, "   {}", self.describe(*addr))?;
+                }
+            }
+            Command::Stack => bm.dump_stack(out)?,
+            Command::Inst => self.print_location(bm, out)?,
+            Command::Help => writeln!(out, "{}", HELP)?,
+            Command::Quit => {}
+        }
+        Ok(())
+    }
+
+    /// Execute a single instruction, reporting why the program can not go on if it stopped.
+    fn step<W>(&self, bm: &mut BM, out: &mut W) -> std::io::Result<Option<Stop>>
+    where
+        W: Write,
+    {
+        if bm.is_halted() {
+            return Ok(Some(Stop::Halted));
+        }
+        if let Err(e) = bm.execute_instruction() {
+            writeln!(out, "{}", e)?;
+            return Ok(Some(Stop::Error));
+        }
+        Ok(None)
+    }
+
+    /// Keep executing until a breakpoint is reached, the condition holds or the program stops.
+    fn run_until<W>(
+        &self,
+        bm: &mut BM,
+        condition: Option<Condition>,
+        out: &mut W,
+    ) -> std::io::Result<Stop>
+    where
+        W: Write,
+    {
+        loop {
+            if let Some(stop) = self.step(bm, out)? {
+                return Ok(stop);
+            }
+            if condition.is_some_and(|c| c.holds(bm.stack())) {
+                return Ok(Stop::Condition);
+            }
+            if self.breakpoints.contains(&bm.ip()) {
+                writeln!(out, "breakpoint hit at {}", self.describe(bm.ip()))?;
+                return Ok(Stop::Breakpoint);
+            }
+        }
+    }
+
+    /// Address along with the label pointing at it.
+    fn describe(&self, addr: Word) -> String {
+        match self.symbols.iter().find(|s| s.addr == addr) {
+            Some(s) => format!("{} <{}>", addr, s.name),
+            None => addr.to_string(),
+        }
+    }
+
+    fn print_location<W>(&self, bm: &BM, out: &mut W) -> std::io::Result<()>
+    where
+        W: Write,
+    {
+        match bm.current_instruction() {
+            Some(inst) if !bm.is_halted() => {
+                writeln!(out, "=> {}: {}", self.describe(bm.ip()), inst)
+            }
+            Some(_) => writeln!(out, "program halted"),
+            None => writeln!(out, "=> {}: <outside of the program>", bm.ip()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn step() {
+        assert_eq!(Command::parse("s"), Ok(Command::Step(1)));
+        assert_eq!(Command::parse("step 5"), Ok(Command::Step(5)));
+        assert_eq!(
+            Command::parse("step -1"),
+            Err("invalid step count `-1`".to_string())
+        );
+        assert_eq!(
+            Command::parse("s x"),
+            Err("invalid step count `x`".to_string())
+        );
+    }
+
+    #[test]
+    fn continue_and_quit() {
+        assert_eq!(Command::parse("c"), Ok(Command::Continue));
+        assert_eq!(Command::parse("  continue  "), Ok(Command::Continue));
+        assert_eq!(Command::parse("q"), Ok(Command::Quit));
+    }
+
+    #[test]
+    fn breakpoints() {
+        assert_eq!(Command::parse("b 4"), Ok(Command::Break("4".to_string())));
+        assert_eq!(
+            Command::parse("break loop"),
+            Ok(Command::Break("loop".to_string()))
+        );
+        assert_eq!(
+            Command::parse("d loop"),
+            Ok(Command::Delete("loop".to_string()))
+        );
+        assert_eq!(Command::parse("l"), Ok(Command::List));
+        let missing = Err("expected an address or a label".to_string());
+        assert_eq!(Command::parse("break"), missing);
+        assert_eq!(Command::parse("delete"), missing);
+    }
+
+    #[test]
+    fn stack_and_inst() {
+        assert_eq!(Command::parse("p"), Ok(Command::Stack));
+        assert_eq!(Command::parse("stack"), Ok(Command::Stack));
+        assert_eq!(Command::parse("i"), Ok(Command::Inst));
+    }
+
+    #[test]
+    fn until() {
+        assert_eq!(
+            Command::parse("until == 0"),
+            Ok(Command::Until(Condition::Eq(0)))
+        );
+        assert_eq!(
+            Command::parse("u >= -3"),
+            Ok(Command::Until(Condition::Ge(-3)))
+        );
+        assert_eq!(
+            Command::parse("u =< 3"),
+            Err("unknown comparison `=<`".to_string())
+        );
+        assert_eq!(
+            Command::parse("u == x"),
+            Err("invalid value `x`".to_string())
+        );
+        assert!(Command::parse("until").is_err());
+    }
+
+    #[test]
+    fn bad_input() {
+        assert_eq!(Command::parse(""), Err("empty command".to_string()));
+        assert_eq!(Command::parse("   "), Err("empty command".to_string()));
+        assert_eq!(
+            Command::parse("run"),
+            Err("unknown command `run`, try `help`".to_string())
+        );
+    }
+
+    #[test]
+    fn conditions_never_hold_on_an_empty_stack() {
+        assert!(Condition::Lt(5).holds(&[1, 4]));
+        assert!(!Condition::Lt(5).holds(&[7]));
+        assert!(!Condition::Ne(0).holds(&[]));
+    }
+}
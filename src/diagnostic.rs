@@ -0,0 +1,164 @@
+use std::fmt::Display;
+use std::io::Write;
+
+/// Location of a piece of basm source text.
+/// `line` and `col` are 1-based, `len` is the number of characters covered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+    pub len: usize,
+    /// Index of the macro expansion the text was produced by, if any
+    pub expansion: Option<usize>,
+    /// Index of the source file the text is in, 0 being the file given to the assembler
+    pub file: usize,
+}
+
+impl Span {
+    pub fn new(line: usize, col: usize, len: usize) -> Self {
+        Self {
+            line,
+            col,
+            len,
+            expansion: None,
+            file: 0,
+        }
+    }
+}
+
+/// How serious a diagnostic is. Only errors make assembling fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Note => write!(f, "note"),
+        }
+    }
+}
+
+/// A message produced while assembling, pointing at the offending source text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub span: Span,
+    pub severity: Severity,
+    pub message: String,
+    pub hint: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, span: Span, message: impl Into<String>) -> Self {
+        Self {
+            span,
+            severity,
+            message: message.into(),
+            hint: None,
+        }
+    }
+
+    pub fn error(span: Span, message: impl Into<String>) -> Self {
+        Self::new(Severity::Error, span, message)
+    }
+
+    pub fn note(span: Span, message: impl Into<String>) -> Self {
+        Self::new(Severity::Note, span, message)
+    }
+
+    /// Attach a hint that is printed below the source excerpt.
+    pub fn with_hint(mut self, hint: impl Into<String>) -> Self {
+        self.hint = Some(hint.into());
+        self
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    /// Render the diagnostic compiler-style: the message, the location, the offending
+    /// line of `source` and a caret underneath the span.
+    /// ```
+    /// use bm::diagnostic::{Diagnostic, Span};
+    /// let d = Diagnostic::error(Span::new(1, 1, 4), "invalid instruction `pusj`");
+    /// let mut out = Vec::new();
+    /// d.render(&mut out, "a.basm", "pusj 1").unwrap();
+    /// assert!(String::from_utf8(out).unwrap().contains("^^^^"));
+    /// ```
+    pub fn render<W>(&self, w: &mut W, file_name: &str, source: &str) -> std::io::Result<()>
+    where
+        W: Write,
+    {
+        writeln!(w, "{}", self)?;
+        match self.span.line {
+            // The diagnostic is about the whole file
+            0 => writeln!(w, " --> {}", file_name)?,
+            line => writeln!(w, " --> {}:{}:{}", file_name, line, self.span.col)?,
+        }
+
+        if let Some(text) = source.lines().nth(self.span.line.wrapping_sub(1)) {
+            let gutter = self.span.line.to_string();
+            let pad = " ".repeat(gutter.len());
+            // Keep tabs so that the caret lines up with the source line.
+            let indent: String = text
+                .chars()
+                .take(self.span.col.saturating_sub(1))
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            writeln!(w, "{} |", pad)?;
+            writeln!(w, "{} | {}", gutter, text)?;
+            writeln!(
+                w,
+                "{} | {}{}",
+                pad,
+                indent,
+                "^".repeat(self.span.len.max(1))
+            )?;
+            if let Some(hint) = &self.hint {
+                writeln!(w, "{} = hint: {}", pad, hint)?;
+            }
+        } else if let Some(hint) = &self.hint {
+            writeln!(w, "  = hint: {}", hint)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.severity, self.message)
+    }
+}
+
+/// Find the candidate closest to `word` (by edit distance) to suggest in a hint.
+pub(crate) fn closest_match<'a, I>(word: &str, candidates: I) -> Option<&'a str>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let max_distance = (word.chars().count() / 3).max(1);
+    candidates
+        .into_iter()
+        .map(|c| (edit_distance(word, c), c))
+        .filter(|(d, _)| *d <= max_distance)
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, c)| c)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            cur[j + 1] = (prev[j] + cost).min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
@@ -0,0 +1,137 @@
+use std::fmt::Display;
+
+use crate::diagnostic::Diagnostic;
+use crate::format::FormatErr;
+use crate::interpreter::InterpreterErr;
+use crate::verifier::VerifyErr;
+use crate::Word;
+
+/// Errors that can be emitted while loading a program into the virtual machine
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadErr {
+    /// The program does not fit in the program memory of the virtual machine
+    ProgramTooLarge { size: usize, capacity: usize },
+    /// The program calls a native function that is not registered
+    UnknownNative(String),
+    /// The instruction at the address has an address operand that was never resolved,
+    /// e.g. in a relocatable object that was not linked
+    UnresolvedAddress(Word),
+    /// The snapshot describes a state the virtual machine can not be in
+    InconsistentSnapshot(String),
+    /// The compiled code was compiled from another program than the one loaded
+    CodeMismatch,
+}
+
+impl Display for LoadErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadErr::ProgramTooLarge { size, capacity } => write!(
+                f,
+                "program of {} instructions exceeds the capacity of {}",
+                size, capacity
+            ),
+            LoadErr::UnknownNative(name) => write!(f, "unknown native function `{}`", name),
+            LoadErr::UnresolvedAddress(addr) => {
+                write!(f, "{}: address operand was never resolved", addr)
+            }
+            LoadErr::InconsistentSnapshot(e) => write!(f, "inconsistent snapshot: {}", e),
+            LoadErr::CodeMismatch => write!(f, "compiled code does not match the loaded program"),
+        }
+    }
+}
+
+/// Every error the library can report.
+/// ```
+/// use bm::{interpreter::InterpreterErr, Error, Instruction, LoadErr, BM, BM_PROGRAM_CAPACITY};
+/// let mut bm: BM = Default::default();
+/// let program = vec![Instruction::Nop; BM_PROGRAM_CAPACITY + 1];
+/// assert!(matches!(
+///     bm.load_program_from_memory(&program),
+///     Err(Error::Load(LoadErr::ProgramTooLarge { .. }))
+/// ));
+/// bm.push_inst(Instruction::Plus).unwrap();
+/// let e = bm.execute_program(None).unwrap_err();
+/// assert_eq!(e.as_runtime(), Some(&InterpreterErr::StackUnderflow));
+/// ```
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing a stream failed
+    Io(std::io::Error),
+    /// A .bm file could not be read or written
+    Format(FormatErr),
+    /// The basm source could not be assembled, holds every diagnostic reported
+    Assemble(Vec<Diagnostic>),
+    /// The program could not be loaded into the virtual machine
+    Load(LoadErr),
+    /// The program failed the static verification, holds every error found
+    Verify(Vec<VerifyErr>),
+    /// The program failed while executing
+    Runtime(InterpreterErr),
+}
+
+impl Error {
+    /// The runtime error, if the program failed while executing.
+    pub fn as_runtime(&self) -> Option<&InterpreterErr> {
+        match self {
+            Error::Runtime(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Format(e) => write!(f, "{}", e),
+            Error::Assemble(diagnostics) => {
+                let errors = diagnostics.iter().filter(|d| d.is_error()).count();
+                write!(f, "could not assemble due to {} error(s)", errors)
+            }
+            Error::Load(e) => write!(f, "{}", e),
+            Error::Verify(errors) => {
+                write!(
+                    f,
+                    "program failed verification due to {} error(s)",
+                    errors.len()
+                )
+            }
+            // Kept as is, the runtime errors are what programs are checked against
+            Error::Runtime(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Format(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<FormatErr> for Error {
+    fn from(e: FormatErr) -> Self {
+        Error::Format(e)
+    }
+}
+
+impl From<LoadErr> for Error {
+    fn from(e: LoadErr) -> Self {
+        Error::Load(e)
+    }
+}
+
+impl From<InterpreterErr> for Error {
+    fn from(e: InterpreterErr) -> Self {
+        Error::Runtime(e)
+    }
+}
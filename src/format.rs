@@ -0,0 +1,546 @@
+use std::fmt::Display;
+use std::io::{Read, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::snapshot::MachineState;
+use crate::{Instruction, Word};
+
+/// Magic bytes every .bm file starts with.
+pub const BM_MAGIC: [u8; 4] = *b"\x7fBM\x00";
+/// Version of the container format written by this build.
+/// Version 2 added the source file of every debug entry.
+pub const BM_FORMAT_VERSION: u16 = 2;
+/// Version reported for headerless files holding a bare list of instructions.
+pub const BM_LEGACY_VERSION: u16 = 0;
+/// Flag marking a relocatable object that has to be linked before it can run.
+pub const BM_FLAG_OBJECT: u16 = 1;
+
+/// Size of the fixed header: magic, version, flags and section count.
+const HEADER_SIZE: usize = 12;
+/// Size of one entry of the section table: kind, offset and size.
+const SECTION_ENTRY_SIZE: usize = 12;
+/// Size of the trailing CRC32.
+const CHECKSUM_SIZE: usize = 4;
+
+/// Kinds of sections a .bm file can contain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SectionKind {
+    /// The instructions of the program
+    Code,
+    /// Initial contents of the data memory
+    Data,
+    /// Label names and their addresses
+    Symbols,
+    /// Mapping from instruction addresses to source lines
+    Debug,
+    /// Symbols an object makes visible to other objects
+    Exports,
+    /// Symbols an object expects other objects to define
+    Imports,
+    /// Operands the linker has to patch
+    Relocations,
+    /// Execution state of a virtual machine, for snapshots
+    State,
+    /// Paths of the source files debug entries refer to
+    Sources,
+}
+
+impl SectionKind {
+    fn id(self) -> u32 {
+        match self {
+            SectionKind::Code => 1,
+            SectionKind::Data => 2,
+            SectionKind::Symbols => 3,
+            SectionKind::Debug => 4,
+            SectionKind::Exports => 5,
+            SectionKind::Imports => 6,
+            SectionKind::Relocations => 7,
+            SectionKind::State => 8,
+            SectionKind::Sources => 9,
+        }
+    }
+
+    fn from_id(id: u32) -> Option<Self> {
+        match id {
+            1 => Some(SectionKind::Code),
+            2 => Some(SectionKind::Data),
+            3 => Some(SectionKind::Symbols),
+            4 => Some(SectionKind::Debug),
+            5 => Some(SectionKind::Exports),
+            6 => Some(SectionKind::Imports),
+            7 => Some(SectionKind::Relocations),
+            8 => Some(SectionKind::State),
+            9 => Some(SectionKind::Sources),
+            _ => None,
+        }
+    }
+}
+
+impl Display for SectionKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SectionKind::Code => write!(f, "code"),
+            SectionKind::Data => write!(f, "data"),
+            SectionKind::Symbols => write!(f, "symbols"),
+            SectionKind::Debug => write!(f, "debug"),
+            SectionKind::Exports => write!(f, "exports"),
+            SectionKind::Imports => write!(f, "imports"),
+            SectionKind::Relocations => write!(f, "relocations"),
+            SectionKind::State => write!(f, "state"),
+            SectionKind::Sources => write!(f, "sources"),
+        }
+    }
+}
+
+/// A named address in the program, usually a label from the basm source.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: Word,
+}
+
+/// Source line an instruction was assembled from.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DebugEntry {
+    pub addr: Word,
+    pub line: usize,
+    /// Index in `BmFile::sources` of the file the line is in
+    pub file: usize,
+}
+
+/// An address operand of a relocatable object that has to be patched when linking.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Relocation {
+    /// Address of the instruction whose operand is patched
+    pub addr: Word,
+    /// Imported symbol the operand refers to, `None` when the operand is an address
+    /// inside of the object that moves along with it
+    pub symbol: Option<String>,
+}
+
+/// Errors that can be emitted while reading or writing a .bm file
+#[derive(Debug)]
+pub enum FormatErr {
+    Io(std::io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    TruncatedHeader,
+    TruncatedSection(SectionKind),
+    ChecksumMismatch { expected: u32, found: u32 },
+    MalformedSection(SectionKind, String),
+    MissingSection(SectionKind),
+}
+
+impl Display for FormatErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FormatErr::Io(e) => write!(f, "I/O error: {}", e),
+            FormatErr::BadMagic => write!(f, "not a bm file (bad magic)"),
+            FormatErr::UnsupportedVersion(v) => write!(
+                f,
+                "unsupported format version {} (this build reads up to {})",
+                v, BM_FORMAT_VERSION
+            ),
+            FormatErr::TruncatedHeader => write!(f, "file is truncated inside the header"),
+            FormatErr::TruncatedSection(kind) => {
+                write!(f, "file is truncated inside the {} section", kind)
+            }
+            FormatErr::ChecksumMismatch { expected, found } => write!(
+                f,
+                "checksum mismatch: expected {:#010x}, found {:#010x}",
+                expected, found
+            ),
+            FormatErr::MalformedSection(kind, e) => {
+                write!(f, "malformed {} section: {}", kind, e)
+            }
+            FormatErr::MissingSection(kind) => write!(f, "file has no {} section", kind),
+        }
+    }
+}
+
+impl std::error::Error for FormatErr {}
+
+impl From<std::io::Error> for FormatErr {
+    fn from(e: std::io::Error) -> Self {
+        FormatErr::Io(e)
+    }
+}
+
+/// In-memory representation of a .bm file.
+///
+/// Layout on disk (all integers little endian):
+/// - header: magic (4 bytes), version (u16), flags (u16), section count (u32)
+/// - section table: one entry per section with kind (u32), offset (u32) and size (u32)
+/// - section payloads
+/// - CRC32 of everything before it (u32)
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct BmFile {
+    /// Format version the file was read as, `BM_LEGACY_VERSION` for headerless files
+    pub version: u16,
+    /// `BM_FLAG_OBJECT` marks relocatable objects; unknown bits are preserved but ignored
+    pub flags: u16,
+    pub code: Vec<Instruction>,
+    pub data: Vec<u8>,
+    pub symbols: Vec<Symbol>,
+    pub debug: Vec<DebugEntry>,
+    /// Paths of the source files, the first one being the file given to the assembler
+    pub sources: Vec<String>,
+    pub exports: Vec<Symbol>,
+    pub imports: Vec<String>,
+    pub relocations: Vec<Relocation>,
+    /// Present in snapshots, the data section then holds the memory of the machine
+    pub state: Option<MachineState>,
+}
+
+impl BmFile {
+    /// Create a file holding only a code section.
+    pub fn new(code: Vec<Instruction>) -> Self {
+        Self {
+            version: BM_FORMAT_VERSION,
+            code,
+            ..Default::default()
+        }
+    }
+
+    /// Whether the file is a relocatable object rather than a program ready to run.
+    pub fn is_object(&self) -> bool {
+        self.flags & BM_FLAG_OBJECT != 0
+    }
+
+    /// Find the name of the symbol pointing at `addr`, if any.
+    pub fn symbol_at(&self, addr: Word) -> Option<&str> {
+        self.symbols
+            .iter()
+            .find(|s| s.addr == addr)
+            .map(|s| s.name.as_str())
+    }
+
+    /// Write the file in the current format version.
+    pub fn write_to<W>(&self, mut w: W) -> Result<(), FormatErr>
+    where
+        W: Write,
+    {
+        let mut sections = vec![section(SectionKind::Code, &self.code)?];
+        if !self.data.is_empty() {
+            sections.push((SectionKind::Data, self.data.clone()));
+        }
+        if !self.symbols.is_empty() {
+            sections.push(section(SectionKind::Symbols, &self.symbols)?);
+        }
+        if !self.debug.is_empty() {
+            sections.push(section(SectionKind::Debug, &self.debug)?);
+        }
+        if !self.sources.is_empty() {
+            sections.push(section(SectionKind::Sources, &self.sources)?);
+        }
+        if !self.exports.is_empty() {
+            sections.push(section(SectionKind::Exports, &self.exports)?);
+        }
+        if !self.imports.is_empty() {
+            sections.push(section(SectionKind::Imports, &self.imports)?);
+        }
+        if !self.relocations.is_empty() {
+            sections.push(section(SectionKind::Relocations, &self.relocations)?);
+        }
+        if let Some(state) = &self.state {
+            sections.push(section(SectionKind::State, state)?);
+        }
+
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&BM_MAGIC);
+        buf.extend_from_slice(&BM_FORMAT_VERSION.to_le_bytes());
+        buf.extend_from_slice(&self.flags.to_le_bytes());
+        buf.extend_from_slice(&(sections.len() as u32).to_le_bytes());
+
+        let mut offset = HEADER_SIZE + sections.len() * SECTION_ENTRY_SIZE;
+        for (kind, payload) in &sections {
+            buf.extend_from_slice(&kind.id().to_le_bytes());
+            buf.extend_from_slice(&(offset as u32).to_le_bytes());
+            buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+            offset += payload.len();
+        }
+        for (_, payload) in &sections {
+            buf.extend_from_slice(payload);
+        }
+        let checksum = crc32(&buf);
+        buf.extend_from_slice(&checksum.to_le_bytes());
+
+        w.write_all(&buf)?;
+        Ok(())
+    }
+
+    /// Read a file, accepting both the current format and legacy headerless files.
+    pub fn read_from<R>(mut r: R) -> Result<Self, FormatErr>
+    where
+        R: Read,
+    {
+        let mut buf = Vec::new();
+        r.read_to_end(&mut buf)?;
+
+        if !buf.starts_with(&BM_MAGIC) {
+            return Self::read_legacy(&buf);
+        }
+        if buf.len() < HEADER_SIZE {
+            return Err(FormatErr::TruncatedHeader);
+        }
+
+        let version = read_u16(&buf, 4);
+        if version == BM_LEGACY_VERSION || version > BM_FORMAT_VERSION {
+            return Err(FormatErr::UnsupportedVersion(version));
+        }
+        let flags = read_u16(&buf, 6);
+        let count = read_u32(&buf, 8) as usize;
+
+        let table_end = count
+            .checked_mul(SECTION_ENTRY_SIZE)
+            .and_then(|n| n.checked_add(HEADER_SIZE))
+            .ok_or(FormatErr::TruncatedHeader)?;
+        if buf.len() < table_end + CHECKSUM_SIZE {
+            return Err(FormatErr::TruncatedHeader);
+        }
+        let body_end = buf.len() - CHECKSUM_SIZE;
+
+        let mut sections = Vec::new();
+        for i in 0..count {
+            let entry = HEADER_SIZE + i * SECTION_ENTRY_SIZE;
+            // Sections unknown to this build are skipped
+            let kind = match SectionKind::from_id(read_u32(&buf, entry)) {
+                Some(kind) => kind,
+                None => continue,
+            };
+            let offset = read_u32(&buf, entry + 4) as usize;
+            let size = read_u32(&buf, entry + 8) as usize;
+            match offset.checked_add(size) {
+                Some(end) if offset >= table_end && end <= body_end => {
+                    sections.push((kind, &buf[offset..end]))
+                }
+                _ => return Err(FormatErr::TruncatedSection(kind)),
+            }
+        }
+
+        let expected = read_u32(&buf, body_end);
+        let found = crc32(&buf[..body_end]);
+        if expected != found {
+            return Err(FormatErr::ChecksumMismatch { expected, found });
+        }
+
+        let mut file = Self {
+            version,
+            flags,
+            ..Default::default()
+        };
+        for (kind, payload) in sections {
+            match kind {
+                SectionKind::Code => file.code = decode(kind, payload)?,
+                SectionKind::Data => file.data = payload.to_vec(),
+                SectionKind::Symbols => file.symbols = decode(kind, payload)?,
+                // Debug entries of version 1 only refer to the file given to the assembler
+                SectionKind::Debug if version == 1 => {
+                    let entries: Vec<(Word, usize)> = decode(kind, payload)?;
+                    file.debug = entries
+                        .into_iter()
+                        .map(|(addr, line)| DebugEntry {
+                            addr,
+                            line,
+                            file: 0,
+                        })
+                        .collect();
+                }
+                SectionKind::Debug => file.debug = decode(kind, payload)?,
+                SectionKind::Sources => file.sources = decode(kind, payload)?,
+                SectionKind::Exports => file.exports = decode(kind, payload)?,
+                SectionKind::Imports => file.imports = decode(kind, payload)?,
+                SectionKind::Relocations => file.relocations = decode(kind, payload)?,
+                SectionKind::State => file.state = Some(decode(kind, payload)?),
+            }
+        }
+        Ok(file)
+    }
+
+    /// Files written before the container format are a bare bincode list of instructions.
+    fn read_legacy(buf: &[u8]) -> Result<Self, FormatErr> {
+        let mut rest = buf;
+        match bincode::deserialize_from::<_, Vec<Instruction>>(&mut rest) {
+            Ok(code) if rest.is_empty() => Ok(Self {
+                version: BM_LEGACY_VERSION,
+                code,
+                ..Default::default()
+            }),
+            _ => Err(FormatErr::BadMagic),
+        }
+    }
+}
+
+/// Encode the payload of a section.
+fn section<T>(kind: SectionKind, value: &T) -> Result<(SectionKind, Vec<u8>), FormatErr>
+where
+    T: Serialize,
+{
+    let payload =
+        bincode::serialize(value).map_err(|e| FormatErr::MalformedSection(kind, e.to_string()))?;
+    Ok((kind, payload))
+}
+
+fn decode<'a, T>(kind: SectionKind, payload: &'a [u8]) -> Result<T, FormatErr>
+where
+    T: Deserialize<'a>,
+{
+    bincode::deserialize(payload).map_err(|e| FormatErr::MalformedSection(kind, e.to_string()))
+}
+
+fn read_u16(buf: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([buf[at], buf[at + 1]])
+}
+
+fn read_u32(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+/// CRC-32 (IEEE 802.3) of `bytes`.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &b in bytes {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode a file with the given version and raw sections, checksum included.
+    fn encode(version: u16, sections: &[(u32, Vec<u8>)]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&BM_MAGIC);
+        buf.extend_from_slice(&version.to_le_bytes());
+        buf.extend_from_slice(&0u16.to_le_bytes());
+        buf.extend_from_slice(&(sections.len() as u32).to_le_bytes());
+        let mut offset = HEADER_SIZE + sections.len() * SECTION_ENTRY_SIZE;
+        for (kind, payload) in sections {
+            buf.extend_from_slice(&kind.to_le_bytes());
+            buf.extend_from_slice(&(offset as u32).to_le_bytes());
+            buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+            offset += payload.len();
+        }
+        for (_, payload) in sections {
+            buf.extend_from_slice(payload);
+        }
+        let checksum = crc32(&buf);
+        buf.extend_from_slice(&checksum.to_le_bytes());
+        buf
+    }
+
+    #[test]
+    fn version_1_debug_entries_refer_to_the_main_file() {
+        let code = bincode::serialize(&vec![Instruction::Halt]).unwrap();
+        let debug = bincode::serialize(&vec![(0 as Word, 3usize)]).unwrap();
+        let buf = encode(1, &[(1, code), (4, debug)]);
+        let file = BmFile::read_from(buf.as_slice()).unwrap();
+        assert_eq!(
+            file.debug,
+            vec![DebugEntry {
+                addr: 0,
+                line: 3,
+                file: 0
+            }]
+        );
+    }
+
+    #[test]
+    fn debug_entries_and_sources_round_trip() {
+        let file = BmFile {
+            debug: vec![DebugEntry {
+                addr: 0,
+                line: 2,
+                file: 1,
+            }],
+            sources: vec!["main.basm".to_string(), "lib.basm".to_string()],
+            ..BmFile::new(vec![Instruction::Halt])
+        };
+        let mut buf = Vec::new();
+        file.write_to(&mut buf).unwrap();
+        assert_eq!(BmFile::read_from(buf.as_slice()).unwrap(), file);
+    }
+
+    fn written(file: &BmFile) -> Vec<u8> {
+        let mut buf = Vec::new();
+        file.write_to(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn truncated_header() {
+        let buf = written(&BmFile::new(vec![Instruction::Halt]));
+        for len in [BM_MAGIC.len(), HEADER_SIZE - 1, HEADER_SIZE + 1] {
+            assert!(
+                matches!(
+                    BmFile::read_from(&buf[..len]),
+                    Err(FormatErr::TruncatedHeader)
+                ),
+                "{} bytes",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn truncated_section() {
+        let mut buf = encode(BM_FORMAT_VERSION, &[(1, vec![0; 8])]);
+        // Claim the code section is longer than the file
+        let size = HEADER_SIZE + 8;
+        buf[size..size + 4].copy_from_slice(&100u32.to_le_bytes());
+        assert!(matches!(
+            BmFile::read_from(buf.as_slice()),
+            Err(FormatErr::TruncatedSection(SectionKind::Code))
+        ));
+    }
+
+    #[test]
+    fn checksum_mismatch() {
+        let mut buf = written(&BmFile::new(vec![Instruction::Push(1), Instruction::Halt]));
+        let last = buf.len() - CHECKSUM_SIZE - 1;
+        buf[last] ^= 0xff;
+        assert!(matches!(
+            BmFile::read_from(buf.as_slice()),
+            Err(FormatErr::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let buf = encode(BM_FORMAT_VERSION + 1, &[]);
+        assert!(matches!(
+            BmFile::read_from(buf.as_slice()),
+            Err(FormatErr::UnsupportedVersion(v)) if v == BM_FORMAT_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn headerless_files_are_read_as_legacy_code() {
+        let code = vec![Instruction::Push(1), Instruction::Halt];
+        let buf = bincode::serialize(&code).unwrap();
+        let file = BmFile::read_from(buf.as_slice()).unwrap();
+        assert_eq!(file.version, BM_LEGACY_VERSION);
+        assert_eq!(file.code, code);
+    }
+
+    #[test]
+    fn garbage_is_not_a_legacy_file() {
+        let mut buf = bincode::serialize(&vec![Instruction::Halt]).unwrap();
+        buf.push(0);
+        assert!(matches!(
+            BmFile::read_from(buf.as_slice()),
+            Err(FormatErr::BadMagic)
+        ));
+        assert!(matches!(
+            BmFile::read_from(&b"hello"[..]),
+            Err(FormatErr::BadMagic)
+        ));
+    }
+}
@@ -2,7 +2,7 @@ use std::fmt::Display;
 
 use serde::{Deserialize, Serialize};
 
-use crate::{Word, BM};
+use crate::{f64_to_word, Word, BM};
 
 use super::serialize_deserialize::BasmCtx;
 
@@ -11,11 +11,13 @@ use super::serialize_deserialize::BasmCtx;
 type Address = Option<Word>;
 
 /// Instruction represents a singular operation that the virtual machine executes.
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
 pub enum Instruction {
     /// No Operation
+    #[default]
     Nop,
-    /// Push the operand on stack
+    /// Push the operand on stack. Written in basm as an integer or a float literal like
+    /// `1.5`, `-.5` or `2e10`, pushing the bit pattern of the float
     Push(Word),
     /// Duplicate the element which is "operand" places from the top
     Dup(Word),
@@ -29,18 +31,111 @@ pub enum Instruction {
     Mult,
     /// Jump to an address
     Jump(Address),
-    /// Jump to given address if the top of stack isn't zero
+    /// Pop the top of the stack and jump to given address if it isn't zero.
+    /// Written `jmpif` or `jmpnz` in basm.
     JumpIf(Address),
     /// Check if top 2 elements of the stack are equal
     Eq,
     /// Halt program execution
     Halt,
     PrintDebug,
+    // New variants go last: the position of a variant is its encoding in .bm files
+    /// Push the address of the next instruction on the call stack and jump to an address
+    Call(Address),
+    /// Pop an address from the call stack and jump to it
+    Ret,
+    /// Replace the address on top of the stack by the byte stored at it
+    Load8,
+    /// Replace the address on top of the stack by the 16-bit word stored at it
+    Load16,
+    /// Replace the address on top of the stack by the 32-bit word stored at it
+    Load32,
+    /// Replace the address on top of the stack by the 64-bit word stored at it
+    Load64,
+    /// Store the lowest byte of the top element at the address below it
+    Store8,
+    /// Store the lowest 16 bits of the top element at the address below it
+    Store16,
+    /// Store the lowest 32 bits of the top element at the address below it
+    Store32,
+    /// Store the top element at the address below it
+    Store64,
+    /// Call the host function registered under the given name
+    Native(String),
+    /// Check if the second element is less than the top element
+    Lt,
+    /// Check if the second element is greater than the top element
+    Gt,
+    /// Check if the second element is less than or equal to the top element
+    Le,
+    /// Check if the second element is greater than or equal to the top element
+    Ge,
+    /// Check if top 2 elements of the stack are different
+    Ne,
+    /// Logical negation of the top element: 1 if it is zero, 0 otherwise
+    Not,
+    /// 1 if both top 2 elements are non zero, 0 otherwise
+    And,
+    /// 1 if any of the top 2 elements is non zero, 0 otherwise
+    Or,
+    /// 1 if exactly one of the top 2 elements is non zero, 0 otherwise
+    Xor,
+    /// Pop the top of the stack and jump to given address if it is zero
+    JumpZero(Address),
+    // Floating point instructions treat words as the bit pattern of an f64
+    /// Add the top 2 elements of the stack as floats
+    FPlus,
+    /// Subtract the top element of the stack from the one after it as floats
+    FMinus,
+    /// Multiply the top 2 elements of the stack as floats
+    FMult,
+    /// Divide the second top element by the top element as floats
+    FDiv,
+    /// Convert the integer on top of the stack to a float
+    I2F,
+    /// Convert the float on top of the stack to an integer, rounding toward zero
+    F2I,
+    /// Check if top 2 elements of the stack are equal as floats
+    FEq,
+    /// Check if the second element is less than the top element as floats
+    FLt,
+    /// Check if the second element is greater than the top element as floats
+    FGt,
+    /// Check if the second element is less than or equal to the top element as floats
+    FLe,
+    /// Check if the second element is greater than or equal to the top element as floats
+    FGe,
+    /// Discard the top element of the stack
+    Drop,
+    /// Swap the top element with the element which is "operand" places below it
+    Swap(Word),
+    /// Duplicate the second element of the stack on top of it
+    Over,
+    /// Move the third element of the stack to the top
+    Rot,
+    /// Pop n and duplicate the element which is n places from the top
+    Pick,
+    /// Pop n and move the element which is n places from the top to the top
+    Roll,
+    /// Read a character from the input and push its code point, or -1 at the end of the input
+    ReadChar,
+    /// Pop a code point and write the character to the output
+    WriteChar,
+    /// Read a decimal integer from the input and push it
+    ReadInt,
+    /// Pop the top element and write it to the output as a decimal integer
+    WriteInt,
 }
 
-impl Default for Instruction {
-    fn default() -> Self {
-        Self::Nop
+/// Displays an address operand, or `?` if it is not resolved yet.
+struct DisplayAddr<'a>(&'a Address);
+
+impl Display for DisplayAddr<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Some(addr) => write!(f, "{}", addr),
+            None => write!(f, "?"),
+        }
     }
 }
 
@@ -53,9 +148,51 @@ impl Display for Instruction {
             Instruction::Minus => write!(f, "minus"),
             Instruction::Div => write!(f, "div"),
             Instruction::Mult => write!(f, "mult"),
-            Instruction::Jump(addr) => write!(f, "jmp {}", addr.unwrap()),
-            Instruction::JumpIf(addr) => write!(f, "jmp_if({})", addr.unwrap()),
+            Instruction::Jump(addr) => write!(f, "jmp {}", DisplayAddr(addr)),
+            Instruction::JumpIf(addr) => write!(f, "jmpif {}", DisplayAddr(addr)),
             Instruction::Eq => write!(f, "eq"),
+            Instruction::Call(addr) => write!(f, "call {}", DisplayAddr(addr)),
+            Instruction::Ret => write!(f, "ret"),
+            Instruction::Load8 => write!(f, "load8"),
+            Instruction::Load16 => write!(f, "load16"),
+            Instruction::Load32 => write!(f, "load32"),
+            Instruction::Load64 => write!(f, "load64"),
+            Instruction::Store8 => write!(f, "store8"),
+            Instruction::Store16 => write!(f, "store16"),
+            Instruction::Store32 => write!(f, "store32"),
+            Instruction::Store64 => write!(f, "store64"),
+            Instruction::Native(name) => write!(f, "native {}", name),
+            Instruction::Lt => write!(f, "lt"),
+            Instruction::Gt => write!(f, "gt"),
+            Instruction::Le => write!(f, "le"),
+            Instruction::Ge => write!(f, "ge"),
+            Instruction::Ne => write!(f, "ne"),
+            Instruction::Not => write!(f, "not"),
+            Instruction::And => write!(f, "and"),
+            Instruction::Or => write!(f, "or"),
+            Instruction::Xor => write!(f, "xor"),
+            Instruction::JumpZero(addr) => write!(f, "jmpz {}", DisplayAddr(addr)),
+            Instruction::FPlus => write!(f, "fplus"),
+            Instruction::FMinus => write!(f, "fminus"),
+            Instruction::FMult => write!(f, "fmult"),
+            Instruction::FDiv => write!(f, "fdiv"),
+            Instruction::I2F => write!(f, "i2f"),
+            Instruction::F2I => write!(f, "f2i"),
+            Instruction::FEq => write!(f, "feq"),
+            Instruction::FLt => write!(f, "flt"),
+            Instruction::FGt => write!(f, "fgt"),
+            Instruction::FLe => write!(f, "fle"),
+            Instruction::FGe => write!(f, "fge"),
+            Instruction::Drop => write!(f, "drop"),
+            Instruction::Swap(op) => write!(f, "swap {}", op),
+            Instruction::Over => write!(f, "over"),
+            Instruction::Rot => write!(f, "rot"),
+            Instruction::Pick => write!(f, "pick"),
+            Instruction::Roll => write!(f, "roll"),
+            Instruction::ReadChar => write!(f, "read_char"),
+            Instruction::WriteChar => write!(f, "write_char"),
+            Instruction::ReadInt => write!(f, "read_int"),
+            Instruction::WriteInt => write!(f, "write_int"),
             Instruction::Halt => write!(f, "halt"),
             Instruction::PrintDebug => write!(f, "print_debug"),
             Instruction::Dup(addr) => write!(f, "dup {}", addr),
@@ -91,72 +228,285 @@ impl Display for InstructionParseErr {
     }
 }
 
+/// Every mnemonic understood by the basm parser.
+pub(crate) const MNEMONICS: &[&str] = &[
+    "nop",
+    "push",
+    "dup",
+    "swap",
+    "drop",
+    "over",
+    "rot",
+    "pick",
+    "roll",
+    "plus",
+    "minus",
+    "div",
+    "mult",
+    "jmp",
+    "jmpif",
+    "jmpnz",
+    "jmpz",
+    "eq",
+    "ne",
+    "lt",
+    "gt",
+    "le",
+    "ge",
+    "not",
+    "and",
+    "or",
+    "xor",
+    "fplus",
+    "fminus",
+    "fmult",
+    "fdiv",
+    "i2f",
+    "f2i",
+    "feq",
+    "flt",
+    "fgt",
+    "fle",
+    "fge",
+    "call",
+    "ret",
+    "load8",
+    "load16",
+    "load32",
+    "load64",
+    "store8",
+    "store16",
+    "store32",
+    "store64",
+    "native",
+    "halt",
+    "print_debug",
+    "read_char",
+    "write_char",
+    "read_int",
+    "write_int",
+];
+
+/// A whitespace separated word of a basm line along with its 1-based column.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Token<'a> {
+    pub text: &'a str,
+    pub col: usize,
+}
+
+/// Split a basm line (with the comment already removed) into tokens.
+pub(crate) fn tokenize(line: &str) -> impl Iterator<Item = Token<'_>> {
+    line.split_whitespace().map(move |text| {
+        let offset = text.as_ptr() as usize - line.as_ptr() as usize;
+        Token {
+            text,
+            col: line[..offset].chars().count() + 1,
+        }
+    })
+}
+
+/// Strip the trailing `#` comment from a basm line.
+pub(crate) fn strip_comment(line: &str) -> &str {
+    line.split_once('#').map_or(line, |(code, _)| code)
+}
+
+/// Parse a float literal: digits with a `.` or an exponent. Unlike `str::parse`, `inf`,
+/// `infinity` and `nan` are rejected.
+fn float_literal(text: &str) -> Option<f64> {
+    let unsigned = text.strip_prefix(['-', '+']).unwrap_or(text);
+    let numeric = unsigned.starts_with(|c: char| c.is_ascii_digit() || c == '.')
+        && unsigned
+            .chars()
+            .all(|c| c.is_ascii_digit() || matches!(c, '.' | 'e' | 'E' | '-' | '+'));
+    match numeric {
+        true => text.parse().ok(),
+        false => None,
+    }
+}
+
+/// Parse an address operand. Labels are recorded in `ctx` and resolved once the whole
+/// program is parsed.
+fn address_operand(
+    op: Option<Token>,
+    line: &str,
+    bm: &BM,
+    ctx: &mut BasmCtx,
+) -> Result<Address, InstructionParseErr> {
+    let op = op.ok_or_else(|| InstructionParseErr::OperandNotFound(line.to_string()))?;
+    match op.text.parse::<Word>() {
+        Ok(addr) => Ok(Some(addr)),
+        Err(_) => {
+            let span = ctx.span(op.col, op.text.chars().count());
+            ctx.add_deffered_opperand(bm.program.len() as Word, op.text.to_string(), span);
+            Ok(None)
+        }
+    }
+}
+
 /// Parse a singular instruction from assembly text.
 impl Instruction {
+    /// Address operand of instructions transferring control, if resolved.
+    pub fn target(&self) -> Option<Word> {
+        match self {
+            Instruction::Jump(addr)
+            | Instruction::JumpIf(addr)
+            | Instruction::JumpZero(addr)
+            | Instruction::Call(addr) => *addr,
+            _ => None,
+        }
+    }
+
+    /// Whether the instruction transfers control to an address that was never resolved.
+    pub fn is_unresolved(&self) -> bool {
+        matches!(
+            self,
+            Instruction::Jump(None)
+                | Instruction::JumpIf(None)
+                | Instruction::JumpZero(None)
+                | Instruction::Call(None)
+        )
+    }
+
+    /// Mutable access to the address operand of instructions transferring control.
+    pub fn target_mut(&mut self) -> Option<&mut Option<Word>> {
+        match self {
+            Instruction::Jump(addr)
+            | Instruction::JumpIf(addr)
+            | Instruction::JumpZero(addr)
+            | Instruction::Call(addr) => Some(addr),
+            _ => None,
+        }
+    }
+
     pub fn from_asm(
         line: &str,
         bm: &BM,
         ctx: &mut BasmCtx,
     ) -> Result<Instruction, InstructionParseErr> {
-        let line = line.trim_start();
-        if line.len() == 0 {
-            return Err(InstructionParseErr::EmptyLine);
-        }
-        let (comment_removed_line, _) = line.split_once("#").unwrap_or((line, ""));
-        let mut line_iter = comment_removed_line.split_whitespace();
-        let mut name = line_iter.next().unwrap();
-
-        if name.len() > 0 && name.ends_with(":") {
-            ctx.insert_label(
-                name[0..name.len() - 1].to_string(),
-                bm.program.len() as Word,
-            );
-            name = line_iter.next().unwrap();
+        let mut tokens = tokenize(strip_comment(line));
+        let mut name = tokens.next().ok_or(InstructionParseErr::EmptyLine)?;
+
+        if let Some(label) = name.text.strip_suffix(':') {
+            let span = ctx.span(name.col, label.chars().count());
+            ctx.insert_label(label.to_string(), bm.program.len() as Word, span);
+            // A line holding only a label does not produce an instruction
+            name = tokens.next().ok_or(InstructionParseErr::EmptyLine)?;
         }
 
-        match name {
+        match name.text {
             "nop" => Ok(Self::Nop),
-            "push" => match line_iter.next() {
-                Some(op) => match op.parse::<Word>() {
-                    Ok(op) => Ok(Self::Push(op)),
-                    Err(_) => Err(InstructionParseErr::InvalidOperand(line.to_string())),
+            "push" => match tokens.next() {
+                Some(op) => match (op.text.parse::<Word>(), float_literal(op.text)) {
+                    (Ok(op), _) => Ok(Self::Push(op)),
+                    (_, Some(op)) => Ok(Self::Push(f64_to_word(op))),
+                    _ => Err(InstructionParseErr::InvalidOperand(line.to_string())),
                 },
                 None => Err(InstructionParseErr::OperandNotFound(line.to_string())),
             },
-            "dup" => match line_iter.next() {
-                Some(op) => match op.parse::<Word>() {
+            "dup" => match tokens.next() {
+                Some(op) => match op.text.parse::<Word>() {
                     Ok(op) => Ok(Self::Dup(op)),
                     Err(_) => Err(InstructionParseErr::InvalidOperand(line.to_string())),
                 },
                 None => Err(InstructionParseErr::OperandNotFound(line.to_string())),
             },
+            "swap" => match tokens.next() {
+                Some(op) => match op.text.parse::<Word>() {
+                    Ok(op) => Ok(Self::Swap(op)),
+                    Err(_) => Err(InstructionParseErr::InvalidOperand(line.to_string())),
+                },
+                None => Err(InstructionParseErr::OperandNotFound(line.to_string())),
+            },
+            "drop" => Ok(Self::Drop),
+            "over" => Ok(Self::Over),
+            "rot" => Ok(Self::Rot),
+            "pick" => Ok(Self::Pick),
+            "roll" => Ok(Self::Roll),
             "plus" => Ok(Self::Plus),
             "minus" => Ok(Self::Minus),
             "div" => Ok(Self::Div),
             "mult" => Ok(Self::Mult),
-            "jmp" => match line_iter.next() {
-                Some(op) => match op.parse::<Word>() {
-                    Ok(op) => Ok(Self::Jump(Some(op))),
-                    Err(_) => {
-                        ctx.add_deffered_opperand(bm.program.len() as Word, op.to_string());
-                        Ok(Self::Jump(None))
-                    }
-                },
-                None => Err(InstructionParseErr::OperandNotFound(line.to_string())),
-            },
-            "jmpif" => match line_iter.next() {
-                Some(op) => match op.parse::<Word>() {
-                    Ok(op) => Ok(Self::JumpIf(Some(op))),
-                    Err(_) => {
-                        ctx.add_deffered_opperand(bm.program.len() as Word, op.to_string());
-                        Ok(Self::JumpIf(None))
-                    }
-                },
+            "jmp" => address_operand(tokens.next(), line, bm, ctx).map(Self::Jump),
+            "jmpif" => address_operand(tokens.next(), line, bm, ctx).map(Self::JumpIf),
+            "jmpnz" => address_operand(tokens.next(), line, bm, ctx).map(Self::JumpIf),
+            "jmpz" => address_operand(tokens.next(), line, bm, ctx).map(Self::JumpZero),
+            "eq" => Ok(Self::Eq),
+            "ne" => Ok(Self::Ne),
+            "lt" => Ok(Self::Lt),
+            "gt" => Ok(Self::Gt),
+            "le" => Ok(Self::Le),
+            "ge" => Ok(Self::Ge),
+            "not" => Ok(Self::Not),
+            "and" => Ok(Self::And),
+            "or" => Ok(Self::Or),
+            "xor" => Ok(Self::Xor),
+            "fplus" => Ok(Self::FPlus),
+            "fminus" => Ok(Self::FMinus),
+            "fmult" => Ok(Self::FMult),
+            "fdiv" => Ok(Self::FDiv),
+            "i2f" => Ok(Self::I2F),
+            "f2i" => Ok(Self::F2I),
+            "feq" => Ok(Self::FEq),
+            "flt" => Ok(Self::FLt),
+            "fgt" => Ok(Self::FGt),
+            "fle" => Ok(Self::FLe),
+            "fge" => Ok(Self::FGe),
+            "call" => address_operand(tokens.next(), line, bm, ctx).map(Self::Call),
+            "ret" => Ok(Self::Ret),
+            "load8" => Ok(Self::Load8),
+            "load16" => Ok(Self::Load16),
+            "load32" => Ok(Self::Load32),
+            "load64" => Ok(Self::Load64),
+            "store8" => Ok(Self::Store8),
+            "store16" => Ok(Self::Store16),
+            "store32" => Ok(Self::Store32),
+            "store64" => Ok(Self::Store64),
+            "native" => match tokens.next() {
+                Some(op) => Ok(Self::Native(op.text.to_string())),
                 None => Err(InstructionParseErr::OperandNotFound(line.to_string())),
             },
-            "eq" => Ok(Self::Eq),
             "halt" => Ok(Self::Halt),
+            "print_debug" => Ok(Self::PrintDebug),
+            "read_char" => Ok(Self::ReadChar),
+            "write_char" => Ok(Self::WriteChar),
+            "read_int" => Ok(Self::ReadInt),
+            "write_int" => Ok(Self::WriteInt),
             _ => Err(InstructionParseErr::InvalidInstruction(line.to_string())),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serialize_deserialize::BasmCtx;
+
+    #[test]
+    fn float_literals() {
+        assert_eq!(float_literal("1.5"), Some(1.5));
+        assert_eq!(float_literal("-.5"), Some(-0.5));
+        assert_eq!(float_literal("+2e3"), Some(2000.0));
+        assert_eq!(float_literal("1E-2"), Some(0.01));
+        for text in [
+            "inf", "-inf", "Infinity", "NaN", "nan", "e5", ".", "1.5.2", "1e",
+        ] {
+            assert_eq!(float_literal(text), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn push_operands() {
+        let bm: BM = Default::default();
+        let mut ctx = BasmCtx::default();
+        let mut push = |line: &str| Instruction::from_asm(line, &bm, &mut ctx);
+        assert!(matches!(push("push 3"), Ok(Instruction::Push(3))));
+        assert!(matches!(push("push 0.5"), Ok(Instruction::Push(w)) if w == f64_to_word(0.5)));
+        for line in ["push nan", "push inf", "push -Infinity"] {
+            assert!(
+                matches!(push(line), Err(InstructionParseErr::InvalidOperand(_))),
+                "{}",
+                line
+            );
+        }
+    }
+}
@@ -1,8 +1,9 @@
 use std::fmt::Display;
 
-use crate::{Instruction, BM};
+use crate::native::NativeStack;
+use crate::{f64_to_word, word_to_f64, Error, Instruction, BM};
 
-use super::{Word, BM_STACK_CAPACITY};
+use super::Word;
 
 /// Errors that can be emitted while interpretting instructions
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -12,6 +13,15 @@ pub enum InterpreterErr {
     DivideByZero,
     IllegalInstructionAccess(Word),
     IllegalOperand,
+    CallStackOverflow,
+    CallStackUnderflow,
+    IllegalMemoryAccess(Word),
+    UnknownNative(String),
+    NativeFailure(String),
+    InvalidInput(String),
+    Io(String),
+    UnresolvedAddress,
+    BudgetExhausted,
 }
 
 impl Display for InterpreterErr {
@@ -24,6 +34,17 @@ impl Display for InterpreterErr {
                 write!(f, "Err::IllegalInstructionAccess({})", ip)
             }
             Self::IllegalOperand => write!(f, "Err::IllegalOperand"),
+            Self::CallStackOverflow => write!(f, "Err::CallStackOverflow"),
+            Self::CallStackUnderflow => write!(f, "Err::CallStackUnderflow"),
+            Self::IllegalMemoryAccess(addr) => {
+                write!(f, "Err::IllegalMemoryAccess({})", addr)
+            }
+            Self::UnknownNative(name) => write!(f, "Err::UnknownNative({})", name),
+            Self::NativeFailure(e) => write!(f, "Err::NativeFailure({})", e),
+            Self::InvalidInput(e) => write!(f, "Err::InvalidInput({})", e),
+            Self::Io(e) => write!(f, "Err::Io({})", e),
+            Self::UnresolvedAddress => write!(f, "Err::UnresolvedAddress"),
+            Self::BudgetExhausted => write!(f, "Err::BudgetExhausted"),
         }
     }
 }
@@ -31,7 +52,7 @@ impl Display for InterpreterErr {
 impl BM {
     /// Execute all the instructions of a virtual machine.
     /// Accepts `limit` as the number of max instructions to be executed.
-    pub fn execute_program(&mut self, limit: Option<usize>) -> Result<(), InterpreterErr> {
+    pub fn execute_program(&mut self, limit: Option<usize>) -> Result<(), Error> {
         let mut i = 1;
         while !self.is_halted() {
             match limit {
@@ -45,7 +66,20 @@ impl BM {
     }
 
     /// Exucutes a single instruction
-    pub fn execute_instruction(&mut self) -> Result<(), InterpreterErr> {
+    pub fn execute_instruction(&mut self) -> Result<(), Error> {
+        if self
+            .config
+            .instruction_budget
+            .is_some_and(|budget| self.executed >= budget)
+        {
+            return Err(Error::Runtime(InterpreterErr::BudgetExhausted));
+        }
+        self.interpret().map_err(Error::Runtime)?;
+        self.executed += 1;
+        Ok(())
+    }
+
+    pub(crate) fn interpret(&mut self) -> Result<(), InterpreterErr> {
         if self.ip < 0 || self.program.len() as Word <= self.ip {
             return Err(InterpreterErr::IllegalInstructionAccess(self.ip));
         }
@@ -54,33 +88,97 @@ impl BM {
                 self.ip += 1;
             }
             Instruction::Push(op) => {
-                if self.stack.len() >= BM_STACK_CAPACITY {
+                if self.stack.len() >= self.config.stack_capacity {
                     return Err(InterpreterErr::StackOverflow);
                 }
                 self.stack.push(op);
                 self.ip += 1;
             }
             Instruction::Dup(op) => {
-                if self.stack.len() >= BM_STACK_CAPACITY {
+                if self.stack.len() >= self.config.stack_capacity {
                     return Err(InterpreterErr::StackOverflow);
                 }
+                if op < 0 {
+                    return Err(InterpreterErr::IllegalOperand);
+                }
                 if self.stack.len() as Word - op <= 0 {
                     return Err(InterpreterErr::StackUnderflow);
                 }
-
+                self.stack
+                    .push(self.stack[self.stack.len() - 1 - op as usize]);
+                self.ip += 1
+            }
+            Instruction::Drop => {
+                if self.stack.pop().is_none() {
+                    return Err(InterpreterErr::StackUnderflow);
+                }
+                self.ip += 1;
+            }
+            Instruction::Swap(op) => {
+                if op < 1 {
+                    return Err(InterpreterErr::IllegalOperand);
+                }
+                if self.stack.len() as Word - op <= 0 {
+                    return Err(InterpreterErr::StackUnderflow);
+                }
+                let stack_size = self.stack.len();
+                self.stack
+                    .swap(stack_size - 1, stack_size - 1 - op as usize);
+                self.ip += 1;
+            }
+            Instruction::Over => {
+                if self.stack.len() >= self.config.stack_capacity {
+                    return Err(InterpreterErr::StackOverflow);
+                }
+                if self.stack.len() < 2 {
+                    return Err(InterpreterErr::StackUnderflow);
+                }
+                self.stack.push(self.stack[self.stack.len() - 2]);
+                self.ip += 1;
+            }
+            Instruction::Rot => {
+                if self.stack.len() < 3 {
+                    return Err(InterpreterErr::StackUnderflow);
+                }
+                let stack_size = self.stack.len();
+                self.stack[stack_size - 3..].rotate_left(1);
+                self.ip += 1;
+            }
+            Instruction::Pick => {
+                let op = self.stack.pop().ok_or(InterpreterErr::StackUnderflow)?;
                 if op < 0 {
+                    self.stack.push(op);
                     return Err(InterpreterErr::IllegalOperand);
                 }
+                if self.stack.len() as Word - op <= 0 {
+                    self.stack.push(op);
+                    return Err(InterpreterErr::StackUnderflow);
+                }
                 self.stack
                     .push(self.stack[self.stack.len() - 1 - op as usize]);
-                self.ip += 1
+                self.ip += 1;
+            }
+            Instruction::Roll => {
+                let op = self.stack.pop().ok_or(InterpreterErr::StackUnderflow)?;
+                if op < 0 {
+                    self.stack.push(op);
+                    return Err(InterpreterErr::IllegalOperand);
+                }
+                if self.stack.len() as Word - op <= 0 {
+                    self.stack.push(op);
+                    return Err(InterpreterErr::StackUnderflow);
+                }
+                let stack_size = self.stack.len();
+                self.stack[stack_size - 1 - op as usize..].rotate_left(1);
+                self.ip += 1;
             }
             Instruction::Plus => {
                 if self.stack.len() < 2 {
                     return Err(InterpreterErr::StackUnderflow);
                 }
                 let stack_size = self.stack.len();
-                self.stack[stack_size - 2] += self.stack[self.stack.len() - 1];
+                self.stack[stack_size - 2] =
+                    self.stack[stack_size - 2].wrapping_add(self.stack[stack_size - 1]);
                 self.stack.pop();
                 self.ip += 1;
             }
@@ -89,7 +187,8 @@ impl BM {
                     return Err(InterpreterErr::StackUnderflow);
                 }
                 let stack_size = self.stack.len();
-                self.stack[stack_size - 2] -= self.stack[self.stack.len() - 1];
+                self.stack[stack_size - 2] =
+                    self.stack[stack_size - 2].wrapping_sub(self.stack[stack_size - 1]);
                 self.stack.pop();
                 self.ip += 1;
             }
@@ -98,7 +197,8 @@ impl BM {
                     return Err(InterpreterErr::StackUnderflow);
                 }
                 let stack_size = self.stack.len();
-                self.stack[stack_size - 2] *= self.stack[self.stack.len() - 1];
+                self.stack[stack_size - 2] =
+                    self.stack[stack_size - 2].wrapping_mul(self.stack[stack_size - 1]);
                 self.stack.pop();
                 self.ip += 1;
             }
@@ -110,20 +210,21 @@ impl BM {
                     return Err(InterpreterErr::DivideByZero);
                 }
                 let stack_size = self.stack.len();
-                self.stack[stack_size - 2] /= self.stack[self.stack.len() - 1];
+                self.stack[stack_size - 2] =
+                    self.stack[stack_size - 2].wrapping_div(self.stack[stack_size - 1]);
                 self.stack.pop();
                 self.ip += 1;
             }
             Instruction::Jump(addr) => {
-                self.ip = addr.expect("Address should be a number in interpretter");
+                self.ip = addr.ok_or(InterpreterErr::UnresolvedAddress)?;
             }
             Instruction::JumpIf(addr) => {
-                if self.stack.len() < 1 {
+                let addr = addr.ok_or(InterpreterErr::UnresolvedAddress)?;
+                if self.stack.is_empty() {
                     return Err(InterpreterErr::StackUnderflow);
                 }
-                if self.stack[self.stack.len() - 1] == 1 {
-                    self.stack.pop();
-                    self.ip = addr.expect("Address should be a number in interpretter");
+                if self.stack.pop() != Some(0) {
+                    self.ip = addr;
                 } else {
                     self.ip += 1;
                 }
@@ -138,18 +239,727 @@ impl BM {
                 self.stack.pop();
                 self.ip += 1;
             }
+            Instruction::Call(addr) => {
+                if self.call_stack.len() >= self.config.call_depth {
+                    return Err(InterpreterErr::CallStackOverflow);
+                }
+                let addr = addr.ok_or(InterpreterErr::UnresolvedAddress)?;
+                self.call_stack.push(self.ip + 1);
+                self.ip = addr;
+            }
+            Instruction::Ret => match self.call_stack.pop() {
+                Some(addr) => self.ip = addr,
+                None => return Err(InterpreterErr::CallStackUnderflow),
+            },
+            Instruction::Load8 => self.load(1)?,
+            Instruction::Load16 => self.load(2)?,
+            Instruction::Load32 => self.load(4)?,
+            Instruction::Load64 => self.load(8)?,
+            Instruction::Store8 => self.store(1)?,
+            Instruction::Store16 => self.store(2)?,
+            Instruction::Store32 => self.store(4)?,
+            Instruction::Store64 => self.store(8)?,
+            Instruction::Native(_) => self.call_native()?,
+            Instruction::JumpZero(addr) => {
+                let addr = addr.ok_or(InterpreterErr::UnresolvedAddress)?;
+                if self.stack.is_empty() {
+                    return Err(InterpreterErr::StackUnderflow);
+                }
+                if self.stack.pop() == Some(0) {
+                    self.ip = addr;
+                } else {
+                    self.ip += 1;
+                }
+            }
+            Instruction::Lt => self.binary_op(|a, b| (a < b) as Word)?,
+            Instruction::Gt => self.binary_op(|a, b| (a > b) as Word)?,
+            Instruction::Le => self.binary_op(|a, b| (a <= b) as Word)?,
+            Instruction::Ge => self.binary_op(|a, b| (a >= b) as Word)?,
+            Instruction::Ne => self.binary_op(|a, b| (a != b) as Word)?,
+            Instruction::And => self.binary_op(|a, b| (a != 0 && b != 0) as Word)?,
+            Instruction::Or => self.binary_op(|a, b| (a != 0 || b != 0) as Word)?,
+            Instruction::Xor => self.binary_op(|a, b| ((a != 0) != (b != 0)) as Word)?,
+            Instruction::FPlus => self.binary_op(|a, b| float_op(a, b, |a, b| a + b))?,
+            Instruction::FMinus => self.binary_op(|a, b| float_op(a, b, |a, b| a - b))?,
+            Instruction::FMult => self.binary_op(|a, b| float_op(a, b, |a, b| a * b))?,
+            Instruction::FDiv => self.binary_op(|a, b| float_op(a, b, |a, b| a / b))?,
+            Instruction::FEq => {
+                self.binary_op(|a, b| (word_to_f64(a) == word_to_f64(b)) as Word)?
+            }
+            Instruction::FLt => self.binary_op(|a, b| (word_to_f64(a) < word_to_f64(b)) as Word)?,
+            Instruction::FGt => self.binary_op(|a, b| (word_to_f64(a) > word_to_f64(b)) as Word)?,
+            Instruction::FLe => {
+                self.binary_op(|a, b| (word_to_f64(a) <= word_to_f64(b)) as Word)?
+            }
+            Instruction::FGe => {
+                self.binary_op(|a, b| (word_to_f64(a) >= word_to_f64(b)) as Word)?
+            }
+            Instruction::I2F => self.unary_op(|a| f64_to_word(a as f64))?,
+            Instruction::F2I => self.unary_op(|a| word_to_f64(a) as Word)?,
+            Instruction::Not => {
+                if self.stack.is_empty() {
+                    return Err(InterpreterErr::StackUnderflow);
+                }
+                let stack_size = self.stack.len();
+                self.stack[stack_size - 1] = (self.stack[stack_size - 1] == 0) as Word;
+                self.ip += 1;
+            }
             Instruction::Halt => {
                 self.halt = true;
             }
             Instruction::PrintDebug => {
-                if self.stack.len() < 1 {
+                if self.stack.is_empty() {
+                    return Err(InterpreterErr::StackUnderflow);
+                }
+                self.io
+                    .write_fmt(format_args!("{}\n", self.stack[self.stack.len() - 1]))?;
+                self.stack.pop();
+                self.ip += 1;
+            }
+            Instruction::ReadChar => {
+                if self.stack.len() >= self.config.stack_capacity {
+                    return Err(InterpreterErr::StackOverflow);
+                }
+                let c = self.io.read_char()?;
+                self.stack.push(c.map_or(-1, |c| c as Word));
+                self.ip += 1;
+            }
+            Instruction::WriteChar => {
+                if self.stack.is_empty() {
                     return Err(InterpreterErr::StackUnderflow);
                 }
-                println!("{}", self.stack[self.stack.len() - 1]);
+                let c = u32::try_from(self.stack[self.stack.len() - 1])
+                    .ok()
+                    .and_then(char::from_u32)
+                    .ok_or(InterpreterErr::IllegalOperand)?;
+                self.io.write_char(c)?;
                 self.stack.pop();
                 self.ip += 1;
             }
+            Instruction::ReadInt => {
+                if self.stack.len() >= self.config.stack_capacity {
+                    return Err(InterpreterErr::StackOverflow);
+                }
+                let n = self.io.read_int()?;
+                self.stack.push(n);
+                self.ip += 1;
+            }
+            Instruction::WriteInt => {
+                if self.stack.is_empty() {
+                    return Err(InterpreterErr::StackUnderflow);
+                }
+                self.io
+                    .write_fmt(format_args!("{}", self.stack[self.stack.len() - 1]))?;
+                self.stack.pop();
+                self.ip += 1;
+            }
+        };
+        Ok(())
+    }
+
+    /// Range of memory accessed by a `width` bytes wide access at `addr`.
+    fn memory_range(
+        &self,
+        addr: Word,
+        width: usize,
+    ) -> Result<std::ops::Range<usize>, InterpreterErr> {
+        if addr < 0 || addr as usize + width > self.memory.len() {
+            return Err(InterpreterErr::IllegalMemoryAccess(addr));
+        }
+        Ok(addr as usize..addr as usize + width)
+    }
+
+    /// Replace the address on top of the stack by the little endian value stored there.
+    fn load(&mut self, width: usize) -> Result<(), InterpreterErr> {
+        if self.stack.is_empty() {
+            return Err(InterpreterErr::StackUnderflow);
+        }
+        let stack_size = self.stack.len();
+        let range = self.memory_range(self.stack[stack_size - 1], width)?;
+        let mut bytes = [0; 8];
+        bytes[..width].copy_from_slice(&self.memory[range]);
+        self.stack[stack_size - 1] = Word::from_le_bytes(bytes);
+        self.ip += 1;
+        Ok(())
+    }
+
+    /// Store the lowest `width` bytes of the top element at the address below it.
+    fn store(&mut self, width: usize) -> Result<(), InterpreterErr> {
+        if self.stack.len() < 2 {
+            return Err(InterpreterErr::StackUnderflow);
+        }
+        let stack_size = self.stack.len();
+        let range = self.memory_range(self.stack[stack_size - 2], width)?;
+        let value = self.stack[stack_size - 1];
+        self.memory[range].copy_from_slice(&value.to_le_bytes()[..width]);
+        self.stack.truncate(stack_size - 2);
+        self.ip += 1;
+        Ok(())
+    }
+
+    /// Call the host function named by the current native instruction.
+    fn call_native(&mut self) -> Result<(), InterpreterErr> {
+        let name = match &self.program[self.ip as usize] {
+            Instruction::Native(name) => name,
+            i => unreachable!("{} is not a native call", i),
         };
+        let f = self
+            .natives
+            .get_mut(name)
+            .ok_or_else(|| InterpreterErr::UnknownNative(name.clone()))?;
+        f(&mut NativeStack::new(
+            &mut self.stack,
+            self.config.stack_capacity,
+        ))?;
+        self.ip += 1;
         Ok(())
     }
+
+    /// Replace the top 2 elements by `f(second, top)`.
+    fn binary_op<F>(&mut self, f: F) -> Result<(), InterpreterErr>
+    where
+        F: Fn(Word, Word) -> Word,
+    {
+        if self.stack.len() < 2 {
+            return Err(InterpreterErr::StackUnderflow);
+        }
+        let stack_size = self.stack.len();
+        self.stack[stack_size - 2] = f(self.stack[stack_size - 2], self.stack[stack_size - 1]);
+        self.stack.pop();
+        self.ip += 1;
+        Ok(())
+    }
+
+    /// Replace the top element by `f(top)`.
+    fn unary_op<F>(&mut self, f: F) -> Result<(), InterpreterErr>
+    where
+        F: Fn(Word) -> Word,
+    {
+        let top = self
+            .stack
+            .last_mut()
+            .ok_or(InterpreterErr::StackUnderflow)?;
+        *top = f(*top);
+        self.ip += 1;
+        Ok(())
+    }
+}
+
+/// Apply a float operation to two words holding the bit pattern of floats.
+fn float_op<F>(a: Word, b: Word, f: F) -> Word
+where
+    F: Fn(f64, f64) -> f64,
+{
+    f64_to_word(f(word_to_f64(a), word_to_f64(b)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::SharedBuffer;
+    use crate::Instruction::*;
+    use std::io::Cursor;
+
+    /// Load `program` and execute its instructions one by one until it halts or fails.
+    fn run(program: &[Instruction]) -> (BM, Result<(), Error>) {
+        let mut bm: BM = Default::default();
+        bm.load_program_from_memory(program).unwrap();
+        let result = bm.execute_program(None);
+        (bm, result)
+    }
+
+    /// Run `program` to completion, returning the final stack.
+    fn stack_after(program: &[Instruction]) -> Vec<Word> {
+        let (bm, result) = run(program);
+        result.unwrap();
+        bm.stack
+    }
+
+    /// Apply `inst` to a stack holding `operands`, returning the stack afterwards.
+    fn apply(inst: Instruction, operands: &[Word]) -> Vec<Word> {
+        let mut program: Vec<Instruction> = operands.iter().map(|op| Push(*op)).collect();
+        program.extend([inst, Halt]);
+        stack_after(&program)
+    }
+
+    #[test]
+    fn call_and_ret() {
+        let (bm, result) = run(&[Push(3), Call(Some(3)), Halt, Dup(0), Mult, Ret]);
+        result.unwrap();
+        assert_eq!(bm.stack, vec![9]);
+        assert_eq!(bm.ip, 2);
+        assert!(bm.call_stack.is_empty());
+    }
+
+    #[test]
+    fn ret_without_call() {
+        let (bm, result) = run(&[Ret]);
+        assert_eq!(
+            result.unwrap_err().as_runtime(),
+            Some(&InterpreterErr::CallStackUnderflow)
+        );
+        assert_eq!(bm.ip, 0);
+    }
+
+    #[test]
+    fn unresolved_call_leaves_the_call_stack_alone() {
+        // Loading rejects unresolved addresses, so the program is set directly
+        let mut bm = BM {
+            program: vec![Call(None)],
+            ..Default::default()
+        };
+        assert_eq!(bm.interpret(), Err(InterpreterErr::UnresolvedAddress));
+        assert!(bm.call_stack.is_empty());
+        assert_eq!(bm.ip, 0);
+    }
+
+    #[test]
+    fn call_depth_is_limited() {
+        let mut bm = BM::builder().call_depth(2).build();
+        bm.load_program_from_memory(&[Call(Some(0))]).unwrap();
+        let e = bm.execute_program(None).unwrap_err();
+        assert_eq!(e.as_runtime(), Some(&InterpreterErr::CallStackOverflow));
+        assert_eq!(bm.call_stack, vec![1, 1]);
+    }
+
+    #[test]
+    fn comparisons() {
+        for (inst, results) in [
+            (Eq, [0, 1, 0]),
+            (Ne, [1, 0, 1]),
+            (Lt, [1, 0, 0]),
+            (Le, [1, 1, 0]),
+            (Gt, [0, 0, 1]),
+            (Ge, [0, 1, 1]),
+        ] {
+            for ((a, b), result) in [(-1, 2), (2, 2), (3, -2)].into_iter().zip(results) {
+                assert_eq!(
+                    apply(inst.clone(), &[a, b]),
+                    vec![result],
+                    "{} {} {}",
+                    a,
+                    inst,
+                    b
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn logical_ops_treat_any_non_zero_as_true() {
+        for (inst, results) in [(And, [0, 0, 1]), (Or, [0, 1, 1]), (Xor, [0, 1, 0])] {
+            for ((a, b), result) in [(0, 0), (0, -7), (2, 5)].into_iter().zip(results) {
+                assert_eq!(
+                    apply(inst.clone(), &[a, b]),
+                    vec![result],
+                    "{} {} {}",
+                    a,
+                    inst,
+                    b
+                );
+            }
+        }
+        assert_eq!(apply(Not, &[0]), vec![1]);
+        assert_eq!(apply(Not, &[-3]), vec![0]);
+    }
+
+    #[test]
+    fn comparisons_underflow() {
+        for inst in [Eq, Ne, Lt, Le, Gt, Ge, And, Or, Xor] {
+            let (bm, result) = run(&[Push(1), inst.clone(), Halt]);
+            let e = result.unwrap_err();
+            assert_eq!(
+                e.as_runtime(),
+                Some(&InterpreterErr::StackUnderflow),
+                "{}",
+                inst
+            );
+            assert_eq!(bm.stack, vec![1]);
+        }
+        let (_, result) = run(&[Not]);
+        assert_eq!(
+            result.unwrap_err().as_runtime(),
+            Some(&InterpreterErr::StackUnderflow)
+        );
+    }
+
+    #[test]
+    fn conditional_jumps_pop_on_both_branches() {
+        // Pushes 10 if the jump is taken and 20 otherwise
+        let branch = |inst: Instruction, top: Word| {
+            stack_after(&[Push(5), Push(top), inst, Push(20), Halt, Push(10), Halt])
+        };
+        assert_eq!(branch(JumpIf(Some(5)), 1), vec![5, 10]);
+        assert_eq!(branch(JumpIf(Some(5)), -2), vec![5, 10]);
+        assert_eq!(branch(JumpIf(Some(5)), 0), vec![5, 20]);
+        assert_eq!(branch(JumpZero(Some(5)), 0), vec![5, 10]);
+        assert_eq!(branch(JumpZero(Some(5)), 3), vec![5, 20]);
+    }
+
+    #[test]
+    fn conditional_jumps_underflow() {
+        for inst in [JumpIf(Some(0)), JumpZero(Some(0))] {
+            let (bm, result) = run(&[inst]);
+            assert_eq!(
+                result.unwrap_err().as_runtime(),
+                Some(&InterpreterErr::StackUnderflow)
+            );
+            assert_eq!(bm.ip, 0);
+        }
+    }
+
+    #[test]
+    fn unresolved_jumps_leave_the_stack_alone() {
+        for inst in [JumpIf(None), JumpZero(None)] {
+            let mut bm = BM {
+                program: vec![inst],
+                stack: vec![0],
+                ..Default::default()
+            };
+            assert_eq!(bm.interpret(), Err(InterpreterErr::UnresolvedAddress));
+            assert_eq!(bm.stack, vec![0]);
+        }
+    }
+
+    /// Apply the float `inst` to `operands`, returning the float left on the stack.
+    fn apply_float(inst: Instruction, operands: &[f64]) -> f64 {
+        let operands: Vec<Word> = operands.iter().map(|op| f64_to_word(*op)).collect();
+        let stack = apply(inst, &operands);
+        assert_eq!(stack.len(), 1);
+        word_to_f64(stack[0])
+    }
+
+    #[test]
+    fn float_arithmetic() {
+        assert_eq!(apply_float(FPlus, &[1.5, 2.25]), 3.75);
+        assert_eq!(apply_float(FMinus, &[1.5, 2.25]), -0.75);
+        assert_eq!(apply_float(FMult, &[1.5, -2.0]), -3.0);
+        assert_eq!(apply_float(FDiv, &[1.0, 4.0]), 0.25);
+    }
+
+    #[test]
+    fn float_division_by_zero_follows_ieee() {
+        assert_eq!(apply_float(FDiv, &[1.0, 0.0]), f64::INFINITY);
+        assert_eq!(apply_float(FDiv, &[-1.0, 0.0]), f64::NEG_INFINITY);
+        assert!(apply_float(FDiv, &[0.0, 0.0]).is_nan());
+    }
+
+    #[test]
+    fn float_comparisons() {
+        let compare =
+            |inst: Instruction, a: f64, b: f64| apply(inst, &[f64_to_word(a), f64_to_word(b)]);
+        assert_eq!(compare(FLt, -1.0, 0.5), vec![1]);
+        assert_eq!(compare(FGt, -1.0, 0.5), vec![0]);
+        assert_eq!(compare(FLe, 0.5, 0.5), vec![1]);
+        assert_eq!(compare(FGe, 0.25, 0.5), vec![0]);
+        // Unlike `eq`, which compares bit patterns
+        assert_eq!(compare(FEq, 0.0, -0.0), vec![1]);
+        assert_eq!(compare(Eq, 0.0, -0.0), vec![0]);
+        // Every comparison with NaN is false
+        for inst in [FEq, FLt, FGt, FLe, FGe] {
+            assert_eq!(compare(inst.clone(), f64::NAN, 1.0), vec![0], "{}", inst);
+            assert_eq!(
+                compare(inst.clone(), f64::NAN, f64::NAN),
+                vec![0],
+                "{}",
+                inst
+            );
+        }
+    }
+
+    #[test]
+    fn float_conversions() {
+        assert_eq!(word_to_f64(apply(I2F, &[-3])[0]), -3.0);
+        assert_eq!(apply(F2I, &[f64_to_word(2.9)]), vec![2]);
+        assert_eq!(apply(F2I, &[f64_to_word(-2.9)]), vec![-2]);
+        // Out of range values saturate and NaN becomes 0
+        assert_eq!(apply(F2I, &[f64_to_word(1e300)]), vec![Word::MAX]);
+        assert_eq!(
+            apply(F2I, &[f64_to_word(f64::NEG_INFINITY)]),
+            vec![Word::MIN]
+        );
+        assert_eq!(apply(F2I, &[f64_to_word(f64::NAN)]), vec![0]);
+    }
+
+    #[test]
+    fn float_ops_underflow() {
+        for inst in [FPlus, FMinus, FMult, FDiv, FEq, FLt, FGt, FLe, FGe] {
+            let (_, result) = run(&[Push(1), inst.clone(), Halt]);
+            let e = result.unwrap_err();
+            assert_eq!(
+                e.as_runtime(),
+                Some(&InterpreterErr::StackUnderflow),
+                "{}",
+                inst
+            );
+        }
+        for inst in [I2F, F2I] {
+            let (_, result) = run(&[inst.clone(), Halt]);
+            let e = result.unwrap_err();
+            assert_eq!(
+                e.as_runtime(),
+                Some(&InterpreterErr::StackUnderflow),
+                "{}",
+                inst
+            );
+        }
+    }
+
+    /// Run `inst` on a stack holding `operands`, returning the error and the stack left.
+    fn fail(inst: Instruction, operands: &[Word]) -> (InterpreterErr, Vec<Word>) {
+        let mut program: Vec<Instruction> = operands.iter().map(|op| Push(*op)).collect();
+        program.extend([inst, Halt]);
+        let (bm, result) = run(&program);
+        let e = result.unwrap_err().as_runtime().cloned().unwrap();
+        (e, bm.stack)
+    }
+
+    #[test]
+    fn stack_manipulation() {
+        assert_eq!(apply(Drop, &[1, 2]), vec![1]);
+        assert_eq!(apply(Swap(1), &[1, 2, 3]), vec![1, 3, 2]);
+        assert_eq!(apply(Swap(2), &[1, 2, 3]), vec![3, 2, 1]);
+        assert_eq!(apply(Over, &[1, 2]), vec![1, 2, 1]);
+        assert_eq!(apply(Rot, &[1, 2, 3]), vec![2, 3, 1]);
+        // The index of pick and roll is popped first
+        assert_eq!(apply(Pick, &[1, 2, 3, 0]), vec![1, 2, 3, 3]);
+        assert_eq!(apply(Pick, &[1, 2, 3, 2]), vec![1, 2, 3, 1]);
+        assert_eq!(apply(Roll, &[1, 2, 3, 0]), vec![1, 2, 3]);
+        assert_eq!(apply(Roll, &[1, 2, 3, 2]), vec![2, 3, 1]);
+    }
+
+    #[test]
+    fn stack_manipulation_underflow() {
+        let underflow = InterpreterErr::StackUnderflow;
+        assert_eq!(fail(Drop, &[]), (underflow.clone(), vec![]));
+        assert_eq!(fail(Swap(1), &[1]), (underflow.clone(), vec![1]));
+        assert_eq!(fail(Swap(2), &[1, 2]), (underflow.clone(), vec![1, 2]));
+        assert_eq!(fail(Over, &[1]), (underflow.clone(), vec![1]));
+        assert_eq!(fail(Rot, &[1, 2]), (underflow.clone(), vec![1, 2]));
+        assert_eq!(fail(Pick, &[]), (underflow.clone(), vec![]));
+        assert_eq!(fail(Roll, &[]), (underflow.clone(), vec![]));
+    }
+
+    #[test]
+    fn pick_and_roll_index_out_of_range() {
+        // A failing pick or roll leaves its index on the stack
+        for inst in [Pick, Roll] {
+            assert_eq!(
+                fail(inst.clone(), &[1, 2, 2]),
+                (InterpreterErr::StackUnderflow, vec![1, 2, 2]),
+                "{}",
+                inst
+            );
+            assert_eq!(
+                fail(inst.clone(), &[5]),
+                (InterpreterErr::StackUnderflow, vec![5]),
+                "{}",
+                inst
+            );
+            assert_eq!(
+                fail(inst.clone(), &[1, -1]),
+                (InterpreterErr::IllegalOperand, vec![1, -1]),
+                "{}",
+                inst
+            );
+            assert_eq!(
+                fail(inst.clone(), &[1, Word::MIN]),
+                (InterpreterErr::IllegalOperand, vec![1, Word::MIN]),
+                "{}",
+                inst
+            );
+            assert_eq!(
+                fail(inst.clone(), &[1, Word::MAX]),
+                (InterpreterErr::StackUnderflow, vec![1, Word::MAX]),
+                "{}",
+                inst
+            );
+        }
+    }
+
+    #[test]
+    fn illegal_operands() {
+        assert_eq!(
+            fail(Swap(0), &[1, 2]),
+            (InterpreterErr::IllegalOperand, vec![1, 2])
+        );
+        assert_eq!(
+            fail(Swap(-1), &[1, 2]),
+            (InterpreterErr::IllegalOperand, vec![1, 2])
+        );
+        assert_eq!(
+            fail(Swap(Word::MAX), &[1, 2]),
+            (InterpreterErr::StackUnderflow, vec![1, 2])
+        );
+        assert_eq!(
+            fail(Dup(-1), &[1]),
+            (InterpreterErr::IllegalOperand, vec![1])
+        );
+        assert_eq!(
+            fail(Dup(1), &[1]),
+            (InterpreterErr::StackUnderflow, vec![1])
+        );
+    }
+
+    #[test]
+    fn stack_manipulation_overflow() {
+        let mut bm = BM::builder().stack_capacity(2).build();
+        bm.load_program_from_memory(&[Push(1), Push(2), Over, Halt])
+            .unwrap();
+        let e = bm.execute_program(None).unwrap_err();
+        assert_eq!(e.as_runtime(), Some(&InterpreterErr::StackOverflow));
+        assert_eq!(bm.stack, vec![1, 2]);
+    }
+
+    /// Run `program` reading from `input`, returning the machine, the result and the output.
+    fn run_io(program: &[Instruction], input: &str) -> (BM, Result<(), Error>, String) {
+        let out = SharedBuffer::default();
+        let mut bm: BM = Default::default();
+        bm.set_input(Cursor::new(input.as_bytes().to_vec()));
+        bm.set_output(out.clone());
+        bm.load_program_from_memory(program).unwrap();
+        let result = bm.execute_program(None);
+        bm.flush_output().unwrap();
+        (bm, result, String::from_utf8(out.contents()).unwrap())
+    }
+
+    #[test]
+    fn read_char() {
+        let (bm, result, _) = run_io(&[ReadChar, ReadChar, ReadChar, Halt], "aé");
+        result.unwrap();
+        // The end of the input reads as -1
+        assert_eq!(bm.stack, vec!['a' as Word, 'é' as Word, -1]);
+    }
+
+    #[test]
+    fn read_char_invalid_utf8() {
+        let out = SharedBuffer::default();
+        let mut bm: BM = Default::default();
+        bm.set_input(Cursor::new(vec![0xC3, b'(']));
+        bm.set_output(out);
+        bm.load_program_from_memory(&[ReadChar, Halt]).unwrap();
+        let e = bm.execute_program(None).unwrap_err();
+        assert!(matches!(
+            e.as_runtime(),
+            Some(InterpreterErr::InvalidInput(_))
+        ));
+        assert!(bm.stack.is_empty());
+    }
+
+    #[test]
+    fn write_char() {
+        let program = [
+            Push('h' as Word),
+            WriteChar,
+            Push('é' as Word),
+            WriteChar,
+            Halt,
+        ];
+        let (bm, result, out) = run_io(&program, "");
+        result.unwrap();
+        assert_eq!(out, "hé");
+        assert!(bm.stack.is_empty());
+    }
+
+    #[test]
+    fn write_char_rejects_invalid_code_points() {
+        for c in [-1, 0xD800, 0x110000] {
+            let (bm, result, out) = run_io(&[Push(c), WriteChar, Halt], "");
+            assert_eq!(
+                result.unwrap_err().as_runtime(),
+                Some(&InterpreterErr::IllegalOperand)
+            );
+            assert_eq!(bm.stack, vec![c]);
+            assert_eq!(out, "");
+        }
+        let (_, result, _) = run_io(&[WriteChar, Halt], "");
+        assert_eq!(
+            result.unwrap_err().as_runtime(),
+            Some(&InterpreterErr::StackUnderflow)
+        );
+    }
+
+    #[test]
+    fn read_int() {
+        let (bm, result, _) = run_io(&[ReadInt, ReadInt, ReadInt, Halt], "  42\n-7 +3x");
+        result.unwrap();
+        assert_eq!(bm.stack, vec![42, -7, 3]);
+    }
+
+    #[test]
+    fn read_int_malformed() {
+        let cases = [
+            ("abc", "expected an integer"),
+            ("  \n", "unexpected end of input"),
+            ("-", "invalid integer `-`"),
+            (
+                "99999999999999999999",
+                "invalid integer `99999999999999999999`",
+            ),
+        ];
+        for (input, message) in cases {
+            let (bm, result, _) = run_io(&[ReadInt, Halt], input);
+            assert_eq!(
+                result.unwrap_err().as_runtime(),
+                Some(&InterpreterErr::InvalidInput(message.to_string())),
+                "input {:?}",
+                input
+            );
+            assert!(bm.stack.is_empty());
+            assert_eq!(bm.ip, 0);
+        }
+    }
+
+    #[test]
+    fn write_int() {
+        let program = [
+            Push(-12),
+            WriteInt,
+            Push(' ' as Word),
+            WriteChar,
+            Push(0),
+            WriteInt,
+            Halt,
+        ];
+        let (bm, result, out) = run_io(&program, "");
+        result.unwrap();
+        assert_eq!(out, "-12 0");
+        assert!(bm.stack.is_empty());
+        let (_, result, _) = run_io(&[WriteInt, Halt], "");
+        assert_eq!(
+            result.unwrap_err().as_runtime(),
+            Some(&InterpreterErr::StackUnderflow)
+        );
+    }
+
+    #[test]
+    fn echo_until_eof() {
+        // Copy the input to the output one character at a time
+        let program = [
+            ReadChar,
+            Dup(0),
+            Push(-1),
+            Eq,
+            JumpIf(Some(7)),
+            WriteChar,
+            Jump(Some(0)),
+            Halt,
+        ];
+        let (bm, result, out) = run_io(&program, "hi\n");
+        result.unwrap();
+        assert_eq!(out, "hi\n");
+        assert_eq!(bm.stack, vec![-1]);
+    }
+
+    #[test]
+    fn reads_overflow_the_stack() {
+        for inst in [ReadChar, ReadInt] {
+            let mut bm = BM::builder().stack_capacity(1).build();
+            bm.set_input(Cursor::new(b"5".to_vec()));
+            bm.load_program_from_memory(&[Push(1), inst, Halt]).unwrap();
+            let e = bm.execute_program(None).unwrap_err();
+            assert_eq!(e.as_runtime(), Some(&InterpreterErr::StackOverflow));
+            assert_eq!(bm.stack, vec![1]);
+        }
+    }
 }
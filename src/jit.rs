@@ -0,0 +1,768 @@
+//! Compiles programs to x86-64 machine code, enabled by the `jit` feature on Linux.
+//!
+//! The generated code works on the stacks of the virtual machine directly. Stack, arithmetic,
+//! comparison, logic and control flow instructions are translated; every other
+//! instruction, as well as any instruction whose checks fail (overflow, underflow,
+//! division by zero, ...), is handed over to the interpreter, so the results and errors
+//! are the same as with `BM::execute_program`.
+
+use std::collections::HashMap;
+use std::ffi::c_void;
+
+use crate::interpreter::InterpreterErr;
+use crate::{Error, Instruction, LoadErr, Word, BM};
+
+extern "C" {
+    fn mmap(addr: *mut c_void, len: usize, prot: i32, flags: i32, fd: i32, off: i64)
+        -> *mut c_void;
+    fn mprotect(addr: *mut c_void, len: usize, prot: i32) -> i32;
+    fn munmap(addr: *mut c_void, len: usize) -> i32;
+}
+
+const PROT_READ: i32 = 1;
+const PROT_WRITE: i32 = 2;
+const PROT_EXEC: i32 = 4;
+const MAP_PRIVATE: i32 = 2;
+const MAP_ANONYMOUS: i32 = 0x20;
+
+/// State shared with the generated code, which keeps `stack`, `len`, `limit` and `fuel`
+/// in registers while it runs.
+#[repr(C)]
+struct Ctx {
+    stack: *mut Word,
+    len: usize,
+    /// Elements the stack can hold without growing
+    limit: usize,
+    /// Instructions left to execute
+    fuel: usize,
+    calls: *mut Word,
+    call_len: usize,
+    call_limit: usize,
+    /// Instruction to start from, and where the execution stopped
+    ip: Word,
+}
+
+// Offsets of the fields of `Ctx`
+const CTX_LEN: u8 = 8;
+const CTX_LIMIT: u8 = 16;
+const CTX_FUEL: u8 = 24;
+const CTX_CALLS: u8 = 32;
+const CTX_CALL_LEN: u8 = 40;
+const CTX_CALL_LIMIT: u8 = 48;
+const CTX_IP: u8 = 56;
+
+// Why the generated code returned
+const HALTED: u32 = 0;
+/// The instruction at `ip` has to be executed by the interpreter
+const INTERPRET: u32 = 1;
+const OUT_OF_FUEL: u32 = 2;
+
+type Entry = unsafe extern "sysv64" fn(*mut Ctx) -> u32;
+
+/// Places in the generated code jumps can go to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Label {
+    /// Code of the instruction at the address
+    Inst(usize),
+    /// Hands the instruction at the address over to the interpreter
+    Interpret(usize),
+    /// Returns once the fuel runs out before the instruction at the address
+    OutOfFuel(usize),
+    /// Jumps to the instruction at the address held by rdx
+    Dispatch,
+    /// Returns with the address held by rdx to be interpreted
+    InterpretRdx,
+    /// Stores the registers back and returns the status held by eax
+    Epilogue,
+    /// Address of the code of every instruction
+    Table,
+}
+
+/// Stack elements are addressed with 32-bit displacements, deeper ones are left to the
+/// interpreter.
+const MAX_DEPTH: Word = (i32::MAX / 8) as Word;
+
+// Registers used for operands
+const RAX: u8 = 0;
+const RCX: u8 = 1;
+const RDX: u8 = 2;
+
+// Condition codes for `jcc` and `setcc`
+const CC_B: u8 = 0x2;
+const CC_AE: u8 = 0x3;
+const CC_E: u8 = 0x4;
+const CC_NE: u8 = 0x5;
+const CC_BE: u8 = 0x6;
+const CC_L: u8 = 0xC;
+const CC_GE: u8 = 0xD;
+const CC_LE: u8 = 0xE;
+const CC_G: u8 = 0xF;
+
+/// Emits machine code. The registers are allocated as:
+/// rbx: `Ctx`, r12: stack base, r13: stack length, r14: stack limit, r15: fuel.
+#[derive(Default)]
+struct Asm {
+    code: Vec<u8>,
+    labels: HashMap<Label, usize>,
+    /// Offsets of rel32 operands along with the label they refer to
+    fixups: Vec<(usize, Label)>,
+    /// Instructions left to the interpreter
+    interpreted: usize,
+}
+
+impl Asm {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn imm32(&mut self, imm: u32) {
+        self.bytes(&imm.to_le_bytes());
+    }
+
+    fn label(&mut self, label: Label) {
+        self.labels.insert(label, self.code.len());
+    }
+
+    fn rel32(&mut self, label: Label) {
+        self.fixups.push((self.code.len(), label));
+        self.imm32(0);
+    }
+
+    fn jmp(&mut self, label: Label) {
+        self.bytes(&[0xE9]);
+        self.rel32(label);
+    }
+
+    fn jcc(&mut self, cc: u8, label: Label) {
+        self.bytes(&[0x0F, 0x80 | cc]);
+        self.rel32(label);
+    }
+
+    /// `op reg, [r12 + r13*8 + disp]`, i.e. an element relative to the top of the stack.
+    fn elem(&mut self, op: u8, reg: u8, disp: i64) {
+        self.bytes(&[0x4B, op]);
+        match i8::try_from(disp) {
+            Ok(disp) => self.bytes(&[0x44 | reg << 3, 0xEC, disp as u8]),
+            Err(_) => {
+                self.bytes(&[0x84 | reg << 3, 0xEC]);
+                self.imm32(disp as i32 as u32);
+            }
+        }
+    }
+
+    /// Load the element `n` places from the top of the stack.
+    fn load(&mut self, reg: u8, n: usize) {
+        self.elem(0x8B, reg, -8 * (n as i64 + 1));
+    }
+
+    /// Store into the element `n` places from the top of the stack.
+    fn store(&mut self, reg: u8, n: usize) {
+        self.elem(0x89, reg, -8 * (n as i64 + 1));
+    }
+
+    /// Store on top of the stack, past its current length.
+    fn push(&mut self, reg: u8) {
+        self.elem(0x89, reg, 0);
+        self.bytes(&[0x49, 0xFF, 0xC5]); // inc r13
+    }
+
+    /// Hand the instruction over to the interpreter unless the stack holds `need`
+    /// elements and has room for `grow` more.
+    fn check_stack(&mut self, addr: usize, need: usize, grow: usize) {
+        if grow > 0 {
+            self.bytes(&[0x4D, 0x39, 0xF5]); // cmp r13, r14
+            self.jcc(CC_AE, Label::Interpret(addr));
+        }
+        if need > 0 {
+            match i8::try_from(need) {
+                Ok(need) => self.bytes(&[0x49, 0x83, 0xFD, need as u8]), // cmp r13, imm8
+                Err(_) => {
+                    self.bytes(&[0x49, 0x81, 0xFD]); // cmp r13, imm32
+                    self.imm32(need as u32);
+                }
+            }
+            self.jcc(CC_B, Label::Interpret(addr));
+        }
+    }
+
+    /// Count the instruction as executed.
+    fn consume_fuel(&mut self) {
+        self.bytes(&[0x49, 0xFF, 0xCF]); // dec r15
+    }
+
+    /// Replace the top 2 elements by the result of `op` on rax (second) and rcx (top).
+    fn binary(&mut self, addr: usize, op: &[u8]) {
+        self.check_stack(addr, 2, 0);
+        self.load(RAX, 1);
+        self.load(RCX, 0);
+        self.bytes(op);
+        self.store(RAX, 1);
+        self.bytes(&[0x49, 0xFF, 0xCD]); // dec r13
+    }
+
+    /// Replace the top 2 elements by the outcome of comparing them.
+    fn compare(&mut self, addr: usize, cc: u8) {
+        let setcc = [
+            0x48,
+            0x39,
+            0xC8, // cmp rax, rcx
+            0x0F,
+            0x90 | cc,
+            0xC0, // setcc al
+            0x0F,
+            0xB6,
+            0xC0, // movzx eax, al
+        ];
+        self.binary(addr, &setcc);
+    }
+
+    /// Replace the top 2 elements by `op al, cl` applied to their truth values.
+    fn logic(&mut self, addr: usize, op: u8) {
+        let logic = [
+            0x48, 0x85, 0xC0, // test rax, rax
+            0x0F, 0x95, 0xC0, // setne al
+            0x48, 0x85, 0xC9, // test rcx, rcx
+            0x0F, 0x95, 0xC1, // setne cl
+            op, 0xC8, // op al, cl
+            0x0F, 0xB6, 0xC0, // movzx eax, al
+        ];
+        self.binary(addr, &logic);
+    }
+
+    /// Translate the instruction at `addr`, `false` if it is left to the interpreter.
+    fn instruction(&mut self, addr: usize, inst: &Instruction, len: usize) -> bool {
+        let target = |t: &Option<Word>| match t {
+            Some(t) if *t >= 0 && (*t as usize) < len => Some(*t as usize),
+            _ => None,
+        };
+        match inst {
+            Instruction::Nop => self.consume_fuel(),
+            Instruction::Push(w) => {
+                self.check_stack(addr, 0, 1);
+                self.bytes(&[0x48, 0xB8]); // mov rax, imm64
+                self.bytes(&w.to_le_bytes());
+                self.push(RAX);
+                self.consume_fuel();
+            }
+            Instruction::Dup(n) if (0..MAX_DEPTH).contains(n) => {
+                self.check_stack(addr, *n as usize + 1, 1);
+                self.load(RAX, *n as usize);
+                self.push(RAX);
+                self.consume_fuel();
+            }
+            Instruction::Over => {
+                self.check_stack(addr, 2, 1);
+                self.load(RAX, 1);
+                self.push(RAX);
+                self.consume_fuel();
+            }
+            Instruction::Swap(n) if (1..MAX_DEPTH).contains(n) => {
+                self.check_stack(addr, *n as usize + 1, 0);
+                self.load(RAX, 0);
+                self.load(RCX, *n as usize);
+                self.store(RCX, 0);
+                self.store(RAX, *n as usize);
+                self.consume_fuel();
+            }
+            Instruction::Drop => {
+                self.check_stack(addr, 1, 0);
+                self.bytes(&[0x49, 0xFF, 0xCD]); // dec r13
+                self.consume_fuel();
+            }
+            Instruction::Rot => {
+                self.check_stack(addr, 3, 0);
+                self.load(RAX, 2);
+                self.load(RCX, 1);
+                self.load(RDX, 0);
+                self.store(RCX, 2);
+                self.store(RDX, 1);
+                self.store(RAX, 0);
+                self.consume_fuel();
+            }
+            Instruction::Plus => {
+                self.binary(addr, &[0x48, 0x01, 0xC8]); // add rax, rcx
+                self.consume_fuel();
+            }
+            Instruction::Minus => {
+                self.binary(addr, &[0x48, 0x29, 0xC8]); // sub rax, rcx
+                self.consume_fuel();
+            }
+            Instruction::Mult => {
+                self.binary(addr, &[0x48, 0x0F, 0xAF, 0xC1]); // imul rax, rcx
+                self.consume_fuel();
+            }
+            Instruction::Div => {
+                self.check_stack(addr, 2, 0);
+                // Dividing by 0 fails and by -1 may trap, both are left to the interpreter
+                self.load(RCX, 0);
+                self.bytes(&[0x48, 0x8D, 0x41, 0x01]); // lea rax, [rcx + 1]
+                self.bytes(&[0x48, 0x83, 0xF8, 0x01]); // cmp rax, 1
+                self.jcc(CC_BE, Label::Interpret(addr));
+                self.binary(addr, &[0x48, 0x99, 0x48, 0xF7, 0xF9]); // cqo; idiv rcx
+                self.consume_fuel();
+            }
+            Instruction::Eq => {
+                self.compare(addr, CC_E);
+                self.consume_fuel();
+            }
+            Instruction::Ne => {
+                self.compare(addr, CC_NE);
+                self.consume_fuel();
+            }
+            Instruction::Lt => {
+                self.compare(addr, CC_L);
+                self.consume_fuel();
+            }
+            Instruction::Gt => {
+                self.compare(addr, CC_G);
+                self.consume_fuel();
+            }
+            Instruction::Le => {
+                self.compare(addr, CC_LE);
+                self.consume_fuel();
+            }
+            Instruction::Ge => {
+                self.compare(addr, CC_GE);
+                self.consume_fuel();
+            }
+            Instruction::And => {
+                self.logic(addr, 0x20);
+                self.consume_fuel();
+            }
+            Instruction::Or => {
+                self.logic(addr, 0x08);
+                self.consume_fuel();
+            }
+            Instruction::Xor => {
+                self.logic(addr, 0x30);
+                self.consume_fuel();
+            }
+            Instruction::Not => {
+                self.check_stack(addr, 1, 0);
+                self.load(RAX, 0);
+                self.bytes(&[0x48, 0x85, 0xC0]); // test rax, rax
+                self.bytes(&[0x0F, 0x94, 0xC0]); // sete al
+                self.bytes(&[0x0F, 0xB6, 0xC0]); // movzx eax, al
+                self.store(RAX, 0);
+                self.consume_fuel();
+            }
+            Instruction::Jump(t) => match target(t) {
+                Some(t) => {
+                    self.consume_fuel();
+                    self.jmp(Label::Inst(t));
+                }
+                None => return false,
+            },
+            Instruction::JumpIf(t) | Instruction::JumpZero(t) => match target(t) {
+                Some(t) => {
+                    self.check_stack(addr, 1, 0);
+                    self.load(RAX, 0);
+                    self.bytes(&[0x49, 0xFF, 0xCD]); // dec r13
+                    self.consume_fuel();
+                    self.bytes(&[0x48, 0x85, 0xC0]); // test rax, rax
+                    let cc = match inst {
+                        Instruction::JumpIf(_) => CC_NE,
+                        _ => CC_E,
+                    };
+                    self.jcc(cc, Label::Inst(t));
+                }
+                None => return false,
+            },
+            Instruction::Call(t) => match target(t) {
+                Some(t) => {
+                    self.bytes(&[0x48, 0x8B, 0x43, CTX_CALL_LEN]); // mov rax, [rbx + call_len]
+                    self.bytes(&[0x48, 0x3B, 0x43, CTX_CALL_LIMIT]); // cmp rax, [rbx + call_limit]
+                    self.jcc(CC_AE, Label::Interpret(addr));
+                    self.bytes(&[0x48, 0x8B, 0x4B, CTX_CALLS]); // mov rcx, [rbx + calls]
+                    self.bytes(&[0x48, 0xC7, 0x04, 0xC1]); // mov qword [rcx + rax*8], imm32
+                    self.imm32(addr as u32 + 1);
+                    self.bytes(&[0x48, 0xFF, 0xC0]); // inc rax
+                    self.bytes(&[0x48, 0x89, 0x43, CTX_CALL_LEN]); // mov [rbx + call_len], rax
+                    self.consume_fuel();
+                    self.jmp(Label::Inst(t));
+                }
+                None => return false,
+            },
+            Instruction::Ret => {
+                self.bytes(&[0x48, 0x8B, 0x43, CTX_CALL_LEN]); // mov rax, [rbx + call_len]
+                self.bytes(&[0x48, 0x85, 0xC0]); // test rax, rax
+                self.jcc(CC_E, Label::Interpret(addr));
+                self.bytes(&[0x48, 0xFF, 0xC8]); // dec rax
+                self.bytes(&[0x48, 0x89, 0x43, CTX_CALL_LEN]); // mov [rbx + call_len], rax
+                self.bytes(&[0x48, 0x8B, 0x4B, CTX_CALLS]); // mov rcx, [rbx + calls]
+                self.bytes(&[0x48, 0x8B, 0x14, 0xC1]); // mov rdx, [rcx + rax*8]
+                self.consume_fuel();
+                self.jmp(Label::Dispatch);
+            }
+            Instruction::Halt => {
+                self.consume_fuel();
+                self.bytes(&[0xBA]); // mov edx, addr
+                self.imm32(addr as u32);
+                self.bytes(&[0xB8]); // mov eax, HALTED
+                self.imm32(HALTED);
+                self.jmp(Label::Epilogue);
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// Translate a whole program. The code returns the status and leaves the state in `Ctx`.
+    fn program(program: &[Instruction]) -> Self {
+        let mut asm = Asm::default();
+        // Entry: save the registers the code uses and jump to the instruction at `ip`
+        asm.bytes(&[0x53, 0x41, 0x54, 0x41, 0x55, 0x41, 0x56, 0x41, 0x57]); // push rbx, r12-r15
+        asm.bytes(&[0x48, 0x89, 0xFB]); // mov rbx, rdi
+        asm.bytes(&[0x4C, 0x8B, 0x23]); // mov r12, [rbx]
+        asm.bytes(&[0x4C, 0x8B, 0x6B, CTX_LEN]); // mov r13, [rbx + len]
+        asm.bytes(&[0x4C, 0x8B, 0x73, CTX_LIMIT]); // mov r14, [rbx + limit]
+        asm.bytes(&[0x4C, 0x8B, 0x7B, CTX_FUEL]); // mov r15, [rbx + fuel]
+        asm.bytes(&[0x48, 0x8B, 0x53, CTX_IP]); // mov rdx, [rbx + ip]
+
+        asm.label(Label::Dispatch);
+        asm.bytes(&[0x48, 0x81, 0xFA]); // cmp rdx, len
+        asm.imm32(program.len() as u32);
+        asm.jcc(CC_AE, Label::InterpretRdx);
+        asm.bytes(&[0x48, 0x8D, 0x05]); // lea rax, [rip + table]
+        asm.rel32(Label::Table);
+        asm.bytes(&[0xFF, 0x24, 0xD0]); // jmp [rax + rdx*8]
+
+        for (addr, inst) in program.iter().enumerate() {
+            asm.label(Label::Inst(addr));
+            asm.bytes(&[0x4D, 0x85, 0xFF]); // test r15, r15
+            asm.jcc(CC_E, Label::OutOfFuel(addr));
+            if !asm.instruction(addr, inst, program.len()) {
+                asm.interpreted += 1;
+                asm.jmp(Label::Interpret(addr));
+            }
+        }
+        // Running past the end of the program
+        asm.bytes(&[0xBA]); // mov edx, len
+        asm.imm32(program.len() as u32);
+
+        asm.label(Label::InterpretRdx);
+        asm.bytes(&[0xB8]); // mov eax, INTERPRET
+        asm.imm32(INTERPRET);
+        asm.label(Label::Epilogue);
+        asm.bytes(&[0x48, 0x89, 0x53, CTX_IP]); // mov [rbx + ip], rdx
+        asm.bytes(&[0x4C, 0x89, 0x6B, CTX_LEN]); // mov [rbx + len], r13
+        asm.bytes(&[0x4C, 0x89, 0x7B, CTX_FUEL]); // mov [rbx + fuel], r15
+        asm.bytes(&[0x41, 0x5F, 0x41, 0x5E, 0x41, 0x5D, 0x41, 0x5C, 0x5B]); // pop r15-r12, rbx
+        asm.bytes(&[0xC3]); // ret
+
+        for addr in 0..program.len() {
+            asm.label(Label::Interpret(addr));
+            asm.bytes(&[0xBA]); // mov edx, addr
+            asm.imm32(addr as u32);
+            asm.jmp(Label::InterpretRdx);
+            asm.label(Label::OutOfFuel(addr));
+            asm.bytes(&[0xBA]); // mov edx, addr
+            asm.imm32(addr as u32);
+            asm.bytes(&[0xB8]); // mov eax, OUT_OF_FUEL
+            asm.imm32(OUT_OF_FUEL);
+            asm.jmp(Label::Epilogue);
+        }
+
+        // The table is filled with absolute addresses once the code is mapped
+        while asm.code.len() % 8 != 0 {
+            asm.bytes(&[0xCC]);
+        }
+        asm.label(Label::Table);
+        asm.code.resize(asm.code.len() + 8 * program.len(), 0);
+
+        for (at, label) in std::mem::take(&mut asm.fixups) {
+            let rel = asm.labels[&label] as i64 - (at as i64 + 4);
+            asm.code[at..at + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+        }
+        asm
+    }
+}
+
+/// A program compiled to machine code by `BM::compile_jit`.
+#[derive(Debug)]
+pub struct JitCode {
+    buf: *mut c_void,
+    size: usize,
+    /// Program the code was compiled from
+    program: Vec<Instruction>,
+    /// Instructions that are always executed by the interpreter
+    interpreted: usize,
+}
+
+impl JitCode {
+    /// Number of instructions that are always left to the interpreter.
+    pub fn interpreted(&self) -> usize {
+        self.interpreted
+    }
+
+    fn entry(&self) -> Entry {
+        // SAFETY: the buffer starts with the entry code and is executable
+        unsafe { std::mem::transmute::<*mut c_void, Entry>(self.buf) }
+    }
+}
+
+impl Drop for JitCode {
+    fn drop(&mut self) {
+        // SAFETY: the buffer was mapped by `compile_jit` and is not used anymore
+        unsafe {
+            munmap(self.buf, self.size);
+        }
+    }
+}
+
+impl BM {
+    /// Compile the loaded program to machine code for `BM::execute_jit`.
+    /// ```
+    /// use bm::{Instruction, BM};
+    /// let mut bm: BM = Default::default();
+    /// bm.load_program_from_memory(&[
+    ///     Instruction::Push(0),
+    ///     Instruction::Push(1),
+    ///     Instruction::Plus,
+    ///     Instruction::Dup(0),
+    ///     Instruction::Push(100),
+    ///     Instruction::Lt,
+    ///     Instruction::JumpIf(Some(1)),
+    ///     Instruction::Halt,
+    /// ])
+    /// .unwrap();
+    /// let code = bm.compile_jit().unwrap();
+    /// bm.execute_jit(&code, None).unwrap();
+    /// assert_eq!(bm.stack(), &[100]);
+    /// ```
+    pub fn compile_jit(&self) -> Result<JitCode, Error> {
+        // Addresses are encoded as 32-bit immediates
+        let capacity = i32::MAX as usize / 8;
+        if self.program.len() > capacity {
+            return Err(LoadErr::ProgramTooLarge {
+                size: self.program.len(),
+                capacity,
+            }
+            .into());
+        }
+        let asm = Asm::program(&self.program);
+        let mut code = asm.code;
+        let size = code.len();
+        // SAFETY: anonymous private mapping, checked for failure below
+        let buf = unsafe {
+            mmap(
+                std::ptr::null_mut(),
+                size,
+                PROT_READ | PROT_WRITE,
+                MAP_PRIVATE | MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if buf as isize == -1 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let base = buf as usize;
+        let table = asm.labels[&Label::Table];
+        for addr in 0..self.program.len() {
+            let at = table + 8 * addr;
+            let target = (base + asm.labels[&Label::Inst(addr)]) as u64;
+            code[at..at + 8].copy_from_slice(&target.to_le_bytes());
+        }
+        let jit = JitCode {
+            buf,
+            size,
+            program: self.program.clone(),
+            interpreted: asm.interpreted,
+        };
+        // SAFETY: the mapping is `size` bytes long and not executable yet
+        unsafe {
+            std::ptr::copy_nonoverlapping(code.as_ptr(), buf as *mut u8, size);
+            if mprotect(buf, size, PROT_READ | PROT_EXEC) != 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+        }
+        Ok(jit)
+    }
+
+    /// Execute the program compiled by `BM::compile_jit`, with the same results as
+    /// `BM::execute_program`. Accepts `limit` as the number of max instructions to be
+    /// executed, like `BM::execute_program`.
+    pub fn execute_jit(&mut self, code: &JitCode, limit: Option<usize>) -> Result<(), Error> {
+        if code.program != self.program {
+            return Err(LoadErr::CodeMismatch.into());
+        }
+        // `execute_program` runs `limit - 1` instructions
+        let steps = limit.map_or(usize::MAX, |l| l.saturating_sub(1));
+        let budget = self
+            .config
+            .instruction_budget
+            .map_or(usize::MAX, |b| b.saturating_sub(self.executed));
+        let mut fuel = steps.min(budget);
+        while !self.halt && fuel > 0 {
+            let mut ctx = Ctx {
+                stack: self.stack.as_mut_ptr(),
+                len: self.stack.len(),
+                limit: self.config.stack_capacity.min(self.stack.capacity()),
+                fuel,
+                calls: self.call_stack.as_mut_ptr(),
+                call_len: self.call_stack.len(),
+                call_limit: self.config.call_depth.min(self.call_stack.capacity()),
+                ip: self.ip,
+            };
+            // SAFETY: the code only writes the stacks below their limits, which are within
+            // their capacities, and leaves the length of everything it wrote in `ctx`
+            let status = unsafe {
+                let status = (code.entry())(&mut ctx);
+                self.stack.set_len(ctx.len);
+                self.call_stack.set_len(ctx.call_len);
+                status
+            };
+            self.ip = ctx.ip;
+            self.executed += fuel - ctx.fuel;
+            fuel = ctx.fuel;
+            match status {
+                HALTED => self.halt = true,
+                INTERPRET if fuel > 0 => {
+                    self.interpret()?;
+                    self.executed += 1;
+                    fuel -= 1;
+                }
+                _ => {}
+            }
+        }
+        if fuel == 0 && !self.halt && budget < steps {
+            return Err(InterpreterErr::BudgetExhausted.into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{BMBuilder, Error, Instruction, Word, BM};
+
+    const STACK_CAPACITY: usize = 4;
+
+    /// Every translated instruction, with operands in and out of range.
+    fn instructions() -> Vec<Instruction> {
+        let mut instructions = vec![
+            Instruction::Nop,
+            Instruction::Push(7),
+            Instruction::Push(Word::MIN),
+            Instruction::Drop,
+            Instruction::Over,
+            Instruction::Rot,
+            Instruction::Plus,
+            Instruction::Minus,
+            Instruction::Mult,
+            Instruction::Div,
+            Instruction::Eq,
+            Instruction::Ne,
+            Instruction::Lt,
+            Instruction::Gt,
+            Instruction::Le,
+            Instruction::Ge,
+            Instruction::And,
+            Instruction::Or,
+            Instruction::Xor,
+            Instruction::Not,
+            Instruction::Ret,
+            Instruction::Halt,
+        ];
+        for n in [-1, 0, 1, 2, 3, 15, 16, 4294967295, Word::MAX, Word::MIN] {
+            instructions.push(Instruction::Dup(n));
+            instructions.push(Instruction::Swap(n));
+        }
+        for target in [None, Some(-1), Some(0), Some(1), Some(100)] {
+            instructions.push(Instruction::Jump(target));
+            instructions.push(Instruction::JumpIf(target));
+            instructions.push(Instruction::JumpZero(target));
+            instructions.push(Instruction::Call(target));
+        }
+        instructions
+    }
+
+    /// Stacks to run every instruction on, from empty to full.
+    fn stacks() -> Vec<Vec<Word>> {
+        vec![
+            vec![],
+            vec![0],
+            vec![5, 0],
+            vec![5, -1],
+            vec![Word::MIN, -1],
+            vec![Word::MAX, 1, 3],
+            vec![1, 2, 3, 0],
+        ]
+    }
+
+    fn machine(program: &[Instruction], call_depth: usize) -> Result<BM, Error> {
+        let mut bm = BMBuilder::new()
+            .stack_capacity(STACK_CAPACITY)
+            .call_depth(call_depth)
+            .build();
+        bm.load_program_from_memory(program)?;
+        Ok(bm)
+    }
+
+    #[test]
+    fn matches_interpreter() {
+        for inst in instructions() {
+            for stack in stacks() {
+                for call_depth in [0, 1] {
+                    let mut program: Vec<Instruction> =
+                        stack.iter().map(|w| Instruction::Push(*w)).collect();
+                    program.push(inst.clone());
+                    program.push(Instruction::Halt);
+                    // Jumps to themselves never halt
+                    for limit in [Some(stack.len() + 1), Some(stack.len() + 2), Some(100)] {
+                        // Unresolved addresses are rejected when loading
+                        let Ok(mut reference) = machine(&program, call_depth) else {
+                            continue;
+                        };
+                        let expected = reference.execute_program(limit);
+
+                        let mut jit = machine(&program, call_depth).unwrap();
+                        let code = jit.compile_jit().unwrap();
+                        let actual = jit.execute_jit(&code, limit);
+
+                        let case = format!("{} on {:?} with limit {:?}", inst, stack, limit);
+                        assert_eq!(
+                            format!("{:?}", actual),
+                            format!("{:?}", expected),
+                            "{}",
+                            case
+                        );
+                        assert_eq!(jit.snapshot().state, reference.snapshot().state, "{}", case);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn matches_interpreter_with_calls() {
+        // Sum of squares of 1..=10, squaring in a subroutine
+        let program = vec![
+            Instruction::Push(0),
+            Instruction::Push(10),
+            Instruction::Dup(0),
+            Instruction::Call(Some(12)),
+            Instruction::Rot,
+            Instruction::Plus,
+            Instruction::Swap(1),
+            Instruction::Push(1),
+            Instruction::Minus,
+            Instruction::Dup(0),
+            Instruction::JumpIf(Some(2)),
+            Instruction::Halt,
+            Instruction::Dup(0),
+            Instruction::Mult,
+            Instruction::Ret,
+        ];
+        for limit in [None, Some(1), Some(10), Some(37)] {
+            let mut reference = machine(&program, 1).unwrap();
+            let expected = reference.execute_program(limit);
+            let mut jit = machine(&program, 1).unwrap();
+            let code = jit.compile_jit().unwrap();
+            let actual = jit.execute_jit(&code, limit);
+            assert_eq!(format!("{:?}", actual), format!("{:?}", expected));
+            assert_eq!(jit.snapshot().state, reference.snapshot().state);
+        }
+    }
+}
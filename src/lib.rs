@@ -1,72 +1,287 @@
+pub mod config;
+pub mod debugger;
+pub mod diagnostic;
+pub mod error;
+pub mod format;
 pub mod instruction;
 pub mod interpreter;
+#[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+pub mod jit;
+pub mod linker;
+pub mod native;
+pub mod optimizer;
+mod preprocessor;
+pub mod profiler;
 pub mod serialize_deserialize;
+pub mod snapshot;
+pub mod stream;
+pub mod test_runner;
+pub mod threaded;
+pub mod trace;
+pub mod verifier;
+pub use config::{BMBuilder, BMConfig};
+pub use error::{Error, LoadErr};
 pub use instruction::Instruction;
 
+use interpreter::InterpreterErr;
+use native::{NativeStack, Natives};
+use stream::HostIo;
+
 use std::io::Write;
 
-/// Represents the maximum capacity of the evaluation stack.
+/// Represents the default capacity of the evaluation stack.
 pub const BM_STACK_CAPACITY: usize = 1024;
-/// Represents the maximum capacity of the instruction list.
+/// Represents the default capacity of the instruction list.
 pub const BM_PROGRAM_CAPACITY: usize = 1024;
+/// Represents the default maximum depth of nested calls.
+pub const BM_CALL_STACK_CAPACITY: usize = 1024;
+/// Represents the default size of the data memory in bytes.
+pub const BM_MEMORY_CAPACITY: usize = 64 * 1024;
 
 /// A word in the virtual machine. Each element of the evaluation stack as well as the instruction pointer needs to be a Word.
 pub type Word = i64;
 
+/// Reinterpret the bits of a word as a float.
+pub fn word_to_f64(w: Word) -> f64 {
+    f64::from_bits(w as u64)
+}
+
+/// Store the bits of a float in a word.
+pub fn f64_to_word(f: f64) -> Word {
+    f.to_bits() as Word
+}
+
+/// How words are rendered when dumping the stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WordFormat {
+    /// Signed integers
+    #[default]
+    Int,
+    /// Bit patterns of floats
+    Float,
+    /// Hexadecimal bit patterns
+    Hex,
+}
+
+impl WordFormat {
+    pub fn render(self, w: Word) -> String {
+        match self {
+            WordFormat::Int => w.to_string(),
+            WordFormat::Float => format!("{:?}", word_to_f64(w)),
+            WordFormat::Hex => format!("{:#018x}", w),
+        }
+    }
+}
+
+/// Writes a stack the way `BM::dump_stack_as` does.
+pub(crate) fn write_stack<W>(f: &mut W, stack: &[Word], format: WordFormat) -> std::io::Result<()>
+where
+    W: Write,
+{
+    writeln!(f, "Stack: ")?;
+    if stack.is_empty() {
+        writeln!(f, "   [empty]")?;
+        return Ok(());
+    }
+    for w in stack {
+        writeln!(f, "   {}", format.render(*w))?;
+    }
+    Ok(())
+}
+
 /// BM represents an instance of the virtual machine with all it's state.
 #[derive(Debug)]
 pub struct BM {
     /// This is the evaluation stack of the virtual machine
     stack: Vec<Word>,
+    /// Return addresses of the calls currently in progress
+    call_stack: Vec<Word>,
+    /// Byte addressable data memory used by load and store instructions
+    memory: Vec<u8>,
+    /// Functions provided by the host for the native instruction
+    natives: Natives,
+    /// Streams used by the I/O instructions
+    io: HostIo,
     /// Tracks if the program halted; as of now, only true if Instruction::Halt was interpreted
     halt: bool,
     /// This is the list of instructions for the virtual machine.
     program: Vec<Instruction>,
     /// IP is the instruction pointer for the virtual machine and represents the instruction that is to be executed next.
     ip: Word,
+    /// Limits of this instance
+    config: BMConfig,
+    /// Number of instructions executed so far, counted against the instruction budget
+    executed: usize,
 }
 
 impl Default for BM {
     fn default() -> Self {
-        let mut stack = Vec::new();
-        stack.reserve(BM_STACK_CAPACITY);
-
-        let mut program = Vec::new();
-        program.reserve(BM_PROGRAM_CAPACITY);
+        Self::with_config(BMConfig::default())
+    }
+}
 
+impl BM {
+    /// Creates a virtual machine with the limits of `config`.
+    pub fn with_config(config: BMConfig) -> Self {
         Self {
-            stack,
+            // Large limits are not allocated upfront
+            stack: Vec::with_capacity(config.stack_capacity.min(BM_STACK_CAPACITY)),
+            call_stack: Vec::with_capacity(config.call_depth.min(BM_CALL_STACK_CAPACITY)),
+            memory: vec![0; config.memory_size],
+            natives: Default::default(),
+            io: Default::default(),
             halt: Default::default(),
-            program,
+            program: Vec::with_capacity(config.program_capacity.min(BM_PROGRAM_CAPACITY)),
             ip: Default::default(),
+            config,
+            executed: 0,
         }
     }
-}
 
-impl BM {
+    /// Creates a builder to configure the limits of a virtual machine.
+    pub fn builder() -> BMBuilder {
+        BMBuilder::new()
+    }
+
+    /// Creates a virtual machine with `size` bytes of data memory.
+    pub fn with_memory_size(size: usize) -> Self {
+        Self::builder().memory_size(size).build()
+    }
+
+    /// The limits of the virtual machine.
+    pub fn config(&self) -> &BMConfig {
+        &self.config
+    }
+
+    /// Number of instructions executed so far.
+    pub fn executed(&self) -> usize {
+        self.executed
+    }
+
     /// Checks if the virtual machine is halted
     pub fn is_halted(&self) -> bool {
         self.halt
     }
 
-    // Pushes a single Instruction into the virtual machine program
-    pub fn push_inst(&mut self, inst: Instruction) {
-        assert!(self.program.len() <= BM_PROGRAM_CAPACITY);
+    // Pushes a single Instruction into the virtual machine program, checked like
+    // `BM::load_program_from_memory`
+    pub fn push_inst(&mut self, inst: Instruction) -> Result<(), Error> {
+        self.check_program(std::slice::from_ref(&inst))?;
         self.program.push(inst);
+        Ok(())
     }
 
     /// Copies the program from a Instruction slice to the virtual machine instruction list.
-    pub fn load_program_from_memory(&mut self, program: &[Instruction]) {
-        assert!(program.len() <= BM_PROGRAM_CAPACITY);
+    /// Programs calling native functions that are not registered or with address operands
+    /// that were never resolved are rejected.
+    pub fn load_program_from_memory(&mut self, program: &[Instruction]) -> Result<(), Error> {
+        self.check_program(program)?;
         self.program.extend_from_slice(program);
+        Ok(())
+    }
+
+    /// Check that `program` can be appended to the loaded program.
+    fn check_program(&self, program: &[Instruction]) -> Result<(), LoadErr> {
+        self.check_program_size(program.len())?;
+        for (addr, inst) in program.iter().enumerate() {
+            if let Instruction::Native(name) = inst {
+                if !self.natives.contains(name) {
+                    return Err(LoadErr::UnknownNative(name.clone()));
+                }
+            }
+            if inst.is_unresolved() {
+                let addr = (self.program.len() + addr) as Word;
+                return Err(LoadErr::UnresolvedAddress(addr));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_program_size(&self, extra: usize) -> Result<(), LoadErr> {
+        let size = self.program.len() + extra;
+        if size > self.config.program_capacity {
+            return Err(LoadErr::ProgramTooLarge {
+                size,
+                capacity: self.config.program_capacity,
+            });
+        }
+        Ok(())
+    }
+
+    /// Registers a host function that programs can call with `native <name>`.
+    /// ```
+    /// use bm::{BM, Instruction};
+    /// let mut bm: BM = Default::default();
+    /// bm.register_native("square", |stack| {
+    ///     let x = stack.pop()?;
+    ///     stack.push(x * x)
+    /// });
+    /// bm.load_program_from_memory(&[
+    ///     Instruction::Push(7),
+    ///     Instruction::Native("square".to_string()),
+    ///     Instruction::Halt,
+    /// ])
+    /// .unwrap();
+    /// bm.execute_program(None).unwrap();
+    /// assert_eq!(bm.stack(), &[49]);
+    /// ```
+    pub fn register_native<F>(&mut self, name: &str, f: F)
+    where
+        F: FnMut(&mut NativeStack) -> Result<(), InterpreterErr> + 'static,
+    {
+        self.natives.insert(name.to_string(), Box::new(f));
+    }
+
+    /// Replaces the stream read by `read_char` and `read_int` (stdin by default).
+    pub fn set_input<R>(&mut self, input: R)
+    where
+        R: std::io::Read + 'static,
+    {
+        self.io.set_input(input);
+    }
+
+    /// Replaces the stream written by `write_char`, `write_int` and `print_debug`
+    /// (stdout by default).
+    pub fn set_output<W>(&mut self, output: W)
+    where
+        W: Write + 'static,
+    {
+        self.io.set_output(output);
+    }
+
+    /// Flushes everything written by the program.
+    pub fn flush_output(&mut self) -> std::io::Result<()> {
+        self.io.flush()
+    }
+
+    /// The instruction pointer, i.e. the address of the instruction to be executed next.
+    pub fn ip(&self) -> Word {
+        self.ip
+    }
+
+    /// The evaluation stack, the top of the stack being the last element.
+    pub fn stack(&self) -> &[Word] {
+        &self.stack
+    }
+
+    /// The instruction to be executed next, if the instruction pointer is valid.
+    pub fn current_instruction(&self) -> Option<&Instruction> {
+        usize::try_from(self.ip)
+            .ok()
+            .and_then(|ip| self.program.get(ip))
+    }
+
+    /// The instructions loaded into the virtual machine.
+    pub fn program(&self) -> &[Instruction] {
+        &self.program
     }
 
     /// Dumps the current state of the stack into a Writer.
     /// ```
     /// use bm::{BM, Instruction};
     /// let mut bm: BM = Default::default();
-    /// bm.push_inst(bm::Instruction::Push(1));
-    /// bm.push_inst(bm::Instruction::Push(2));
+    /// bm.push_inst(bm::Instruction::Push(1)).unwrap();
+    /// bm.push_inst(bm::Instruction::Push(2)).unwrap();
     /// bm.program_to_asm(&mut std::io::stdout()).unwrap();
     /// bm.dump_stack(&mut std::io::stdout());
     /// ```
@@ -74,13 +289,49 @@ impl BM {
     where
         W: Write,
     {
-        writeln!(f, "Stack: ")?;
-        if self.stack.len() == 0 {
-            writeln!(f, "   [empty]")?;
-            return Ok(());
-        }
-        for i in 0..self.stack.len() {
-            writeln!(f, "   {}", self.stack[i])?;
+        self.dump_stack_as(f, WordFormat::Int)
+    }
+
+    /// Dumps the current state of the stack into a Writer, rendering words as `format`.
+    /// ```
+    /// use bm::{BM, Instruction, WordFormat};
+    /// let mut bm: BM = Default::default();
+    /// bm.push_inst(Instruction::Push(bm::f64_to_word(1.5))).unwrap();
+    /// bm.push_inst(Instruction::Halt).unwrap();
+    /// bm.execute_program(None).unwrap();
+    /// let mut out = Vec::new();
+    /// bm.dump_stack_as(&mut out, WordFormat::Float).unwrap();
+    /// assert_eq!(String::from_utf8(out).unwrap(), "Stack: \n   1.5\n");
+    /// ```
+    pub fn dump_stack_as<W>(&self, f: &mut W, format: WordFormat) -> std::io::Result<()>
+    where
+        W: Write,
+    {
+        write_stack(f, &self.stack, format)
+    }
+
+    /// Dumps the data memory into a Writer as hex, 16 bytes per row.
+    /// Consecutive rows of zeros are collapsed into a single `*`.
+    pub fn dump_memory<W>(&self, f: &mut W) -> std::io::Result<()>
+    where
+        W: Write,
+    {
+        writeln!(f, "Memory: ")?;
+        let mut skipping = false;
+        for (i, row) in self.memory.chunks(16).enumerate() {
+            if row.iter().all(|b| *b == 0) {
+                if !skipping {
+                    writeln!(f, "   *")?;
+                    skipping = true;
+                }
+                continue;
+            }
+            skipping = false;
+            write!(f, "   {:08x}:", i * 16)?;
+            for b in row {
+                write!(f, " {:02x}", b)?;
+            }
+            writeln!(f)?;
         }
         Ok(())
     }
@@ -0,0 +1,269 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::Path;
+
+use crate::format::{BmFile, Symbol};
+use crate::{Instruction, Word};
+
+/// A relocatable object to link, named after the file it was read from.
+#[derive(Debug, Clone)]
+pub struct Object {
+    pub name: String,
+    pub file: BmFile,
+}
+
+impl Object {
+    /// Name the labels of the object that are not exported are prefixed with.
+    fn module(&self) -> String {
+        Path::new(&self.name)
+            .file_stem()
+            .map_or_else(|| self.name.clone(), |s| s.to_string_lossy().into_owned())
+    }
+}
+
+/// Errors that can be emitted while linking objects.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkErr {
+    /// The file is a program rather than a relocatable object
+    NotAnObject { object: String },
+    /// Two objects export the same symbol
+    DuplicateSymbol {
+        name: String,
+        first: String,
+        second: String,
+    },
+    /// An object imports a symbol that no object exports
+    UndefinedSymbol { name: String, object: String },
+    /// A relocation does not point at an address operand it can patch
+    BadRelocation { object: String, addr: Word },
+    /// More than one object has initial contents for the data memory
+    ConflictingData { first: String, second: String },
+}
+
+impl Display for LinkErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LinkErr::NotAnObject { object } => {
+                write!(f, "{}: not a relocatable object", object)
+            }
+            LinkErr::DuplicateSymbol {
+                name,
+                first,
+                second,
+            } => write!(
+                f,
+                "symbol `{}` is exported by both {} and {}",
+                name, first, second
+            ),
+            LinkErr::UndefinedSymbol { name, object } => {
+                write!(f, "{}: undefined symbol `{}`", object, name)
+            }
+            LinkErr::BadRelocation { object, addr } => write!(
+                f,
+                "{}: relocation at {} does not point at an address operand",
+                object, addr
+            ),
+            LinkErr::ConflictingData { first, second } => {
+                write!(f, "both {} and {} have a data section", first, second)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LinkErr {}
+
+/// Link objects into a program ready to run. The code of the objects is laid out in
+/// order, so the program starts at the beginning of the first object.
+/// Labels that are not exported are kept as `<module>.<label>` symbols.
+/// Every problem found is reported, not only the first one.
+pub fn link(objects: &[Object]) -> Result<BmFile, Vec<LinkErr>> {
+    let mut errors = Vec::new();
+    let mut bases = Vec::with_capacity(objects.len());
+    let mut size = 0;
+    for o in objects {
+        if !o.file.is_object() {
+            errors.push(LinkErr::NotAnObject {
+                object: o.name.clone(),
+            });
+        }
+        bases.push(size as Word);
+        size += o.file.code.len();
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    // Addresses of the exported symbols in the linked program
+    let mut globals: HashMap<&str, (Word, &str)> = HashMap::new();
+    for (o, base) in objects.iter().zip(&bases) {
+        for s in &o.file.exports {
+            match globals.get(s.name.as_str()) {
+                Some((_, first)) => errors.push(LinkErr::DuplicateSymbol {
+                    name: s.name.clone(),
+                    first: first.to_string(),
+                    second: o.name.clone(),
+                }),
+                None => {
+                    globals.insert(&s.name, (base + s.addr, &o.name));
+                }
+            }
+        }
+    }
+
+    let mut program = BmFile::new(Vec::with_capacity(size));
+    let mut data_from: Option<&str> = None;
+    for (o, base) in objects.iter().zip(&bases) {
+        let mut code = o.file.code.clone();
+        for r in &o.file.relocations {
+            let operand = code
+                .get_mut(r.addr as usize)
+                .and_then(Instruction::target_mut);
+            match (operand, &r.symbol) {
+                (Some(Some(addr)), None) => *addr += base,
+                (Some(target @ None), Some(name)) => match globals.get(name.as_str()) {
+                    Some((addr, _)) => *target = Some(*addr),
+                    None => {
+                        let err = LinkErr::UndefinedSymbol {
+                            name: name.clone(),
+                            object: o.name.clone(),
+                        };
+                        if !errors.contains(&err) {
+                            errors.push(err);
+                        }
+                    }
+                },
+                _ => errors.push(LinkErr::BadRelocation {
+                    object: o.name.clone(),
+                    addr: r.addr,
+                }),
+            }
+        }
+        program.code.extend(code);
+
+        let module = o.module();
+        for s in &o.file.symbols {
+            let exported = o.file.exports.contains(s);
+            program.symbols.push(Symbol {
+                name: match exported {
+                    true => s.name.clone(),
+                    false => format!("{}.{}", module, s.name),
+                },
+                addr: base + s.addr,
+            });
+        }
+
+        if !o.file.data.is_empty() {
+            match data_from {
+                Some(first) => errors.push(LinkErr::ConflictingData {
+                    first: first.to_string(),
+                    second: o.name.clone(),
+                }),
+                None => {
+                    data_from = Some(&o.name);
+                    program.data = o.file.data.clone();
+                }
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(program)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{Relocation, BM_FLAG_OBJECT};
+    use crate::Instruction::*;
+
+    /// An object exporting every label in `exports` and calling every symbol in `imports`.
+    fn object(name: &str, exports: &[(&str, Word)], imports: &[&str]) -> Object {
+        let mut file = BmFile::new(vec![Nop; 2]);
+        file.flags = BM_FLAG_OBJECT;
+        for (name, addr) in exports {
+            let symbol = Symbol {
+                name: name.to_string(),
+                addr: *addr,
+            };
+            file.symbols.push(symbol.clone());
+            file.exports.push(symbol);
+        }
+        for name in imports {
+            file.relocations.push(Relocation {
+                addr: file.code.len() as Word,
+                symbol: Some(name.to_string()),
+            });
+            file.code.push(Call(None));
+            file.imports.push(name.to_string());
+        }
+        file.code.push(Ret);
+        Object {
+            name: name.to_string(),
+            file,
+        }
+    }
+
+    #[test]
+    fn imports_are_resolved_to_exports_of_other_objects() {
+        let objects = [
+            object("main.o", &[], &["f"]),
+            object("lib.o", &[("f", 1)], &[]),
+        ];
+        let program = link(&objects).unwrap();
+        assert!(!program.is_object());
+        assert_eq!(program.code[2], Call(Some(5)));
+        assert_eq!(program.symbol_at(5), Some("f"));
+    }
+
+    #[test]
+    fn duplicate_symbol() {
+        let objects = [
+            object("a.o", &[("f", 0)], &[]),
+            object("b.o", &[("g", 0)], &[]),
+            object("c.o", &[("f", 1)], &[]),
+        ];
+        assert_eq!(
+            link(&objects).unwrap_err(),
+            vec![LinkErr::DuplicateSymbol {
+                name: "f".to_string(),
+                first: "a.o".to_string(),
+                second: "c.o".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn undefined_symbol_is_reported_once_per_object() {
+        let objects = [
+            object("a.o", &[], &["f", "f"]),
+            object("b.o", &[], &["f", "g"]),
+        ];
+        let errors = link(&objects).unwrap_err();
+        let undefined = |name: &str, object: &str| LinkErr::UndefinedSymbol {
+            name: name.to_string(),
+            object: object.to_string(),
+        };
+        assert_eq!(
+            errors,
+            vec![
+                undefined("f", "a.o"),
+                undefined("f", "b.o"),
+                undefined("g", "b.o")
+            ]
+        );
+    }
+
+    #[test]
+    fn programs_can_not_be_linked() {
+        let mut program = object("a.bm", &[], &[]);
+        program.file.flags = 0;
+        assert_eq!(
+            link(&[program]).unwrap_err(),
+            vec![LinkErr::NotAnObject {
+                object: "a.bm".to_string()
+            }]
+        );
+    }
+}
@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+
+use crate::interpreter::InterpreterErr;
+use crate::Word;
+
+/// A function provided by the host, called by the `native <name>` instruction.
+pub type NativeFn = Box<dyn FnMut(&mut NativeStack) -> Result<(), InterpreterErr>>;
+
+/// Mutable view of the evaluation stack handed to native functions.
+/// It enforces the same capacity limits as the interpreter.
+pub struct NativeStack<'a> {
+    stack: &'a mut Vec<Word>,
+    capacity: usize,
+}
+
+impl<'a> NativeStack<'a> {
+    pub(crate) fn new(stack: &'a mut Vec<Word>, capacity: usize) -> Self {
+        Self { stack, capacity }
+    }
+
+    /// Pop the top element of the stack.
+    pub fn pop(&mut self) -> Result<Word, InterpreterErr> {
+        self.stack.pop().ok_or(InterpreterErr::StackUnderflow)
+    }
+
+    /// Push an element on the stack.
+    pub fn push(&mut self, value: Word) -> Result<(), InterpreterErr> {
+        if self.stack.len() >= self.capacity {
+            return Err(InterpreterErr::StackOverflow);
+        }
+        self.stack.push(value);
+        Ok(())
+    }
+
+    /// Read the element which is `n` places from the top without removing it.
+    pub fn peek(&self, n: usize) -> Result<Word, InterpreterErr> {
+        self.stack
+            .len()
+            .checked_sub(n + 1)
+            .map(|i| self.stack[i])
+            .ok_or(InterpreterErr::StackUnderflow)
+    }
+
+    pub fn len(&self) -> usize {
+        self.stack.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stack.is_empty()
+    }
+
+    /// The whole stack, the top of the stack being the last element.
+    pub fn as_slice(&self) -> &[Word] {
+        self.stack
+    }
+}
+
+/// Native functions registered by the host, by name.
+#[derive(Default)]
+pub struct Natives {
+    functions: HashMap<String, NativeFn>,
+}
+
+impl Natives {
+    pub fn insert(&mut self, name: String, f: NativeFn) {
+        self.functions.insert(name, f);
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.functions.contains_key(name)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut NativeFn> {
+        self.functions.get_mut(name)
+    }
+}
+
+impl std::fmt::Debug for Natives {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.functions.keys()).finish()
+    }
+}
@@ -0,0 +1,373 @@
+//! Peephole optimizer. The passes rewrite short sequences of instructions:
+//!
+//! - constant folding: `push 2; push 3; plus` becomes `push 5`
+//! - identity elimination: `push 0; plus`, `push 1; mult`, `dup 0; drop`, ... are removed
+//! - jump threading: a jump to a `jmp` goes straight to its target, and a `jmp` to the
+//!   next instruction is removed
+//! - `nop` removal
+//!
+//! Sequences are only rewritten if control can not enter them past their first
+//! instruction, and every address operand is re-targeted once instructions are removed.
+//! Optimized programs compute the same results, but a program failing with a stack
+//! error may fail at another point or not at all, and executes fewer instructions.
+
+use std::collections::HashSet;
+
+use crate::serialize_deserialize::BasmCtx;
+use crate::{Instruction, Word, BM};
+
+/// An optimized program along with where its instructions moved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Optimized {
+    pub program: Vec<Instruction>,
+    /// New address of every original instruction, `None` if it was removed
+    moved: Vec<Option<Word>>,
+    /// Where execution continues when jumping to every original address, including the
+    /// end of the program
+    labels: Vec<Word>,
+}
+
+impl Optimized {
+    /// New address of the instruction at `addr`, `None` if it was removed.
+    pub fn instruction_addr(&self, addr: Word) -> Option<Word> {
+        usize::try_from(addr)
+            .ok()
+            .and_then(|addr| self.moved.get(addr).copied().flatten())
+    }
+
+    /// New address of a label defined at `addr`. Addresses outside of the program are
+    /// kept as they are.
+    pub fn label_addr(&self, addr: Word) -> Word {
+        usize::try_from(addr)
+            .ok()
+            .and_then(|addr| self.labels.get(addr).copied())
+            .unwrap_or(addr)
+    }
+}
+
+/// Optimize `program`. Control may enter the program at `entries` besides the addresses
+/// it jumps to, e.g. at labels other programs call.
+/// ```
+/// use bm::{optimizer::optimize, Instruction};
+/// let optimized = optimize(
+///     vec![
+///         Instruction::Push(2),
+///         Instruction::Push(3),
+///         Instruction::Plus,
+///         Instruction::Push(0),
+///         Instruction::Plus,
+///         Instruction::Jump(Some(6)),
+///         Instruction::Nop,
+///         Instruction::Halt,
+///     ],
+///     &[],
+/// );
+/// assert_eq!(
+///     optimized.program,
+///     vec![Instruction::Push(5), Instruction::Halt]
+/// );
+/// assert_eq!(optimized.label_addr(6), 1);
+/// ```
+pub fn optimize(program: Vec<Instruction>, entries: &[Word]) -> Optimized {
+    let len = program.len();
+    let mut optimized = Optimized {
+        program,
+        moved: (0..len as Word).map(Some).collect(),
+        labels: (0..=len as Word).collect(),
+    };
+    let mut entries = entries.to_vec();
+    loop {
+        let leaders = leaders(&optimized.program, &entries);
+        let mut changed = thread_jumps(&mut optimized.program);
+        changed |= peephole(&mut optimized.program, &leaders);
+        changed |= remove_nops(&mut optimized, &mut entries);
+        if !changed {
+            return optimized;
+        }
+    }
+}
+
+/// Addresses control can reach other than from the previous instruction.
+fn leaders(program: &[Instruction], entries: &[Word]) -> HashSet<Word> {
+    let mut leaders: HashSet<Word> = entries.iter().copied().collect();
+    leaders.insert(0);
+    for (addr, inst) in program.iter().enumerate() {
+        if let Some(target) = inst.target() {
+            leaders.insert(target);
+        }
+        // Where calls return to
+        if let Instruction::Call(_) = inst {
+            leaders.insert(addr as Word + 1);
+        }
+    }
+    leaders
+}
+
+/// Point jumps to a `jmp` at its target, and turn jumps to the next instruction into `nop`.
+fn thread_jumps(program: &mut [Instruction]) -> bool {
+    let mut changed = false;
+    for addr in 0..program.len() {
+        let original = match program[addr].target() {
+            Some(target) => target,
+            None => continue,
+        };
+        let mut target = original;
+        let mut seen = vec![target];
+        while let Some(Instruction::Jump(Some(next))) =
+            usize::try_from(target).ok().and_then(|t| program.get(t))
+        {
+            // Jumps into a loop of jumps are left alone
+            if seen.contains(next) {
+                target = original;
+                break;
+            }
+            seen.push(*next);
+            target = *next;
+        }
+        if let Some(operand) = program[addr].target_mut() {
+            if *operand != Some(target) {
+                *operand = Some(target);
+                changed = true;
+            }
+        }
+        if program[addr] == Instruction::Jump(Some(addr as Word + 1)) {
+            program[addr] = Instruction::Nop;
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Value of `op` applied to constants, if it can be computed without failing.
+fn fold(a: Word, b: Word, op: &Instruction) -> Option<Word> {
+    let r = match op {
+        Instruction::Plus => a.wrapping_add(b),
+        Instruction::Minus => a.wrapping_sub(b),
+        Instruction::Mult => a.wrapping_mul(b),
+        Instruction::Div if b != 0 => a.wrapping_div(b),
+        Instruction::Eq => (a == b) as Word,
+        Instruction::Ne => (a != b) as Word,
+        Instruction::Lt => (a < b) as Word,
+        Instruction::Gt => (a > b) as Word,
+        Instruction::Le => (a <= b) as Word,
+        Instruction::Ge => (a >= b) as Word,
+        Instruction::And => (a != 0 && b != 0) as Word,
+        Instruction::Or => (a != 0 || b != 0) as Word,
+        Instruction::Xor => ((a != 0) != (b != 0)) as Word,
+        _ => return None,
+    };
+    Some(r)
+}
+
+/// Whether the pair leaves the stack as it found it.
+fn is_identity(first: &Instruction, second: &Instruction) -> bool {
+    matches!(
+        (first, second),
+        (Instruction::Push(0), Instruction::Plus | Instruction::Minus)
+            | (Instruction::Push(1), Instruction::Mult | Instruction::Div)
+            | (
+                Instruction::Push(_) | Instruction::Dup(0),
+                Instruction::Drop
+            )
+    )
+}
+
+/// Rewrite sequences that are only entered at their first instruction. Removed
+/// instructions are replaced by `nop`.
+fn peephole(program: &mut [Instruction], leaders: &HashSet<Word>) -> bool {
+    let mut changed = false;
+    let enters = |addr: usize| leaders.contains(&(addr as Word));
+    let mut addr = 0;
+    while addr < program.len() {
+        if addr + 2 < program.len() && !enters(addr + 1) && !enters(addr + 2) {
+            if let (Instruction::Push(a), Instruction::Push(b)) =
+                (&program[addr], &program[addr + 1])
+            {
+                if let Some(r) = fold(*a, *b, &program[addr + 2]) {
+                    program[addr] = Instruction::Push(r);
+                    program[addr + 1] = Instruction::Nop;
+                    program[addr + 2] = Instruction::Nop;
+                    changed = true;
+                    addr += 3;
+                    continue;
+                }
+            }
+        }
+        if addr + 1 < program.len() && !enters(addr + 1) {
+            if let (Instruction::Push(a), Instruction::Not) = (&program[addr], &program[addr + 1]) {
+                program[addr] = Instruction::Push((*a == 0) as Word);
+                program[addr + 1] = Instruction::Nop;
+                changed = true;
+                addr += 2;
+                continue;
+            }
+            if is_identity(&program[addr], &program[addr + 1]) {
+                program[addr] = Instruction::Nop;
+                program[addr + 1] = Instruction::Nop;
+                changed = true;
+                addr += 2;
+                continue;
+            }
+        }
+        addr += 1;
+    }
+    changed
+}
+
+/// Remove every `nop`, re-targeting address operands and entries.
+fn remove_nops(optimized: &mut Optimized, entries: &mut [Word]) -> bool {
+    let program = &optimized.program;
+    // Where execution continues when jumping to every address, including the end
+    let mut labels = vec![0; program.len() + 1];
+    let mut next = program.iter().filter(|i| **i != Instruction::Nop).count() as Word;
+    labels[program.len()] = next;
+    for addr in (0..program.len()).rev() {
+        if program[addr] != Instruction::Nop {
+            next -= 1;
+        }
+        labels[addr] = next;
+    }
+    if labels[program.len()] as usize == program.len() {
+        return false;
+    }
+
+    let relabel = |addr: Word| match usize::try_from(addr).ok().and_then(|a| labels.get(a)) {
+        Some(new) => *new,
+        None => addr,
+    };
+    let kept: Vec<bool> = program.iter().map(|i| *i != Instruction::Nop).collect();
+    let mut program = std::mem::take(&mut optimized.program);
+    program.retain(|i| *i != Instruction::Nop);
+    for inst in &mut program {
+        if let Some(Some(target)) = inst.target_mut() {
+            *target = relabel(*target);
+        }
+    }
+    for entry in entries.iter_mut() {
+        *entry = relabel(*entry);
+    }
+    for moved in &mut optimized.moved {
+        *moved = moved.filter(|addr| kept[*addr as usize]).map(relabel);
+    }
+    for label in &mut optimized.labels {
+        *label = relabel(*label);
+    }
+    optimized.program = program;
+    true
+}
+
+impl BM {
+    /// Optimize the program assembled with `ctx`, keeping the labels, debug info and
+    /// relocations gathered in `ctx` in sync. Every label is kept as an entry point.
+    pub fn optimize_program(&mut self, ctx: &mut BasmCtx) {
+        let entries: Vec<Word> = ctx.symbols().iter().map(|s| s.addr).collect();
+        let optimized = optimize(std::mem::take(&mut self.program), &entries);
+        ctx.relocate(&optimized);
+        self.program = optimized.program;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Instruction::*;
+
+    #[test]
+    fn jumps_are_retargeted_past_removed_instructions() {
+        // The `jmpif` to the `jmp` is threaded to its target as well
+        let optimized = optimize(
+            vec![
+                Push(2),
+                Push(3),
+                Plus,
+                Jump(Some(6)),
+                Nop,
+                Push(7),
+                Push(1),
+                JumpIf(Some(3)),
+                Halt,
+            ],
+            &[],
+        );
+        assert_eq!(
+            optimized.program,
+            vec![
+                Push(5),
+                Jump(Some(3)),
+                Push(7),
+                Push(1),
+                JumpIf(Some(3)),
+                Halt
+            ]
+        );
+    }
+
+    #[test]
+    fn calls_are_retargeted_past_removed_instructions() {
+        let optimized = optimize(
+            vec![
+                Push(3),
+                Call(Some(4)),
+                Halt,
+                Nop,
+                Dup(0),
+                Drop,
+                Dup(0),
+                Mult,
+                Ret,
+            ],
+            &[],
+        );
+        assert_eq!(
+            optimized.program,
+            vec![Push(3), Call(Some(3)), Halt, Dup(0), Mult, Ret]
+        );
+        assert_eq!(optimized.instruction_addr(6), Some(3));
+        assert_eq!(optimized.instruction_addr(4), None);
+    }
+
+    #[test]
+    fn labels_move_to_the_next_kept_instruction() {
+        let optimized = optimize(vec![Nop, Push(1), Nop, Nop, WriteInt, Halt, Nop], &[2]);
+        assert_eq!(optimized.program, vec![Push(1), WriteInt, Halt]);
+        assert_eq!(optimized.label_addr(0), 0);
+        assert_eq!(optimized.label_addr(2), 1);
+        assert_eq!(optimized.label_addr(3), 1);
+        // The end of the program and addresses outside of it
+        assert_eq!(optimized.label_addr(7), 3);
+        assert_eq!(optimized.label_addr(-1), -1);
+        assert_eq!(optimized.label_addr(100), 100);
+    }
+
+    #[test]
+    fn entries_block_rewrites_across_them() {
+        // Control may enter at the `plus`, so the constants can not be folded
+        let program = vec![Push(2), Push(3), Plus, Halt];
+        let optimized = optimize(program.clone(), &[2]);
+        assert_eq!(optimized.program, program);
+    }
+
+    #[test]
+    fn symbols_follow_the_program() {
+        let source = "      push 3
+      nop
+      call square
+      halt
+square: push 0
+      plus
+      dup 0
+      mult
+      ret
+";
+        let mut bm: BM = Default::default();
+        let mut ctx = BasmCtx::default();
+        bm.program_from_asm(source.as_bytes(), &mut ctx).unwrap();
+        bm.optimize_program(&mut ctx);
+        assert_eq!(
+            bm.program(),
+            &[Push(3), Call(Some(3)), Halt, Dup(0), Mult, Ret, Halt]
+        );
+        let square = ctx.symbols().into_iter().find(|s| s.name == "square");
+        assert_eq!(square.map(|s| s.addr), Some(3));
+    }
+}
@@ -0,0 +1,698 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::diagnostic::{Diagnostic, Span};
+use crate::instruction::{strip_comment, tokenize, Token};
+use crate::serialize_deserialize::{BasmCtx, SourceFile};
+use crate::Word;
+
+/// Maximum depth of macros expanding other macros.
+const MAX_EXPANSION_DEPTH: usize = 64;
+
+/// A line of basm ready to be parsed into an instruction, after constants and macros
+/// have been expanded.
+#[derive(Debug, Clone)]
+pub(crate) struct SourceLine {
+    pub text: String,
+    /// Line of the source the text comes from (1-based)
+    pub line: usize,
+    /// Index of the source file the text comes from
+    pub file: usize,
+    /// Index of the macro expansion the line was produced by, if any
+    pub expansion: Option<usize>,
+}
+
+/// A `%macro name args ... %endmacro` block.
+#[derive(Debug, Clone)]
+pub(crate) struct MacroDef {
+    params: Vec<String>,
+    /// Lines of the body along with their line numbers
+    body: Vec<(usize, String)>,
+    /// Where the macro name is written in the definition
+    span: Span,
+}
+
+/// Record of a macro being used, so diagnostics can point at the use site.
+#[derive(Debug, Clone)]
+pub(crate) struct Expansion {
+    pub name: String,
+    pub use_span: Span,
+}
+
+/// Replace tokens of `text` for which `f` returns a value, keeping the rest of the text
+/// (and thus the columns before the first replacement) untouched.
+fn substitute<F>(text: &str, mut f: F) -> String
+where
+    F: FnMut(usize, &str) -> Option<String>,
+{
+    let mut out = String::with_capacity(text.len());
+    let mut rest = 0;
+    for (i, token) in text.split_whitespace().enumerate() {
+        let start = token.as_ptr() as usize - text.as_ptr() as usize;
+        out.push_str(&text[rest..start]);
+        match f(i, token) {
+            Some(replacement) => out.push_str(&replacement),
+            None => out.push_str(token),
+        }
+        rest = start + token.len();
+    }
+    out.push_str(&text[rest..]);
+    out
+}
+
+impl BasmCtx {
+    fn span_of(&self, line: usize, t: &Token) -> Span {
+        Span {
+            line,
+            col: t.col,
+            len: t.text.chars().count(),
+            expansion: self.expansion,
+            file: self.file,
+        }
+    }
+
+    /// Expand the `%include`, `%const` and `%macro` directives of a source file.
+    pub(crate) fn preprocess(&mut self, file: usize) -> Vec<SourceLine> {
+        let mut out = Vec::new();
+        self.preprocess_file(file, &mut out);
+        self.expansion = None;
+        out
+    }
+
+    fn preprocess_file(&mut self, file: usize, out: &mut Vec<SourceLine>) {
+        let source = self.files[file].source.clone();
+        let lines: Vec<&str> = source.lines().collect();
+        let mut i = 0;
+        while i < lines.len() {
+            let line = i + 1;
+            self.line = line;
+            self.file = file;
+            self.expansion = None;
+            let code = strip_comment(lines[i]);
+            let tokens: Vec<Token> = tokenize(code).collect();
+            i += 1;
+            match tokens.first().map(|t| t.text) {
+                Some("%include") => self.include(code, line, &tokens, out),
+                Some("%const") => self.define_const(line, &tokens),
+                Some("%export") | Some("%import") => self.declare_symbols(line, &tokens),
+                Some("%macro") => {
+                    let start = i;
+                    while i < lines.len() {
+                        let directive = tokenize(strip_comment(lines[i])).next();
+                        if directive.is_some_and(|t| t.text == "%endmacro") {
+                            break;
+                        }
+                        i += 1;
+                    }
+                    let body: Vec<(usize, String)> = (start..i)
+                        .map(|j| (j + 1, strip_comment(lines[j]).to_string()))
+                        .collect();
+                    if i == lines.len() {
+                        self.report(
+                            Diagnostic::error(self.span_of(line, &tokens[0]), "unterminated macro")
+                                .with_hint("close the macro with `%endmacro`"),
+                        );
+                    }
+                    i += 1; // skip %endmacro
+                    self.define_macro(line, &tokens, body);
+                }
+                Some("%endmacro") => self.report(Diagnostic::error(
+                    self.span_of(line, &tokens[0]),
+                    "`%endmacro` without a matching `%macro`",
+                )),
+                Some(directive) if directive.starts_with('%') => {
+                    let d = Diagnostic::error(
+                        self.span_of(line, &tokens[0]),
+                        format!("unknown directive `{}`", directive),
+                    );
+                    self.report(d.with_hint(
+                        "supported directives are `%include`, `%const`, `%macro`, `%export` and `%import`",
+                    ));
+                }
+                _ => self.expand_line(code, line, 0, out),
+            }
+        }
+    }
+
+    /// Find an included file next to the including file or in one of the include paths.
+    fn resolve_include(&self, name: &str) -> Option<PathBuf> {
+        let name = Path::new(name);
+        let dir = self.files[self.file]
+            .path
+            .parent()
+            .map(|dir| dir.join(name));
+        dir.into_iter()
+            .chain(self.include_paths.iter().map(|dir| dir.join(name)))
+            .find(|path| path.is_file())
+    }
+
+    fn include(&mut self, code: &str, line: usize, tokens: &[Token], out: &mut Vec<SourceLine>) {
+        let quoted = code.trim().trim_start_matches("%include").trim();
+        let span = match tokens.get(1) {
+            Some(t) => Span {
+                len: quoted.chars().count(),
+                ..self.span_of(line, t)
+            },
+            None => self.span_of(line, &tokens[0]),
+        };
+        let name = match quoted.strip_prefix('"').and_then(|q| q.strip_suffix('"')) {
+            Some(name) if !name.is_empty() => name,
+            _ => {
+                let d = Diagnostic::error(span, "malformed include");
+                self.report(d.with_hint("expected `%include \"path\"`"));
+                return;
+            }
+        };
+        let path = match self.resolve_include(name) {
+            Some(path) => path,
+            None => {
+                let d = Diagnostic::error(span, format!("cannot find included file `{}`", name));
+                let hint = match self.include_paths.len() {
+                    0 => "files are looked up relative to the including file".to_string(),
+                    n => format!(
+                        "files are looked up relative to the including file and in {} include path(s)",
+                        n
+                    ),
+                };
+                self.report(d.with_hint(hint));
+                return;
+            }
+        };
+
+        let canonical = |p: &Path| p.canonicalize().unwrap_or_else(|_| p.to_path_buf());
+        let target = canonical(&path);
+        if let Some(existing) = self.files.iter().position(|f| canonical(&f.path) == target) {
+            // Every file is included at most once, but including a file that is still
+            // being processed is a mistake
+            let mut chain = vec![self.file];
+            while let Some(from) = self.files[*chain.last().unwrap()].included_from {
+                chain.push(from.file);
+            }
+            if let Some(pos) = chain.iter().position(|f| *f == existing) {
+                let names: Vec<String> = chain[..=pos]
+                    .iter()
+                    .rev()
+                    .chain(std::iter::once(&existing))
+                    .map(|f| self.files[*f].path.display().to_string())
+                    .collect();
+                let d = Diagnostic::error(span, format!("`{}` includes itself", name));
+                self.report(d.with_hint(format!("include cycle: {}", names.join(" -> "))));
+            }
+            return;
+        }
+        let source = match std::fs::read_to_string(&path) {
+            Ok(source) => source,
+            Err(e) => {
+                self.report(Diagnostic::error(
+                    span,
+                    format!("could not read `{}`: {}", path.display(), e),
+                ));
+                return;
+            }
+        };
+
+        self.files.push(SourceFile {
+            path,
+            source,
+            included_from: Some(span),
+        });
+        let outer = self.file;
+        self.preprocess_file(self.files.len() - 1, out);
+        self.file = outer;
+    }
+
+    fn define_const(&mut self, line: usize, tokens: &[Token]) {
+        let (name, value) = match tokens {
+            [_, name, value] => (name, value),
+            _ => {
+                let d = Diagnostic::error(self.span_of(line, &tokens[0]), "malformed constant");
+                self.report(d.with_hint("expected `%const NAME value`"));
+                return;
+            }
+        };
+        let text = match self.consts.get(value.text) {
+            Some((text, _)) => text.clone(),
+            None => value.text.to_string(),
+        };
+        if text.parse::<Word>().is_err() && text.parse::<f64>().is_err() {
+            self.report(Diagnostic::error(
+                self.span_of(line, value),
+                format!("constant value `{}` is not a number", value.text),
+            ));
+            return;
+        }
+        let span = self.span_of(line, name);
+        if let Some((_, prev)) = self.consts.get(name.text) {
+            let prev = *prev;
+            self.report(Diagnostic::error(
+                span,
+                format!("constant `{}` is defined more than once", name.text),
+            ));
+            self.report(Diagnostic::note(prev, "previously defined here"));
+            return;
+        }
+        self.consts.insert(name.text.to_string(), (text, span));
+    }
+
+    /// Record the labels of an `%export` or `%import` directive.
+    fn declare_symbols(&mut self, line: usize, tokens: &[Token]) {
+        if tokens.len() < 2 {
+            let d = Diagnostic::error(
+                self.span_of(line, &tokens[0]),
+                format!("`{}` expects at least one label", tokens[0].text),
+            );
+            self.report(d);
+            return;
+        }
+        for t in &tokens[1..] {
+            let symbol = (t.text.to_string(), self.span_of(line, t));
+            match tokens[0].text {
+                "%export" => self.exports.push(symbol),
+                _ => self.imports.push(symbol),
+            }
+        }
+    }
+
+    fn define_macro(&mut self, line: usize, tokens: &[Token], body: Vec<(usize, String)>) {
+        let name = match tokens.get(1) {
+            Some(name) => name,
+            None => {
+                let d = Diagnostic::error(self.span_of(line, &tokens[0]), "macro without a name");
+                self.report(d.with_hint("expected `%macro name args...`"));
+                return;
+            }
+        };
+        for (l, text) in &body {
+            let directive = tokenize(text)
+                .next()
+                .filter(|t| t.text.starts_with('%') && !t.text.starts_with("%%"));
+            if let Some(t) = directive {
+                let d = Diagnostic::error(
+                    self.span_of(*l, &t),
+                    "directives are not allowed inside a macro",
+                );
+                self.report(d);
+                return;
+            }
+        }
+        let span = self.span_of(line, name);
+        if let Some(prev) = self.macros.get(name.text) {
+            let prev = prev.span;
+            self.report(Diagnostic::error(
+                span,
+                format!("macro `{}` is defined more than once", name.text),
+            ));
+            self.report(Diagnostic::note(prev, "previously defined here"));
+            return;
+        }
+        let params = tokens[2..].iter().map(|t| t.text.to_string()).collect();
+        self.macros
+            .insert(name.text.to_string(), MacroDef { params, body, span });
+    }
+
+    /// Substitute constants in the operands of the line and expand it if it uses a macro.
+    fn expand_line(&mut self, text: &str, line: usize, depth: usize, out: &mut Vec<SourceLine>) {
+        let consts = &self.consts;
+        let mut operands_from = 1;
+        let text = substitute(text, |i, token| {
+            if i == 0 && token.ends_with(':') {
+                operands_from = 2;
+            }
+            if i < operands_from {
+                return None;
+            }
+            consts.get(token).map(|(value, _)| value.clone())
+        });
+
+        let tokens: Vec<Token> = tokenize(&text).collect();
+        let (label, rest) = match tokens.split_first() {
+            Some((first, rest)) if first.text.ends_with(':') => (Some(first), rest),
+            _ => (None, &tokens[..]),
+        };
+        let (name, args) = match rest.split_first() {
+            Some((name, args)) if self.macros.contains_key(name.text) => (name, args),
+            _ => {
+                out.push(SourceLine {
+                    text,
+                    line,
+                    file: self.file,
+                    expansion: self.expansion,
+                });
+                return;
+            }
+        };
+
+        if let Some(label) = label {
+            out.push(SourceLine {
+                text: label.text.to_string(),
+                line,
+                file: self.file,
+                expansion: self.expansion,
+            });
+        }
+        let use_span = self.span_of(line, name);
+        let def = self.macros[name.text].clone();
+        if args.len() != def.params.len() {
+            self.report(Diagnostic::error(
+                use_span,
+                format!(
+                    "macro `{}` takes {} argument(s) but {} were given",
+                    name.text,
+                    def.params.len(),
+                    args.len()
+                ),
+            ));
+            self.report(Diagnostic::note(def.span, "macro defined here"));
+            return;
+        }
+        // A macro using itself, even through other macros, would never stop expanding
+        let mut outer = self.expansion;
+        while let Some(i) = outer {
+            if self.expansions[i].name == name.text {
+                let d = Diagnostic::error(use_span, format!("macro `{}` uses itself", name.text));
+                self.report(d.with_hint("macros can not be expanded recursively"));
+                return;
+            }
+            outer = self.expansions[i].use_span.expansion;
+        }
+        if depth >= MAX_EXPANSION_DEPTH {
+            self.report(Diagnostic::error(
+                use_span,
+                format!("macro `{}` expands too deeply", name.text),
+            ));
+            return;
+        }
+
+        let id = self.expansions.len();
+        self.expansions.push(Expansion {
+            name: name.text.to_string(),
+            use_span,
+        });
+        let args: HashMap<&str, &str> = def
+            .params
+            .iter()
+            .map(|p| p.as_str())
+            .zip(args.iter().map(|a| a.text))
+            .collect();
+
+        let (outer, outer_file) = (self.expansion, self.file);
+        self.expansion = Some(id);
+        self.file = def.span.file;
+        for (body_line, body_text) in &def.body {
+            // Local labels written `%%name` are made unique to every expansion
+            let expanded = substitute(body_text, |_, token| match token.strip_prefix("%%") {
+                Some(local) => Some(format!("__{}_{}_{}", name.text, id, local)),
+                None => args.get(token).map(|a| a.to_string()),
+            });
+            self.expand_line(&expanded, *body_line, depth + 1, out);
+        }
+        self.expansion = outer;
+        self.file = outer_file;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::serialize_deserialize::BasmCtx;
+    use crate::Instruction::{self, *};
+    use crate::{Word, BM};
+
+    /// Write `files` into a fresh directory, returning its path.
+    fn write_files(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("bm-{}-{}", test, std::process::id()));
+        for (name, source) in files {
+            let path = dir.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, source).unwrap();
+        }
+        dir
+    }
+
+    /// Assemble `path`, returning the context holding the diagnostics and debug info.
+    fn assemble_file(path: PathBuf) -> (BasmCtx, bool) {
+        let mut bm: BM = Default::default();
+        let mut ctx = BasmCtx::default();
+        let ok = bm.program_from_asm_file(path, &mut ctx).is_ok();
+        (ctx, ok)
+    }
+
+    /// Assemble `source`, returning the severity, line and message of every diagnostic.
+    fn diagnostics(source: &str) -> Vec<(String, usize, String)> {
+        let mut bm: BM = Default::default();
+        let mut ctx = BasmCtx::default();
+        let _ = bm.program_from_asm(source.as_bytes(), &mut ctx);
+        ctx.diagnostics()
+            .iter()
+            .map(|d| (d.severity.to_string(), d.span.line, d.message.clone()))
+            .collect()
+    }
+
+    fn diagnostic(severity: &str, line: usize, message: &str) -> (String, usize, String) {
+        (severity.to_string(), line, message.to_string())
+    }
+
+    /// Assemble `source`, returning the program and the labels with their addresses.
+    fn assemble(source: &str) -> (Vec<Instruction>, Vec<(String, Word)>) {
+        let mut bm: BM = Default::default();
+        let mut ctx = BasmCtx::default();
+        bm.program_from_asm(source.as_bytes(), &mut ctx)
+            .unwrap_or_else(|e| panic!("{:?}: {:?}", e, ctx.diagnostics()));
+        let labels = ctx
+            .symbols()
+            .into_iter()
+            .map(|s| (s.name, s.addr))
+            .collect();
+        (bm.program, labels)
+    }
+
+    #[test]
+    fn constants_are_substituted_in_operands() {
+        let source = "%const N 7\n%const HALF 0.5\n%const ALIAS N\n    push N\n    push HALF\n    push ALIAS\n";
+        let (program, _) = assemble(source);
+        assert_eq!(
+            program,
+            vec![Push(7), Push(0.5f64.to_bits() as Word), Push(7), Halt]
+        );
+    }
+
+    #[test]
+    fn constants_only_replace_operands() {
+        let source = "%const dup 1\ndup:\n    push dup\n    dup 0\n";
+        let (program, labels) = assemble(source);
+        assert_eq!(program, vec![Push(1), Dup(0), Halt]);
+        assert_eq!(labels, vec![("dup".to_string(), 0)]);
+    }
+
+    #[test]
+    fn macro_parameters_are_substituted() {
+        let source = "%const TEN 10\n%macro add x y\n    push x\n    push y\n    plus\n%endmacro\n    add 1 2\n    add TEN 3\n";
+        let (program, _) = assemble(source);
+        assert_eq!(
+            program,
+            vec![Push(1), Push(2), Plus, Push(10), Push(3), Plus, Halt,]
+        );
+    }
+
+    #[test]
+    fn local_labels_are_unique_to_every_expansion() {
+        let (program, labels) = assemble(include_str!("../examples/macros.basm"));
+        let locals: Vec<&(String, Word)> = labels
+            .iter()
+            .filter(|(name, _)| name.contains("loop"))
+            .collect();
+        assert_eq!(locals.len(), 2, "{:?}", labels);
+        assert_ne!(locals[0].0, locals[1].0);
+        // Every expansion jumps back to its own loop
+        let jumps: Vec<&Instruction> = program
+            .iter()
+            .filter(|inst| matches!(inst, JumpIf(_)))
+            .collect();
+        assert_eq!(
+            jumps,
+            vec![&JumpIf(Some(locals[0].1)), &JumpIf(Some(locals[1].1))]
+        );
+    }
+
+    #[test]
+    fn recursive_macro_is_rejected_at_its_first_use() {
+        let source = "%macro m\n    m\n    m\n%endmacro\n    m\n";
+        assert_eq!(
+            diagnostics(source),
+            vec![
+                diagnostic("error", 2, "macro `m` uses itself"),
+                diagnostic("note", 5, "in expansion of macro `m`"),
+                diagnostic("error", 3, "macro `m` uses itself"),
+                diagnostic("note", 5, "in expansion of macro `m`"),
+            ]
+        );
+    }
+
+    #[test]
+    fn mutually_recursive_macros_are_rejected() {
+        let source = "%macro a\n    b\n%endmacro\n%macro b\n    a\n%endmacro\n    a\n";
+        assert_eq!(
+            diagnostics(source),
+            vec![
+                diagnostic("error", 5, "macro `a` uses itself"),
+                diagnostic("note", 2, "in expansion of macro `b`"),
+                diagnostic("note", 7, "in expansion of macro `a`"),
+            ]
+        );
+    }
+
+    #[test]
+    fn error_inside_macro_points_at_its_use() {
+        let source = "%macro twice x\n    push x\n    bogus\n%endmacro\n    twice 3\n";
+        assert_eq!(
+            diagnostics(source),
+            vec![
+                diagnostic("error", 3, "unknown instruction `bogus`"),
+                diagnostic("note", 5, "in expansion of macro `twice`"),
+            ]
+        );
+    }
+
+    #[test]
+    fn macro_argument_count_is_checked() {
+        let source = "%macro add x y\n    push x\n    push y\n    plus\n%endmacro\n    add 1\n";
+        assert_eq!(
+            diagnostics(source),
+            vec![
+                diagnostic(
+                    "error",
+                    6,
+                    "macro `add` takes 2 argument(s) but 1 were given"
+                ),
+                diagnostic("note", 1, "macro defined here"),
+            ]
+        );
+    }
+
+    #[test]
+    fn unterminated_macro_is_reported() {
+        let source = "%macro m\n    nop\n";
+        assert_eq!(
+            diagnostics(source),
+            vec![diagnostic("error", 1, "unterminated macro")]
+        );
+    }
+
+    #[test]
+    fn include_cycle_is_reported_with_the_chain() {
+        let dir = write_files(
+            "include-cycle",
+            &[
+                ("a.basm", "%include \"b.basm\"\n    halt\n"),
+                ("b.basm", "    nop\n%include \"a.basm\"\n"),
+            ],
+        );
+        let (ctx, ok) = assemble_file(dir.join("a.basm"));
+        assert!(!ok);
+        let d = ctx.diagnostics();
+        assert_eq!(d.len(), 2);
+        assert_eq!(d[0].message, "`a.basm` includes itself");
+        assert_eq!((d[0].span.file, d[0].span.line), (1, 2));
+        let a = dir.join("a.basm").display().to_string();
+        let b = dir.join("b.basm").display().to_string();
+        assert_eq!(
+            d[0].hint.as_deref(),
+            Some(format!("include cycle: {} -> {} -> {}", a, b, a).as_str())
+        );
+        assert_eq!(d[1].message, "in file included from here");
+        assert_eq!((d[1].span.file, d[1].span.line), (0, 1));
+    }
+
+    #[test]
+    fn file_included_twice_is_not_a_cycle() {
+        let dir = write_files(
+            "include-twice",
+            &[
+                (
+                    "main.basm",
+                    "%include \"lib.basm\"\n%include \"lib.basm\"\n    halt\n",
+                ),
+                ("lib.basm", "    nop\n"),
+            ],
+        );
+        let (ctx, ok) = assemble_file(dir.join("main.basm"));
+        assert!(ok, "{:?}", ctx.diagnostics());
+    }
+
+    #[test]
+    fn debug_entries_point_into_included_files() {
+        let dir = write_files(
+            "include-debug",
+            &[
+                ("main.basm", "    push 1\n%include \"lib.basm\"\n    halt\n"),
+                ("lib.basm", "\n    push 2\n"),
+            ],
+        );
+        let (ctx, ok) = assemble_file(dir.join("main.basm"));
+        assert!(ok, "{:?}", ctx.diagnostics());
+        let entries: Vec<(usize, usize)> = ctx
+            .debug_entries()
+            .iter()
+            .map(|e| (e.file, e.line))
+            .collect();
+        assert_eq!(entries, vec![(0, 1), (1, 2), (0, 3)]);
+    }
+
+    #[test]
+    fn include_paths_are_searched_in_order() {
+        let dir = write_files(
+            "include-paths",
+            &[
+                (
+                    "src/main.basm",
+                    "%include \"a.basm\"\n%include \"b.basm\"\n%include \"c.basm\"\n",
+                ),
+                ("src/a.basm", "    push 1\n"),
+                ("first/a.basm", "    push 2\n"),
+                ("first/b.basm", "    push 3\n"),
+                ("second/a.basm", "    push 4\n"),
+                ("second/b.basm", "    push 5\n"),
+                ("second/c.basm", "    push 6\n"),
+            ],
+        );
+        let mut bm: BM = Default::default();
+        let mut ctx = BasmCtx::default();
+        ctx.add_include_path(dir.join("first"));
+        ctx.add_include_path(dir.join("second"));
+        bm.program_from_asm_file(dir.join("src/main.basm"), &mut ctx)
+            .unwrap_or_else(|e| panic!("{:?}", e));
+        // The directory of the including file comes before the include paths
+        assert_eq!(bm.program, vec![Push(1), Push(3), Push(6), Halt]);
+        let included: Vec<PathBuf> = ctx.files[1..].iter().map(|f| f.path.clone()).collect();
+        assert_eq!(
+            included,
+            vec![
+                dir.join("src/a.basm"),
+                dir.join("first/b.basm"),
+                dir.join("second/c.basm")
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_include_mentions_the_include_paths() {
+        let dir = write_files(
+            "include-missing",
+            &[("main.basm", "%include \"lib.basm\"\n")],
+        );
+        let mut bm: BM = Default::default();
+        let mut ctx = BasmCtx::default();
+        ctx.add_include_path(dir.join("lib"));
+        assert!(bm
+            .program_from_asm_file(dir.join("main.basm"), &mut ctx)
+            .is_err());
+        let d = ctx.diagnostics();
+        assert_eq!(d.len(), 1);
+        assert_eq!(d[0].message, "cannot find included file `lib.basm`");
+        assert_eq!(
+            d[0].hint.as_deref(),
+            Some("files are looked up relative to the including file and in 1 include path(s)")
+        );
+    }
+}
@@ -0,0 +1,343 @@
+//! Instruction level profiler. Counts how many times every address executes, how often
+//! conditional jumps are taken, and the call stacks instructions execute in, which can
+//! be written out as a table or as folded stacks for flamegraph tools.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+
+use crate::format::Symbol;
+use crate::{Error, Instruction, Word, BM};
+
+/// Number of addresses listed as hot spots in the table.
+const HOT_SPOTS: usize = 20;
+
+/// How the profile is written out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProfileFormat {
+    /// Hot spots, instructions and branches as aligned text
+    Table,
+    /// One `frame;frame;... count` line per call stack, as read by flamegraph tools
+    Folded,
+}
+
+/// Gathers execution counts over any number of runs of the same program.
+#[derive(Debug, Default)]
+pub struct Profiler {
+    /// Sorted by address
+    symbols: Vec<Symbol>,
+    /// Executions of every address
+    counts: Vec<usize>,
+    /// Times the conditional jump at every address was taken
+    taken: Vec<usize>,
+    /// Entry address of every function the program is currently in, outermost first
+    frames: Vec<Word>,
+    /// Id of the current call stack
+    stack: usize,
+    /// Every call stack seen, indexed by id
+    stacks: Vec<Vec<Word>>,
+    stack_ids: HashMap<Vec<Word>, usize>,
+    /// Executions of every address in every call stack
+    samples: HashMap<(usize, Word), usize>,
+}
+
+impl Profiler {
+    /// Creates a profiler that annotates addresses with the given symbols.
+    pub fn new(mut symbols: Vec<Symbol>) -> Self {
+        symbols.sort_by_key(|s| s.addr);
+        Self {
+            symbols,
+            ..Default::default()
+        }
+    }
+
+    /// Total number of instructions profiled.
+    pub fn executed(&self) -> usize {
+        self.counts.iter().sum()
+    }
+
+    /// Executions of the instruction at `addr`.
+    pub fn count(&self, addr: Word) -> usize {
+        usize::try_from(addr)
+            .ok()
+            .and_then(|addr| self.counts.get(addr).copied())
+            .unwrap_or_default()
+    }
+
+    /// Executions of every kind of instruction, by mnemonic.
+    pub fn counts_by_instruction(&self, program: &[Instruction]) -> BTreeMap<String, usize> {
+        let mut counts = BTreeMap::new();
+        for (inst, count) in program.iter().zip(&self.counts) {
+            if *count > 0 {
+                *counts.entry(mnemonic(inst)).or_default() += count;
+            }
+        }
+        counts
+    }
+
+    /// How many times the conditional jump at `addr` was taken and not taken.
+    pub fn branch(&self, addr: Word) -> (usize, usize) {
+        let taken = usize::try_from(addr)
+            .ok()
+            .and_then(|addr| self.taken.get(addr).copied())
+            .unwrap_or_default();
+        (taken, self.count(addr) - taken)
+    }
+
+    /// Nearest label at or before `addr` along with the distance from it.
+    fn label(&self, addr: Word) -> Option<(&str, Word)> {
+        let after = self.symbols.partition_point(|s| s.addr <= addr);
+        after
+            .checked_sub(1)
+            .map(|i| (self.symbols[i].name.as_str(), addr - self.symbols[i].addr))
+    }
+
+    /// Address relative to the nearest label, e.g. `loop+2`.
+    fn describe(&self, addr: Word) -> String {
+        match self.label(addr) {
+            Some((name, 0)) => name.to_string(),
+            Some((name, offset)) => format!("{}+{}", name, offset),
+            None => String::new(),
+        }
+    }
+
+    /// Name of the frame of a function or block starting at or before `addr`.
+    fn frame(&self, addr: Word) -> String {
+        match self.label(addr) {
+            Some((name, _)) => name.to_string(),
+            None => addr.to_string(),
+        }
+    }
+
+    /// Name of the outermost frame: the label at the entry point, `main` if there is none.
+    fn root(&self) -> String {
+        match self.label(0) {
+            Some((name, 0)) => name.to_string(),
+            _ => "main".to_string(),
+        }
+    }
+
+    /// Make the frames match the call stack of `bm`.
+    fn sync(&mut self, bm: &BM) {
+        if self.counts.len() < bm.program.len() {
+            self.counts.resize(bm.program.len(), 0);
+            self.taken.resize(bm.program.len(), 0);
+        }
+        // Functions are entered by the call preceding the address they return to
+        self.frames = bm
+            .call_stack
+            .iter()
+            .map(|ret| {
+                usize::try_from(ret - 1)
+                    .ok()
+                    .and_then(|call| bm.program.get(call))
+                    .and_then(Instruction::target)
+                    .unwrap_or(ret - 1)
+            })
+            .collect();
+        self.intern();
+    }
+
+    /// Look up the id of the current call stack.
+    fn intern(&mut self) {
+        self.stack = match self.stack_ids.get(&self.frames) {
+            Some(id) => *id,
+            None => {
+                let id = self.stacks.len();
+                self.stacks.push(self.frames.clone());
+                self.stack_ids.insert(self.frames.clone(), id);
+                id
+            }
+        };
+    }
+
+    /// Record the successful execution of the instruction at `addr`.
+    fn record(&mut self, bm: &BM, addr: Word, taken: bool) {
+        let i = addr as usize;
+        self.counts[i] += 1;
+        self.taken[i] += taken as usize;
+        *self.samples.entry((self.stack, addr)).or_default() += 1;
+        if bm.call_stack.len() != self.frames.len() {
+            if bm.call_stack.len() > self.frames.len() {
+                self.frames.push(bm.ip);
+            } else {
+                self.frames.pop();
+            }
+            self.intern();
+        }
+    }
+
+    /// Write the hot spots, the executions per instruction and the branches taken.
+    pub fn write_table<W>(&self, program: &[Instruction], w: &mut W) -> std::io::Result<()>
+    where
+        W: Write,
+    {
+        let total = self.executed();
+        let percent = |count: usize| 100.0 * count as f64 / total.max(1) as f64;
+        writeln!(w, "{} instructions executed", total)?;
+
+        writeln!(w, "\nhot spots:")?;
+        writeln!(
+            w,
+            "{:>12} {:>7} {:>6}  {:<24} label",
+            "count", "%", "addr", "instruction"
+        )?;
+        let mut hot: Vec<usize> = (0..self.counts.len())
+            .filter(|addr| self.counts[*addr] > 0)
+            .collect();
+        hot.sort_by_key(|addr| std::cmp::Reverse(self.counts[*addr]));
+        for addr in hot.into_iter().take(HOT_SPOTS) {
+            let count = self.counts[addr];
+            writeln!(
+                w,
+                "{:>12} {:>6.2}% {:>6}  {:<24} {}",
+                count,
+                percent(count),
+                addr,
+                program.get(addr).map_or(String::new(), |i| i.to_string()),
+                self.describe(addr as Word)
+            )?;
+        }
+
+        writeln!(w, "\ninstructions:")?;
+        writeln!(w, "{:>12} {:>7}  instruction", "count", "%")?;
+        let mut kinds: Vec<_> = self.counts_by_instruction(program).into_iter().collect();
+        kinds.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        for (kind, count) in kinds {
+            writeln!(w, "{:>12} {:>6.2}%  {}", count, percent(count), kind)?;
+        }
+
+        let branches: Vec<usize> = (0..self.counts.len())
+            .filter(|addr| {
+                self.counts[*addr] > 0
+                    && matches!(
+                        program.get(*addr),
+                        Some(Instruction::JumpIf(_) | Instruction::JumpZero(_))
+                    )
+            })
+            .collect();
+        if !branches.is_empty() {
+            writeln!(w, "\nbranches:")?;
+            writeln!(
+                w,
+                "{:>6}  {:<24} {:>12} {:>12} {:>7}  label",
+                "addr", "instruction", "taken", "not taken", "taken%"
+            )?;
+            for addr in branches {
+                let (taken, not_taken) = self.branch(addr as Word);
+                writeln!(
+                    w,
+                    "{:>6}  {:<24} {:>12} {:>12} {:>6.2}%  {}",
+                    addr,
+                    program[addr].to_string(),
+                    taken,
+                    not_taken,
+                    100.0 * taken as f64 / (taken + not_taken) as f64,
+                    self.describe(addr as Word)
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write one line per call stack, from the outermost function to the block holding
+    /// the instruction, followed by the number of instructions executed in it.
+    pub fn write_folded<W>(&self, w: &mut W) -> std::io::Result<()>
+    where
+        W: Write,
+    {
+        let mut folded: BTreeMap<String, usize> = BTreeMap::new();
+        for ((stack, addr), count) in &self.samples {
+            let mut frames: Vec<String> = std::iter::once(self.root())
+                .chain(self.stacks[*stack].iter().map(|entry| self.frame(*entry)))
+                .collect();
+            // Code before the first label belongs to the frame it runs in
+            if let Some((block, _)) = self.label(*addr) {
+                if frames.last().map(String::as_str) != Some(block) {
+                    frames.push(block.to_string());
+                }
+            }
+            *folded.entry(frames.join(";")).or_default() += count;
+        }
+        for (frames, count) in folded {
+            writeln!(w, "{} {}", frames, count)?;
+        }
+        Ok(())
+    }
+
+    /// Write the profile in the given format.
+    pub fn write<W>(
+        &self,
+        program: &[Instruction],
+        format: ProfileFormat,
+        w: &mut W,
+    ) -> std::io::Result<()>
+    where
+        W: Write,
+    {
+        match format {
+            ProfileFormat::Table => self.write_table(program, w),
+            ProfileFormat::Folded => self.write_folded(w),
+        }
+    }
+}
+
+/// Name of the instruction without its operand.
+fn mnemonic(inst: &Instruction) -> String {
+    let text = inst.to_string();
+    text.split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+impl BM {
+    /// Same as `BM::execute_program` but counts every executed instruction in `profiler`.
+    /// ```
+    /// use bm::{format::Symbol, profiler::Profiler, Instruction, BM};
+    /// let mut bm: BM = Default::default();
+    /// bm.load_program_from_memory(&[
+    ///     Instruction::Push(3),
+    ///     Instruction::Push(1),
+    ///     Instruction::Minus,
+    ///     Instruction::Dup(0),
+    ///     Instruction::JumpIf(Some(1)),
+    ///     Instruction::Halt,
+    /// ])
+    /// .unwrap();
+    /// let symbols = vec![Symbol { name: "loop".to_string(), addr: 1 }];
+    /// let mut profiler = Profiler::new(symbols);
+    /// bm.execute_program_profiled(None, &mut profiler).unwrap();
+    /// assert_eq!(profiler.count(2), 3);
+    /// assert_eq!(profiler.branch(4), (2, 1));
+    /// assert_eq!(profiler.counts_by_instruction(bm.program())["minus"], 3);
+    ///
+    /// let mut folded = Vec::new();
+    /// profiler.write_folded(&mut folded).unwrap();
+    /// assert_eq!(String::from_utf8(folded).unwrap(), "main 1\nmain;loop 13\n");
+    /// ```
+    pub fn execute_program_profiled(
+        &mut self,
+        limit: Option<usize>,
+        profiler: &mut Profiler,
+    ) -> Result<(), Error> {
+        profiler.sync(self);
+        let mut i = 1;
+        while !self.is_halted() {
+            match limit {
+                Some(l) if l <= i => break,
+                _ => {}
+            }
+            let ip = self.ip;
+            let top = self.stack.last().copied();
+            let taken = match self.current_instruction() {
+                Some(Instruction::JumpIf(_)) => top.is_some_and(|top| top != 0),
+                Some(Instruction::JumpZero(_)) => top == Some(0),
+                _ => false,
+            };
+            self.execute_instruction()?;
+            profiler.record(self, ip, taken);
+            i += 1;
+        }
+        Ok(())
+    }
+}
@@ -1,8 +1,13 @@
 use super::Word;
-use crate::{Instruction, BM};
-use std::collections::HashMap;
-use std::io::BufRead;
+use crate::diagnostic::{closest_match, Diagnostic, Severity, Span};
+use crate::format::{BmFile, DebugEntry, Relocation, Symbol, BM_FLAG_OBJECT};
+use crate::instruction::{strip_comment, tokenize, InstructionParseErr, Token, MNEMONICS};
+use crate::optimizer::Optimized;
+use crate::preprocessor::{Expansion, MacroDef};
+use crate::{Error, Instruction, BM};
+use std::collections::{BTreeMap, HashMap};
 use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 
 /// Placeholder for Address/Label type operands
 /// It is used to convert all the occurances of labels being used to raw addresses.
@@ -11,100 +16,625 @@ pub struct UnresolvedLabel {
     pub addr: Word,
     /// Label
     pub label: String,
+    /// Where the label is used in the source
+    pub span: Span,
+}
+
+/// A label defined in the source.
+#[derive(Debug, Clone, Copy)]
+pub struct Label {
+    /// Address of the instruction the label points to
+    pub addr: Word,
+    /// Where the label is defined in the source
+    pub span: Span,
+}
+
+/// A source file taking part in assembling a program.
+#[derive(Debug, Clone)]
+pub struct SourceFile {
+    pub path: PathBuf,
+    pub source: String,
+    /// Where the file is included from, `None` for the file given to the assembler
+    pub included_from: Option<Span>,
 }
 
 /// Context for Basm Parser. Contains everything necessary for the parser to do the parsing.
+#[derive(Default)]
 pub struct BasmCtx {
     /// Table of the labels that are created in the code.
-    label_table: HashMap<String, Word>,
+    label_table: HashMap<String, Label>,
     /// i.e. all the occurances of a label being used.
     deferred_operand: Vec<UnresolvedLabel>,
-}
-
-impl Default for BasmCtx {
-    fn default() -> Self {
-        Self {
-            label_table: Default::default(),
-            deferred_operand: Default::default(),
-        }
-    }
+    /// Diagnostics reported so far.
+    diagnostics: Vec<Diagnostic>,
+    /// Line of the source currently being parsed (1-based).
+    pub(crate) line: usize,
+    /// Source file currently being parsed.
+    pub(crate) file: usize,
+    /// Every source file read so far, the first one being the file given to the assembler.
+    pub(crate) files: Vec<SourceFile>,
+    /// Directories searched for included files.
+    pub(crate) include_paths: Vec<PathBuf>,
+    /// Macro expansion the line currently being parsed comes from.
+    pub(crate) expansion: Option<usize>,
+    /// Constants defined with `%const`, along with where they are defined.
+    pub(crate) consts: HashMap<String, (String, Span)>,
+    /// Macros defined with `%macro`.
+    pub(crate) macros: HashMap<String, MacroDef>,
+    /// Every macro expansion performed so far.
+    pub(crate) expansions: Vec<Expansion>,
+    /// Source line of every instruction parsed so far.
+    debug_entries: Vec<DebugEntry>,
+    /// Whether the program is assembled into a relocatable object.
+    relocatable: bool,
+    /// Labels listed by `%export`.
+    pub(crate) exports: Vec<(String, Span)>,
+    /// Labels listed by `%import`.
+    pub(crate) imports: Vec<(String, Span)>,
+    /// Operands the linker has to patch, only recorded for relocatable objects.
+    relocations: Vec<Relocation>,
 }
 
 impl BasmCtx {
-    /// Insert a new label
-    pub fn insert_label(&mut self, label: String, addr: Word) {
-        self.label_table.insert(label, addr);
+    /// Insert a new label, reporting an error if it was already defined.
+    pub fn insert_label(&mut self, label: String, addr: Word, span: Span) {
+        if let Some(prev) = self.label_table.get(&label) {
+            let prev_span = prev.span;
+            self.report(Diagnostic::error(
+                span,
+                format!("label `{}` is defined more than once", label),
+            ));
+            self.report(Diagnostic::note(prev_span, "previously defined here"));
+            return;
+        }
+        self.label_table.insert(label, Label { addr, span });
     }
 
     /// Insert a new occurance of label being used.
-    pub fn add_deffered_opperand(&mut self, addr: Word, label: String) {
-        self.deferred_operand.push(UnresolvedLabel { addr, label });
+    pub fn add_deffered_opperand(&mut self, addr: Word, label: String, span: Span) {
+        self.deferred_operand
+            .push(UnresolvedLabel { addr, label, span });
     }
 
     /// Convert a label to it's raw address.
-    pub fn get_addr_for(&self, label: &str) -> Word {
-        self.label_table.get(label).unwrap().clone()
+    pub fn get_addr_for(&self, label: &str) -> Option<Word> {
+        self.label_table.get(label).map(|l| l.addr)
+    }
+
+    /// Search `dir` for files included with `%include` that are not found next to the
+    /// including file. Directories are searched in the order they are added.
+    pub fn add_include_path<P>(&mut self, dir: P)
+    where
+        P: Into<PathBuf>,
+    {
+        self.include_paths.push(dir.into());
+    }
+
+    /// Assemble into a relocatable object: labels listed by `%import` may be left
+    /// undefined and every address operand is recorded for the linker to patch.
+    pub fn set_relocatable(&mut self, relocatable: bool) {
+        self.relocatable = relocatable;
+    }
+
+    /// Whether the program is assembled into a relocatable object.
+    pub fn is_relocatable(&self) -> bool {
+        self.relocatable
+    }
+
+    /// Operands the linker has to patch.
+    pub fn relocations(&self) -> &[Relocation] {
+        &self.relocations
+    }
+
+    /// Move the labels, debug info and relocations along with the instructions of an
+    /// optimized program.
+    pub fn relocate(&mut self, optimized: &Optimized) {
+        for label in self.label_table.values_mut() {
+            label.addr = optimized.label_addr(label.addr);
+        }
+        self.debug_entries = self
+            .debug_entries
+            .iter()
+            .filter_map(|e| {
+                optimized
+                    .instruction_addr(e.addr)
+                    .map(|addr| DebugEntry { addr, ..*e })
+            })
+            .collect();
+        self.relocations = self
+            .relocations
+            .iter()
+            .filter_map(|r| {
+                optimized.instruction_addr(r.addr).map(|addr| Relocation {
+                    addr,
+                    symbol: r.symbol.clone(),
+                })
+            })
+            .collect();
+    }
+
+    /// Labels listed by `%export` along with their addresses.
+    pub fn exports(&self) -> Vec<Symbol> {
+        self.exports
+            .iter()
+            .filter_map(|(name, _)| {
+                let addr = self.get_addr_for(name)?;
+                let name = name.clone();
+                Some(Symbol { name, addr })
+            })
+            .collect()
+    }
+
+    /// Every source file read while assembling.
+    pub fn files(&self) -> &[SourceFile] {
+        &self.files
+    }
+
+    /// Record a diagnostic. Diagnostics pointing inside of a macro get a note for every
+    /// use site the macro was expanded from, and then one for every `%include` leading
+    /// to the file.
+    pub fn report(&mut self, diagnostic: Diagnostic) {
+        if diagnostic.severity == Severity::Note {
+            self.diagnostics.push(diagnostic);
+            return;
+        }
+        let mut span = diagnostic.span;
+        self.diagnostics.push(diagnostic);
+        while let Some(i) = span.expansion {
+            let e = &self.expansions[i];
+            let note = Diagnostic::note(e.use_span, format!("in expansion of macro `{}`", e.name));
+            span = e.use_span;
+            self.diagnostics.push(note);
+        }
+        while let Some(from) = self.files.get(span.file).and_then(|f| f.included_from) {
+            self.diagnostics
+                .push(Diagnostic::note(from, "in file included from here"));
+            span = from;
+        }
+    }
+
+    /// Render a diagnostic along with the source of the file it points into.
+    pub fn render_diagnostic<W>(&self, d: &Diagnostic, w: &mut W) -> std::io::Result<()>
+    where
+        W: Write,
+    {
+        match self.files.get(d.span.file) {
+            Some(f) => d.render(w, &f.path.display().to_string(), &f.source),
+            None => d.render(w, "<input>", ""),
+        }
+    }
+
+    /// All the diagnostics reported so far.
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    /// All the labels defined in the source, ordered by address.
+    pub fn symbols(&self) -> Vec<Symbol> {
+        let mut symbols: Vec<Symbol> = self
+            .label_table
+            .iter()
+            .map(|(name, l)| Symbol {
+                name: name.clone(),
+                addr: l.addr,
+            })
+            .collect();
+        symbols.sort_by(|a, b| a.addr.cmp(&b.addr).then_with(|| a.name.cmp(&b.name)));
+        symbols
+    }
+
+    /// Source line of every instruction parsed.
+    pub fn debug_entries(&self) -> &[DebugEntry] {
+        &self.debug_entries
+    }
+
+    /// Span on the line currently being parsed.
+    pub fn span(&self, col: usize, len: usize) -> Span {
+        Span {
+            expansion: self.expansion,
+            file: self.file,
+            ..Span::new(self.line, col, len)
+        }
+    }
+
+    /// Build the diagnostic for an instruction that could not be parsed.
+    fn parse_error(&self, line: &str, err: &InstructionParseErr) -> Diagnostic {
+        let mut tokens = tokenize(strip_comment(line)).peekable();
+        if tokens.peek().is_some_and(|t| t.text.ends_with(':')) {
+            tokens.next();
+        }
+        let name = tokens.next();
+        let operand = tokens.next();
+        let span_of = |t: Option<Token>| match t {
+            Some(t) => self.span(t.col, t.text.chars().count()),
+            None => self.span(1, 0),
+        };
+        match err {
+            InstructionParseErr::InvalidInstruction(_) => {
+                let text = name.map_or("", |t| t.text);
+                let d = Diagnostic::error(span_of(name), format!("unknown instruction `{}`", text));
+                match closest_match(text, MNEMONICS.iter().copied()) {
+                    Some(m) => d.with_hint(format!("did you mean `{}`?", m)),
+                    None => d,
+                }
+            }
+            InstructionParseErr::InvalidOperand(_) => Diagnostic::error(
+                span_of(operand),
+                format!("invalid operand `{}`", operand.map_or("", |t| t.text)),
+            )
+            .with_hint(match name.map_or("", |t| t.text) {
+                "push" => "`push` expects an integer or a float".to_string(),
+                n => format!("`{}` expects an integer", n),
+            }),
+            InstructionParseErr::OperandNotFound(_) => {
+                let (col, text) = name.map_or((1, ""), |t| (t.col, t.text));
+                Diagnostic::error(
+                    self.span(col + text.chars().count(), 1),
+                    format!("`{}` expects an operand", text),
+                )
+            }
+            InstructionParseErr::EmptyLine => Diagnostic::error(self.span(1, 0), err.to_string()),
+        }
+    }
+
+    /// Replace every deferred label operand with the address of its label.
+    /// Operands referring to imported labels are left for the linker.
+    fn resolve_labels(&mut self, program: &mut [Instruction]) {
+        let mut errors = Vec::new();
+        for (name, span) in &self.imports {
+            if !self.relocatable {
+                let d = Diagnostic::error(*span, "labels can only be imported into an object");
+                errors.push(d.with_hint("assemble the file as a relocatable object"));
+            } else if let Some(l) = self.label_table.get(name) {
+                errors.push(Diagnostic::error(
+                    l.span,
+                    format!("label `{}` is both defined and imported", name),
+                ));
+                errors.push(Diagnostic::note(*span, "imported here"));
+            }
+        }
+        for (name, span) in &self.exports {
+            if self.get_addr_for(name).is_none() {
+                errors.push(Diagnostic::error(
+                    *span,
+                    format!("exported label `{}` is not defined", name),
+                ));
+            }
+        }
+
+        let mut imported = Vec::new();
+        for ul in &self.deferred_operand {
+            let addr = match self.get_addr_for(&ul.label) {
+                Some(addr) => addr,
+                None if self.relocatable && self.imports.iter().any(|(i, _)| *i == ul.label) => {
+                    imported.push(Relocation {
+                        addr: ul.addr,
+                        symbol: Some(ul.label.clone()),
+                    });
+                    continue;
+                }
+                None => {
+                    let d = Diagnostic::error(ul.span, format!("undefined label `{}`", ul.label));
+                    let labels = self.label_table.keys().map(|l| l.as_str());
+                    errors.push(match closest_match(&ul.label, labels) {
+                        Some(l) => d.with_hint(format!("did you mean `{}`?", l)),
+                        None => d,
+                    });
+                    continue;
+                }
+            };
+            let inst = &mut program[ul.addr as usize];
+            match inst.target_mut() {
+                Some(target @ None) => *target = Some(addr),
+                _ => unreachable!("{} should not be marked unresolved", inst),
+            };
+        }
+        for e in errors {
+            self.report(e);
+        }
+
+        if self.relocatable {
+            // Every address left in the object moves along with it when linked
+            self.relocations = program
+                .iter()
+                .enumerate()
+                .filter(|(_, inst)| inst.target().is_some())
+                .map(|(addr, _)| Relocation {
+                    addr: addr as Word,
+                    symbol: None,
+                })
+                .chain(imported)
+                .collect();
+            self.relocations.sort_by_key(|r| r.addr);
+        }
     }
 }
 
 impl BM {
-    /// Serialize the program of the virtual machine into a Writer as binary.
-    pub fn serialize_program_into<W>(&self, w: W)
+    /// Serialize the program of the virtual machine into a Writer as a .bm file.
+    pub fn serialize_program_into<W>(&self, w: W) -> Result<(), Error>
     where
         W: Write,
     {
-        bincode::serialize_into(w, &self.program).expect("could not serialzie");
+        Ok(BmFile::new(self.program.clone()).write_to(w)?)
+    }
+
+    /// Serialize the program along with the symbols and debug info gathered while assembling it.
+    pub fn serialize_program_with_ctx_into<W>(&self, w: W, ctx: &BasmCtx) -> Result<(), Error>
+    where
+        W: Write,
+    {
+        let mut file = BmFile {
+            symbols: ctx.symbols(),
+            debug: ctx.debug_entries().to_vec(),
+            sources: ctx
+                .files()
+                .iter()
+                .map(|f| f.path.display().to_string())
+                .collect(),
+            ..BmFile::new(self.program.clone())
+        };
+        if ctx.is_relocatable() {
+            file.flags |= BM_FLAG_OBJECT;
+            file.exports = ctx.exports();
+            file.imports = ctx.imports.iter().map(|(name, _)| name.clone()).collect();
+            file.imports.sort();
+            file.imports.dedup();
+            file.relocations = ctx.relocations().to_vec();
+        }
+        Ok(file.write_to(w)?)
     }
 
-    /// Parse binary data from Reader and convert to List of Instructions
-    pub fn deserialize_program_from<R>(r: R) -> Vec<Instruction>
+    /// Parse a .bm file from Reader and convert to List of Instructions
+    pub fn deserialize_program_from<R>(r: R) -> Result<Vec<Instruction>, Error>
     where
         R: Read,
     {
-        bincode::deserialize_from::<R, Vec<Instruction>>(r).expect("could not deserialize program")
+        Ok(BmFile::read_from(r)?.code)
     }
 
-    /// Parse program from assembly
-    pub fn program_from_asm<R>(&mut self, source: R, ctx: &mut BasmCtx)
+    /// Parse program from assembly.
+    /// Every error in the source is reported in one pass; if there is any, the
+    /// returned `Error::Assemble` holds all the diagnostics collected in `ctx`.
+    /// Files included by the source are looked up relative to the current directory.
+    pub fn program_from_asm<R>(&mut self, mut source: R, ctx: &mut BasmCtx) -> Result<(), Error>
     where
         R: Read,
     {
+        let mut text = String::new();
+        let source = source.read_to_string(&mut text).map(|_| text);
+        self.assemble(PathBuf::from("<input>"), source, ctx)
+    }
+
+    /// Parse program from an assembly file, looking up the files it includes relative to it.
+    pub fn program_from_asm_file<P>(&mut self, path: P, ctx: &mut BasmCtx) -> Result<(), Error>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        self.assemble(path.to_path_buf(), std::fs::read_to_string(path), ctx)
+    }
+
+    fn assemble(
+        &mut self,
+        path: PathBuf,
+        source: std::io::Result<String>,
+        ctx: &mut BasmCtx,
+    ) -> Result<(), Error> {
         self.program.clear();
+        ctx.file = 0;
+        ctx.files = vec![SourceFile {
+            path,
+            source: String::new(),
+            included_from: None,
+        }];
+        match source {
+            Ok(source) => ctx.files[0].source = source,
+            Err(e) => {
+                ctx.report(Diagnostic::error(
+                    Span::default(),
+                    format!("could not read source: {}", e),
+                ));
+                return Err(Error::Assemble(ctx.diagnostics.clone()));
+            }
+        }
 
         // Parse Program from Assembly
-        for line in std::io::BufReader::new(source).lines() {
-            if let Ok(line) = line {
-                // Ignore lines starting with # as comments
-                if !line.trim().starts_with("#") {
-                    self.program
-                        .push(Instruction::from_asm(&line, &self, ctx).unwrap());
+        for source_line in ctx.preprocess(0) {
+            let line = source_line.text;
+            ctx.line = source_line.line;
+            ctx.file = source_line.file;
+            ctx.expansion = source_line.expansion;
+            match Instruction::from_asm(&line, self, ctx) {
+                Ok(inst) => {
+                    ctx.debug_entries.push(DebugEntry {
+                        addr: self.program.len() as Word,
+                        line: ctx.line,
+                        file: ctx.file,
+                    });
+                    self.program.push(inst);
+                }
+                // Blank, comment-only and label-only lines
+                Err(InstructionParseErr::EmptyLine) => {}
+                Err(e) => {
+                    let d = ctx.parse_error(&line, &e);
+                    ctx.report(d);
                 }
             }
         }
         self.program.push(Instruction::Halt); // Mark End Of Program
+        ctx.expansion = None;
+        ctx.file = 0;
 
-        for ul in &ctx.deferred_operand {
-            match &self.program[ul.addr as usize] {
-                Instruction::Jump(None) => {
-                    self.program[ul.addr as usize] =
-                        Instruction::Jump(Some(ctx.get_addr_for(ul.label.as_str())));
-                }
-                Instruction::JumpIf(None) => {
-                    self.program[ul.addr as usize] =
-                        Instruction::Jump(Some(ctx.get_addr_for(ul.label.as_str())));
-                }
-                i => panic!("{} should not be marked unresolved", &i),
-            };
+        ctx.resolve_labels(&mut self.program);
+
+        if ctx.diagnostics.iter().any(Diagnostic::is_error) {
+            return Err(Error::Assemble(ctx.diagnostics.clone()));
+        }
+        // Natives and addresses imported by objects are only known once the program is
+        // loaded or linked, but the size is already known
+        if let Err(e) = self.check_program_size(0) {
+            self.program.clear();
+            return Err(e.into());
         }
+        Ok(())
     }
 
+    /// Disassemble the program into basm, synthesizing a label for every jump target.
     pub fn program_to_asm<W>(&self, w: &mut W) -> std::io::Result<()>
     where
         W: Write,
     {
-        for inst in self.program.iter() {
-            w.write_fmt(format_args!("{}\n", inst))?;
+        self.program_to_asm_with_symbols(w, &[])
+    }
+
+    /// Disassemble the program into basm that `BM::program_from_asm` assembles back into
+    /// the same program. Labels are named after `symbols` when one points at the address,
+    /// every other jump target inside of the program gets a synthesized `addr_<n>` label.
+    pub fn program_to_asm_with_symbols<W>(
+        &self,
+        w: &mut W,
+        symbols: &[Symbol],
+    ) -> std::io::Result<()>
+    where
+        W: Write,
+    {
+        write_asm(&self.program, symbols, w)
+    }
+}
+
+/// Disassemble `program` like `BM::program_to_asm_with_symbols`, without loading it into
+/// a virtual machine.
+pub fn write_asm<W>(program: &[Instruction], symbols: &[Symbol], w: &mut W) -> std::io::Result<()>
+where
+    W: Write,
+{
+    // basm appends a halt to the program, so a label past the last instruction would
+    // point at it; such addresses are written as numbers instead
+    let in_range = |addr: Word| addr >= 0 && (addr as usize) < program.len();
+    let mut labels: BTreeMap<Word, Vec<String>> = BTreeMap::new();
+    for s in symbols.iter().filter(|s| in_range(s.addr)) {
+        labels.entry(s.addr).or_default().push(s.name.clone());
+    }
+    let targets: Vec<Word> = program
+        .iter()
+        .filter_map(Instruction::target)
+        .filter(|t| in_range(*t))
+        .collect();
+    for target in targets {
+        if labels.contains_key(&target) {
+            continue;
         }
-        Ok(())
+        let mut name = format!("addr_{}", target);
+        while symbols.iter().any(|s| s.name == name) {
+            name.push('_');
+        }
+        labels.insert(target, vec![name]);
+    }
+
+    // basm marks the end of the program with a halt, so it is not written out
+    let end = match program.last() {
+        Some(Instruction::Halt) => program.len() - 1,
+        _ => program.len(),
+    };
+    for addr in 0..program.len() {
+        for label in labels.get(&(addr as Word)).into_iter().flatten() {
+            writeln!(w, "{}:", label)?;
+        }
+        let inst = match program.get(addr) {
+            Some(inst) if addr < end => inst,
+            _ => continue,
+        };
+        let text = match inst.target().and_then(|t| labels.get(&t)) {
+            Some(names) => {
+                let mnemonic = inst.to_string();
+                let mnemonic = mnemonic.split_whitespace().next().unwrap_or_default();
+                format!("{} {}", mnemonic, names[0])
+            }
+            None => inst.to_string(),
+        };
+        writeln!(w, "    {:<24} # {}", text, addr)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Instruction::*;
+
+    /// Disassemble `program` and assemble the result back.
+    fn round_trip(program: &[Instruction], symbols: &[Symbol]) -> Vec<Instruction> {
+        let mut asm = Vec::new();
+        write_asm(program, symbols, &mut asm).unwrap();
+        let mut bm: BM = Default::default();
+        let mut ctx = BasmCtx::default();
+        bm.program_from_asm(asm.as_slice(), &mut ctx)
+            .unwrap_or_else(|e| panic!("{}\n{}", e, String::from_utf8_lossy(&asm)));
+        bm.program().to_vec()
+    }
+
+    #[test]
+    fn jumps_round_trip() {
+        let program = vec![
+            Push(3),
+            Dup(0),
+            JumpZero(Some(6)),
+            Push(1),
+            Minus,
+            Jump(Some(1)),
+            Halt,
+        ];
+        assert_eq!(round_trip(&program, &[]), program);
+    }
+
+    #[test]
+    fn jumps_past_the_end_round_trip() {
+        // The halt basm appends is at 3, a jump to 4 has to stay out of the program
+        let program = vec![Push(1), JumpIf(Some(4)), Jump(Some(3)), Halt];
+        assert_eq!(round_trip(&program, &[]), program);
+        let program = vec![Jump(Some(-1)), Halt];
+        assert_eq!(round_trip(&program, &[]), program);
+    }
+
+    #[test]
+    fn symbols_round_trip() {
+        let program = vec![Call(Some(2)), Halt, Push(1), Ret, Halt];
+        let symbols = [
+            Symbol {
+                name: "one".to_string(),
+                addr: 2,
+            },
+            // Nothing can be labelled past the halt basm appends
+            Symbol {
+                name: "end".to_string(),
+                addr: 5,
+            },
+        ];
+        let mut asm = Vec::new();
+        write_asm(&program, &symbols, &mut asm).unwrap();
+        let asm = String::from_utf8(asm).unwrap();
+        assert!(asm.contains("call one"), "{}", asm);
+        assert!(!asm.contains("end:"), "{}", asm);
+        assert_eq!(round_trip(&program, &symbols), program);
+    }
+
+    #[test]
+    fn programs_without_a_final_halt_get_one() {
+        let program = vec![Push(1), Jump(Some(0))];
+        let mut expected = program.clone();
+        expected.push(Halt);
+        assert_eq!(round_trip(&program, &[]), expected);
+    }
+
+    #[test]
+    fn macros_example_round_trips() {
+        let source = include_str!("../examples/macros.basm");
+        let mut bm: BM = Default::default();
+        let mut ctx = BasmCtx::default();
+        bm.program_from_asm(source.as_bytes(), &mut ctx).unwrap();
+        let program = bm.program().to_vec();
+        assert_eq!(round_trip(&program, &ctx.symbols()), program);
+        assert_eq!(round_trip(&program, &[]), program);
     }
 }
@@ -0,0 +1,144 @@
+use std::io::{Read, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::format::{BmFile, FormatErr, SectionKind};
+use crate::{BMConfig, Error, Instruction, LoadErr, Word, BM};
+
+/// Execution state of a virtual machine besides its program and memory.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MachineState {
+    pub stack: Vec<Word>,
+    pub call_stack: Vec<Word>,
+    pub ip: Word,
+    pub halt: bool,
+    pub config: BMConfig,
+    /// Instructions executed so far, counted against the instruction budget
+    pub executed: usize,
+}
+
+/// Everything needed to resume a virtual machine where it stopped. Native functions
+/// and streams belong to the host and are not part of it.
+///
+/// On disk a snapshot is a .bm file with a state section, the memory of the machine
+/// being stored in the data section.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub program: Vec<Instruction>,
+    pub memory: Vec<u8>,
+    pub state: MachineState,
+}
+
+impl Snapshot {
+    pub fn write_to<W>(&self, w: W) -> Result<(), Error>
+    where
+        W: Write,
+    {
+        let file = BmFile {
+            data: self.memory.clone(),
+            state: Some(self.state.clone()),
+            ..BmFile::new(self.program.clone())
+        };
+        Ok(file.write_to(w)?)
+    }
+
+    pub fn read_from<R>(r: R) -> Result<Self, Error>
+    where
+        R: Read,
+    {
+        Self::from_file(BmFile::read_from(r)?)
+    }
+
+    /// Extract the snapshot held by a .bm file.
+    pub fn from_file(file: BmFile) -> Result<Self, Error> {
+        let state = file
+            .state
+            .ok_or(FormatErr::MissingSection(SectionKind::State))?;
+        Ok(Self {
+            program: file.code,
+            memory: file.data,
+            state,
+        })
+    }
+}
+
+impl BM {
+    /// Capture the full state of the virtual machine.
+    /// ```
+    /// use bm::{snapshot::Snapshot, Instruction, BM};
+    /// let mut bm: BM = Default::default();
+    /// bm.load_program_from_memory(&[
+    ///     Instruction::Push(1),
+    ///     Instruction::Push(2),
+    ///     Instruction::Plus,
+    ///     Instruction::Halt,
+    /// ])
+    /// .unwrap();
+    /// bm.execute_instruction().unwrap();
+    /// bm.execute_instruction().unwrap();
+    ///
+    /// let mut file = Vec::new();
+    /// bm.snapshot().write_to(&mut file).unwrap();
+    /// let mut resumed: BM = Default::default();
+    /// resumed.restore(Snapshot::read_from(&file[..]).unwrap()).unwrap();
+    /// resumed.execute_program(None).unwrap();
+    /// assert_eq!(resumed.stack(), &[3]);
+    /// ```
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            program: self.program.clone(),
+            memory: self.memory.clone(),
+            state: MachineState {
+                stack: self.stack.clone(),
+                call_stack: self.call_stack.clone(),
+                ip: self.ip,
+                halt: self.halt,
+                config: self.config,
+                executed: self.executed,
+            },
+        }
+    }
+
+    /// Replace the state of the virtual machine by the one captured in `snapshot`,
+    /// including its limits. Native functions the program calls have to be registered.
+    pub fn restore(&mut self, snapshot: Snapshot) -> Result<(), Error> {
+        let Snapshot {
+            program,
+            memory,
+            state,
+        } = snapshot;
+        let config = BMConfig {
+            memory_size: memory.len(),
+            ..state.config
+        };
+        let inconsistent = |e: &str| Error::Load(LoadErr::InconsistentSnapshot(e.to_string()));
+        if state.stack.len() > config.stack_capacity {
+            return Err(inconsistent("stack exceeds its capacity"));
+        }
+        if state.call_stack.len() > config.call_depth {
+            return Err(inconsistent("call stack exceeds its capacity"));
+        }
+
+        let mut restored = BM {
+            stack: state.stack,
+            call_stack: state.call_stack,
+            memory,
+            natives: std::mem::take(&mut self.natives),
+            io: std::mem::take(&mut self.io),
+            halt: state.halt,
+            program: Vec::new(),
+            ip: state.ip,
+            config,
+            executed: state.executed,
+        };
+        let loaded = restored.load_program_from_memory(&program);
+        // The host functions and streams stay with this machine whatever happens
+        self.natives = std::mem::take(&mut restored.natives);
+        self.io = std::mem::take(&mut restored.io);
+        loaded?;
+        restored.natives = std::mem::take(&mut self.natives);
+        restored.io = std::mem::take(&mut self.io);
+        *self = restored;
+        Ok(())
+    }
+}
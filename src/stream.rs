@@ -0,0 +1,212 @@
+use std::cell::RefCell;
+use std::io::{BufRead, Read, Write};
+use std::rc::Rc;
+
+use crate::interpreter::InterpreterErr;
+use crate::Word;
+
+/// Streams used by the I/O instructions of the virtual machine.
+pub struct HostIo {
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+}
+
+impl Default for HostIo {
+    /// Reads from stdin and writes to stdout.
+    fn default() -> Self {
+        Self {
+            input: Box::new(std::io::BufReader::new(std::io::stdin())),
+            output: Box::new(std::io::stdout()),
+        }
+    }
+}
+
+impl std::fmt::Debug for HostIo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HostIo").finish_non_exhaustive()
+    }
+}
+
+fn io_err(e: std::io::Error) -> InterpreterErr {
+    InterpreterErr::Io(e.to_string())
+}
+
+impl HostIo {
+    pub fn set_input<R>(&mut self, input: R)
+    where
+        R: Read + 'static,
+    {
+        self.input = Box::new(std::io::BufReader::new(input));
+    }
+
+    pub fn set_output<W>(&mut self, output: W)
+    where
+        W: Write + 'static,
+    {
+        self.output = Box::new(output);
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.output.flush()
+    }
+
+    fn peek_byte(&mut self) -> Result<Option<u8>, InterpreterErr> {
+        let buf = self.input.fill_buf().map_err(io_err)?;
+        Ok(buf.first().copied())
+    }
+
+    /// Read a single UTF-8 encoded character, `None` at the end of the input.
+    pub fn read_char(&mut self) -> Result<Option<char>, InterpreterErr> {
+        // Anything waiting in the output is probably a prompt for this input
+        self.output.flush().map_err(io_err)?;
+        let first = match self.peek_byte()? {
+            Some(b) => b,
+            None => return Ok(None),
+        };
+        let len = match first {
+            0x00..=0x7F => 1,
+            0xC0..=0xDF => 2,
+            0xE0..=0xEF => 3,
+            0xF0..=0xF7 => 4,
+            _ => 1,
+        };
+        let mut bytes = [0; 4];
+        let mut read = 0;
+        while read < len {
+            match self.input.read(&mut bytes[read..len]).map_err(io_err)? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        std::str::from_utf8(&bytes[..read])
+            .ok()
+            .and_then(|s| s.chars().next())
+            .map(Some)
+            .ok_or_else(|| InterpreterErr::InvalidInput("invalid UTF-8 sequence".to_string()))
+    }
+
+    /// Read a decimal integer, skipping the whitespace before it.
+    pub fn read_int(&mut self) -> Result<Word, InterpreterErr> {
+        self.output.flush().map_err(io_err)?;
+        while self.peek_byte()?.is_some_and(|b| b.is_ascii_whitespace()) {
+            self.input.consume(1);
+        }
+        let mut text = String::new();
+        while let Some(b) = self.peek_byte()? {
+            let sign = text.is_empty() && (b == b'-' || b == b'+');
+            if !sign && !b.is_ascii_digit() {
+                break;
+            }
+            text.push(b as char);
+            self.input.consume(1);
+        }
+        if text.is_empty() {
+            return Err(InterpreterErr::InvalidInput(match self.peek_byte()? {
+                Some(_) => "expected an integer".to_string(),
+                None => "unexpected end of input".to_string(),
+            }));
+        }
+        text.parse()
+            .map_err(|_| InterpreterErr::InvalidInput(format!("invalid integer `{}`", text)))
+    }
+
+    pub fn write_char(&mut self, c: char) -> Result<(), InterpreterErr> {
+        let mut buf = [0; 4];
+        self.output
+            .write_all(c.encode_utf8(&mut buf).as_bytes())
+            .map_err(io_err)
+    }
+
+    pub fn write_fmt(&mut self, args: std::fmt::Arguments) -> Result<(), InterpreterErr> {
+        self.output.write_fmt(args).map_err(io_err)
+    }
+}
+
+/// Input stream shared between the virtual machine and its host, e.g. the debugger
+/// reading commands from the stdin the program reads from. A read returns at most one
+/// line, so a reader buffering on top of it never takes input meant for the other one.
+/// ```
+/// use std::io::BufRead;
+/// use bm::{stream::SharedInput, BM, Instruction};
+/// let input = SharedInput::new(&b"41\nnext\n"[..]);
+/// let mut bm: BM = Default::default();
+/// bm.set_input(input.clone());
+/// bm.load_program_from_memory(&[Instruction::ReadInt, Instruction::Halt])
+///     .unwrap();
+/// bm.execute_program(None).unwrap();
+/// assert_eq!(bm.stack(), &[41]);
+/// let mut line = String::new();
+/// std::io::BufReader::new(input).read_line(&mut line).unwrap();
+/// assert_eq!(line, "next\n");
+/// ```
+#[derive(Clone)]
+pub struct SharedInput(Rc<RefCell<Box<dyn BufRead>>>);
+
+impl SharedInput {
+    pub fn new<R>(input: R) -> Self
+    where
+        R: BufRead + 'static,
+    {
+        Self(Rc::new(RefCell::new(Box::new(input))))
+    }
+}
+
+impl std::fmt::Debug for SharedInput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SharedInput").finish_non_exhaustive()
+    }
+}
+
+impl Read for SharedInput {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut input = self.0.borrow_mut();
+        let available = input.fill_buf()?;
+        let line = available
+            .iter()
+            .position(|b| *b == b'\n')
+            .map_or(available.len(), |end| end + 1);
+        let n = line.min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        input.consume(n);
+        Ok(n)
+    }
+}
+
+/// In-memory output stream that can still be read after handing it to the virtual machine.
+/// ```
+/// use bm::{stream::SharedBuffer, BM, Instruction};
+/// let out = SharedBuffer::default();
+/// let mut bm: BM = Default::default();
+/// bm.set_input(&b"41"[..]);
+/// bm.set_output(out.clone());
+/// bm.load_program_from_memory(&[
+///     Instruction::ReadInt,
+///     Instruction::Push(1),
+///     Instruction::Plus,
+///     Instruction::WriteInt,
+///     Instruction::Halt,
+/// ])
+/// .unwrap();
+/// bm.execute_program(None).unwrap();
+/// assert_eq!(out.contents(), b"42");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    /// Everything written so far.
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.borrow().clone()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
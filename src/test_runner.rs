@@ -0,0 +1,550 @@
+//! Runs basm programs as tests. Expectations are written as comments in the tested file:
+//!
+//! ```text
+//! # expect-stack: 1 2 3       final stack, bottom first
+//! # expect-stdout: hello      a line of output, may be repeated
+//! # expect-error: StackUnderflow
+//! # stdin: 42                 a line of input, may be repeated
+//! ```
+//!
+//! A test passes if the program halts, or fails with the expected error, and every other
+//! expectation holds.
+
+use std::fmt::Display;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+use crate::serialize_deserialize::BasmCtx;
+use crate::stream::SharedBuffer;
+use crate::{f64_to_word, write_stack, BMConfig, Error, Word, WordFormat, BM};
+
+/// Number of instructions a test may execute unless configured otherwise.
+pub const DEFAULT_TEST_BUDGET: usize = 1_000_000;
+
+/// What a test file expects from its program.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Expectations {
+    pub stack: Option<Vec<Word>>,
+    /// Lines of output, compared with the output without its trailing newline
+    pub stdout: Option<Vec<String>>,
+    /// Name of the runtime error, without the `Err::` prefix
+    pub error: Option<String>,
+    /// Lines fed to the input of the program
+    pub stdin: Vec<String>,
+}
+
+impl Expectations {
+    /// Collect the directives of a source. Comments that are not directives are ignored.
+    pub fn parse(source: &str) -> Result<Self, Failure> {
+        let mut expectations = Self::default();
+        for (i, line) in source.lines().enumerate() {
+            let directive = |message: String| Failure::Directive {
+                line: i + 1,
+                message,
+            };
+            let comment = match line.trim_start().strip_prefix('#') {
+                Some(comment) => comment.trim_start(),
+                None => continue,
+            };
+            let (name, value) = match comment.split_once(':') {
+                Some((name, value)) => (name, value.strip_prefix(' ').unwrap_or(value)),
+                None => continue,
+            };
+            match name {
+                "expect-stack" if expectations.stack.is_some() => {
+                    return Err(directive("expect-stack is given twice".to_string()))
+                }
+                "expect-stack" => {
+                    let stack = value
+                        .split_whitespace()
+                        .map(|w| match (w.parse::<Word>(), w.parse::<f64>()) {
+                            (Ok(w), _) => Ok(w),
+                            (_, Ok(f)) => Ok(f64_to_word(f)),
+                            _ => Err(directive(format!("`{}` is not a word", w))),
+                        })
+                        .collect::<Result<_, _>>()?;
+                    expectations.stack = Some(stack);
+                }
+                "expect-stdout" => expectations
+                    .stdout
+                    .get_or_insert_with(Vec::new)
+                    .push(value.to_string()),
+                "expect-error" if expectations.error.is_some() => {
+                    return Err(directive("expect-error is given twice".to_string()))
+                }
+                "expect-error" => {
+                    let error = value.trim();
+                    let error = error.strip_prefix("Err::").unwrap_or(error);
+                    if error.is_empty() {
+                        return Err(directive("expect-error needs an error name".to_string()));
+                    }
+                    expectations.error = Some(error.to_string());
+                }
+                "stdin" => expectations.stdin.push(value.to_string()),
+                name if name.starts_with("expect-") => {
+                    return Err(directive(format!("unknown directive `{}`", name)))
+                }
+                _ => {}
+            }
+        }
+        Ok(expectations)
+    }
+
+    /// Checks if the source has no directive at all, in which case it is not a test.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// A reason for a test to fail.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Failure {
+    /// A directive is malformed
+    Directive {
+        line: usize,
+        message: String,
+    },
+    /// The program could not be assembled, holds the rendered diagnostics
+    Assemble(String),
+    /// The program could not be read or loaded
+    Load(String),
+    Stack {
+        expected: Vec<Word>,
+        actual: Vec<Word>,
+    },
+    Stdout {
+        expected: String,
+        actual: String,
+    },
+    /// The program did not fail the way it was expected to, errors are rendered as
+    /// displayed by the interpreter
+    Error {
+        expected: Option<String>,
+        actual: Option<String>,
+    },
+}
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Failure::Directive { line, message } => write!(f, "{}: {}", line, message),
+            Failure::Assemble(diagnostics) => {
+                write!(f, "could not assemble:\n{}", diagnostics.trim_end())
+            }
+            Failure::Load(e) => write!(f, "could not load program: {}", e),
+            Failure::Stack { expected, actual } => {
+                writeln!(f, "final stack differs (-expected +actual):")?;
+                for line in diff(&render_stack(expected), &render_stack(actual)) {
+                    writeln!(f, "{}", line)?;
+                }
+                Ok(())
+            }
+            Failure::Stdout { expected, actual } => write!(
+                f,
+                "stdout differs:\n  expected: {:?}\n  actual:   {:?}",
+                expected, actual
+            ),
+            Failure::Error { expected, actual } => match (expected, actual) {
+                (Some(e), None) => write!(f, "expected Err::{}, the program halted", e),
+                (Some(e), Some(a)) => {
+                    write!(f, "expected Err::{}, the program failed with {}", e, a)
+                }
+                (None, Some(a)) => write!(f, "the program failed with {}", a),
+                (None, None) => write!(f, "the program did not fail as expected"),
+            },
+        }
+    }
+}
+
+/// The stack as dumped by `BM::dump_stack`, one line per element.
+fn render_stack(stack: &[Word]) -> Vec<String> {
+    let mut out = Vec::new();
+    // Writing into a Vec can not fail
+    let _ = write_stack(&mut out, stack, WordFormat::Int);
+    String::from_utf8_lossy(&out)
+        .lines()
+        .map(str::to_string)
+        .collect()
+}
+
+/// Line by line diff, each line prefixed by ` `, `-` or `+`.
+fn diff(expected: &[String], actual: &[String]) -> Vec<String> {
+    // Length of the longest common subsequence of the suffixes
+    let mut lcs = vec![vec![0; actual.len() + 1]; expected.len() + 1];
+    for i in (0..expected.len()).rev() {
+        for j in (0..actual.len()).rev() {
+            lcs[i][j] = if expected[i] == actual[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut lines = Vec::new();
+    while i < expected.len() || j < actual.len() {
+        if i < expected.len() && j < actual.len() && expected[i] == actual[j] {
+            lines.push(format!(" {}", expected[i]));
+            i += 1;
+            j += 1;
+        } else if j == actual.len() || (i < expected.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            lines.push(format!("-{}", expected[i]));
+            i += 1;
+        } else {
+            lines.push(format!("+{}", actual[j]));
+            j += 1;
+        }
+    }
+    lines
+}
+
+/// Outcome of a single test file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestReport {
+    pub path: PathBuf,
+    /// Empty if the test passed
+    pub failures: Vec<Failure>,
+}
+
+impl TestReport {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// Runs tests, each on a fresh virtual machine.
+/// ```
+/// use bm::test_runner::TestRunner;
+/// let runner = TestRunner::new();
+/// let source = "# expect-stack: 5\npush 2\npush 3\nplus\n";
+/// assert!(runner.run_source(source).is_empty());
+/// let failures = runner.run_source("# expect-error: StackUnderflow\nplus\nhalt\n");
+/// assert!(failures.is_empty());
+/// let failures = runner.run_source("# expect-stack: 1\npush 2\nhalt\n");
+/// assert_eq!(failures.len(), 1);
+/// ```
+#[derive(Debug, Clone)]
+pub struct TestRunner {
+    config: BMConfig,
+    include_paths: Vec<PathBuf>,
+}
+
+impl Default for TestRunner {
+    fn default() -> Self {
+        Self::with_config(BMConfig {
+            instruction_budget: Some(DEFAULT_TEST_BUDGET),
+            ..Default::default()
+        })
+    }
+}
+
+impl TestRunner {
+    /// A runner with the default limits and an instruction budget of `DEFAULT_TEST_BUDGET`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A runner whose virtual machines have the limits of `config`.
+    pub fn with_config(config: BMConfig) -> Self {
+        Self {
+            config,
+            include_paths: Vec::new(),
+        }
+    }
+
+    /// Add a directory searched for the files included by the tests.
+    pub fn add_include_path<P>(&mut self, dir: P)
+    where
+        P: Into<PathBuf>,
+    {
+        self.include_paths.push(dir.into());
+    }
+
+    pub fn run_file<P>(&self, path: P) -> TestReport
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let failures = match std::fs::read_to_string(path) {
+            Ok(source) => self.run(&source, |bm, ctx| bm.program_from_asm_file(path, ctx)),
+            Err(e) => vec![Failure::Load(format!(
+                "could not read {}: {}",
+                path.display(),
+                e
+            ))],
+        };
+        TestReport {
+            path: path.to_path_buf(),
+            failures,
+        }
+    }
+
+    /// Run the test held by `source`, returning why it failed.
+    pub fn run_source(&self, source: &str) -> Vec<Failure> {
+        self.run(source, |bm, ctx| {
+            bm.program_from_asm(source.as_bytes(), ctx)
+        })
+    }
+
+    fn run<F>(&self, source: &str, assemble: F) -> Vec<Failure>
+    where
+        F: FnOnce(&mut BM, &mut BasmCtx) -> Result<(), Error>,
+    {
+        let expectations = match Expectations::parse(source) {
+            Ok(expectations) => expectations,
+            Err(failure) => return vec![failure],
+        };
+        let mut bm = BM::with_config(self.config);
+        let mut ctx = BasmCtx::default();
+        for dir in &self.include_paths {
+            ctx.add_include_path(dir.clone());
+        }
+        match assemble(&mut bm, &mut ctx) {
+            Ok(()) => {}
+            Err(Error::Assemble(diagnostics)) => {
+                let mut out = Vec::new();
+                for d in &diagnostics {
+                    let _ = ctx.render_diagnostic(d, &mut out);
+                }
+                return vec![Failure::Assemble(
+                    String::from_utf8_lossy(&out).into_owned(),
+                )];
+            }
+            Err(e) => return vec![Failure::Load(e.to_string())],
+        }
+
+        let mut input = String::new();
+        for line in &expectations.stdin {
+            input.push_str(line);
+            input.push('\n');
+        }
+        let output = SharedBuffer::default();
+        bm.set_input(Cursor::new(input.into_bytes()));
+        bm.set_output(output.clone());
+        let result = bm.execute_program(None);
+        let _ = bm.flush_output();
+
+        let mut failures = Vec::new();
+        let actual = result.err().map(|e| e.to_string());
+        let error_matches = match (&expectations.error, &actual) {
+            (Some(expected), Some(actual)) => {
+                let actual = actual.strip_prefix("Err::").unwrap_or(actual);
+                // Errors with operands match by their name alone
+                actual == expected || actual.split('(').next() == Some(expected)
+            }
+            (None, None) => true,
+            _ => false,
+        };
+        if !error_matches {
+            failures.push(Failure::Error {
+                expected: expectations.error,
+                actual,
+            });
+        }
+        if let Some(expected) = expectations.stack {
+            if expected != bm.stack() {
+                failures.push(Failure::Stack {
+                    expected,
+                    actual: bm.stack().to_vec(),
+                });
+            }
+        }
+        if let Some(lines) = expectations.stdout {
+            let expected = lines.join("\n");
+            let actual = String::from_utf8_lossy(&output.contents()).into_owned();
+            if actual.strip_suffix('\n').unwrap_or(&actual) != expected {
+                failures.push(Failure::Stdout { expected, actual });
+            }
+        }
+        failures
+    }
+}
+
+/// Find the tests under `path`. A file is always a test, a directory is searched
+/// recursively for `.basm` files with at least one directive, so files that are only
+/// included by tests are left out.
+pub fn discover<P>(path: P) -> std::io::Result<Vec<PathBuf>>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut entries = std::fs::read_dir(path)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort();
+    let mut tests = Vec::new();
+    for entry in entries {
+        if entry.is_dir() {
+            tests.extend(discover(&entry)?);
+        } else if entry.extension().is_some_and(|ext| ext == "basm") {
+            let source = std::fs::read_to_string(&entry)?;
+            // Malformed directives are reported when the test runs
+            if Expectations::parse(&source).map_or(true, |e| !e.is_empty()) {
+                tests.push(entry);
+            }
+        }
+    }
+    Ok(tests)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn directive(line: usize, message: &str) -> Failure {
+        Failure::Directive {
+            line,
+            message: message.to_string(),
+        }
+    }
+
+    fn lines(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn directives() {
+        let source = "# a comment: not a directive\n\
+            # expect-stack: 1 -2 0.5\n\
+            #expect-stdout: hello\n\
+            # expect-stdout:\n\
+            # expect-error: Err::StackUnderflow\n\
+            # stdin:  indented\n\
+            push 1 # expect-stack: comments after code are ignored\n";
+        assert_eq!(
+            Expectations::parse(source).unwrap(),
+            Expectations {
+                stack: Some(vec![1, -2, f64_to_word(0.5)]),
+                stdout: Some(lines(&["hello", ""])),
+                error: Some("StackUnderflow".to_string()),
+                stdin: lines(&[" indented"]),
+            }
+        );
+        assert!(Expectations::parse("push 1\n# just a comment\n")
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn malformed_directives() {
+        let cases = [
+            (
+                "# expect-stack: 1 two\n",
+                directive(1, "`two` is not a word"),
+            ),
+            (
+                "push 1\n# expect-error:  \n",
+                directive(2, "expect-error needs an error name"),
+            ),
+            (
+                "# expect-error: Err::\n",
+                directive(1, "expect-error needs an error name"),
+            ),
+            (
+                "# expect-stdin: 1\n",
+                directive(1, "unknown directive `expect-stdin`"),
+            ),
+        ];
+        for (source, failure) in cases {
+            assert_eq!(Expectations::parse(source), Err(failure), "{:?}", source);
+        }
+    }
+
+    #[test]
+    fn duplicate_directives() {
+        assert_eq!(
+            Expectations::parse("# expect-stack: 1\n# expect-stack: 2\n"),
+            Err(directive(2, "expect-stack is given twice"))
+        );
+        assert_eq!(
+            Expectations::parse("# expect-error: A\n\n# expect-error: A\n"),
+            Err(directive(3, "expect-error is given twice"))
+        );
+        // Output and input are given line by line
+        let e =
+            Expectations::parse("# stdin: 1\n# stdin: 2\n# expect-stdout: a\n# expect-stdout: b\n")
+                .unwrap();
+        assert_eq!(e.stdin, lines(&["1", "2"]));
+        assert_eq!(e.stdout, Some(lines(&["a", "b"])));
+    }
+
+    #[test]
+    fn malformed_directives_fail_the_test() {
+        let runner = TestRunner::new();
+        assert_eq!(
+            runner.run_source("# expect-stack: x\npush 1\n"),
+            vec![directive(1, "`x` is not a word")]
+        );
+    }
+
+    #[test]
+    fn diff_with_missing_and_extra_lines() {
+        assert_eq!(
+            diff(&lines(&["1", "2", "3", "4"]), &lines(&["1", "3", "4", "5"])),
+            lines(&[" 1", "-2", " 3", " 4", "+5"])
+        );
+        assert_eq!(
+            diff(&lines(&["1", "2"]), &lines(&["3"])),
+            lines(&["-1", "-2", "+3"])
+        );
+        assert_eq!(diff(&lines(&[]), &lines(&["1"])), lines(&["+1"]));
+        assert_eq!(diff(&lines(&["1"]), &lines(&[])), lines(&["-1"]));
+        assert_eq!(diff(&lines(&["1"]), &lines(&["1"])), lines(&[" 1"]));
+    }
+
+    #[test]
+    fn stack_failure_shows_the_diff() {
+        let failures =
+            TestRunner::new().run_source("# expect-stack: 1 2\npush 1\npush 3\npush 4\n");
+        assert_eq!(
+            failures,
+            vec![Failure::Stack {
+                expected: vec![1, 2],
+                actual: vec![1, 3, 4],
+            }]
+        );
+        let rendered = failures[0].to_string();
+        let changed: Vec<&str> = rendered
+            .lines()
+            .skip(1)
+            .filter(|l| !l.starts_with(' '))
+            .collect();
+        assert_eq!(changed.len(), 3, "{}", rendered);
+        assert_eq!(changed.iter().filter(|l| l.starts_with('-')).count(), 1);
+        assert_eq!(changed.iter().filter(|l| l.starts_with('+')).count(), 2);
+    }
+
+    #[test]
+    fn discover_skips_files_without_directives() {
+        let dir = std::env::temp_dir().join(format!("bm-discover-{}", std::process::id()));
+        let files = [
+            ("a.basm", "# expect-stack: 1\n%include \"lib/util.basm\"\n"),
+            ("b.basm", "# expect-stack: x\n"),
+            ("notes.txt", "# expect-stack: 1\n"),
+            ("lib/util.basm", "# shared code\npush 1\n"),
+            ("sub/c.basm", "# stdin: 1\nread_int\n"),
+        ];
+        for (name, source) in files {
+            let path = dir.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, source).unwrap();
+        }
+        assert_eq!(
+            discover(&dir).unwrap(),
+            vec![
+                dir.join("a.basm"),
+                dir.join("b.basm"),
+                dir.join("sub/c.basm")
+            ]
+        );
+        // A file given directly is always run
+        assert_eq!(
+            discover(dir.join("lib/util.basm")).unwrap(),
+            vec![dir.join("lib/util.basm")]
+        );
+        assert!(TestRunner::new().run_file(dir.join("a.basm")).passed());
+    }
+}
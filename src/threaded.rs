@@ -0,0 +1,526 @@
+//! Alternative execution engine for hot loops. The program is decoded once into a
+//! compact form with resolved jump targets, and the stack checks of every instruction
+//! whose stack depth the verifier proves are dropped. Instructions without a fast form,
+//! and those whose checks fail, go through the reference interpreter so the results and
+//! errors are the same as with `BM::execute_program`.
+
+use crate::interpreter::InterpreterErr;
+use crate::{Error, Instruction, LoadErr, Word, BM};
+
+/// Decoded form of an instruction.
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Nop,
+    Push(Word),
+    Dup(usize),
+    Swap(usize),
+    Drop,
+    Over,
+    Rot,
+    Plus,
+    Minus,
+    Mult,
+    Div,
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    And,
+    Or,
+    Xor,
+    Not,
+    Jump(Word),
+    JumpIf(Word),
+    JumpZero(Word),
+    Call(Word),
+    Ret,
+    Halt,
+    /// Executed by the reference interpreter
+    Interpret,
+}
+
+/// A decoded instruction along with the stack checks it still needs.
+#[derive(Debug, Clone, Copy)]
+struct Step {
+    op: Op,
+    /// Whether the stack has to be checked before executing the step
+    guarded: bool,
+    /// Elements the step reads
+    need: usize,
+    /// Elements the step adds
+    grow: usize,
+}
+
+/// Stands for instructions that can not run in their fast form.
+const INTERPRET: Step = Step {
+    op: Op::Interpret,
+    guarded: false,
+    need: 0,
+    grow: 0,
+};
+
+/// A program decoded for `BM::execute_compiled`.
+#[derive(Debug, Clone)]
+pub struct Compiled {
+    steps: Vec<Step>,
+    /// Program the code was compiled from
+    program: Vec<Instruction>,
+    /// Stack depth before every instruction proven by the verifier
+    depths: Vec<Option<i64>>,
+}
+
+impl Compiled {
+    /// Number of instructions executed without any stack check.
+    pub fn unchecked(&self) -> usize {
+        self.steps
+            .iter()
+            .filter(|s| !s.guarded && !matches!(s.op, Op::Interpret))
+            .count()
+    }
+}
+
+/// Stack elements read and added by the fast form of an instruction.
+fn stack_effect(op: Op) -> (usize, usize) {
+    match op {
+        Op::Push(_) => (0, 1),
+        Op::Dup(n) => (n + 1, 1),
+        Op::Swap(n) => (n + 1, 0),
+        Op::Drop | Op::Not | Op::JumpIf(_) | Op::JumpZero(_) => (1, 0),
+        Op::Over => (2, 1),
+        Op::Rot => (3, 0),
+        Op::Plus
+        | Op::Minus
+        | Op::Mult
+        | Op::Div
+        | Op::Eq
+        | Op::Ne
+        | Op::Lt
+        | Op::Gt
+        | Op::Le
+        | Op::Ge
+        | Op::And
+        | Op::Or
+        | Op::Xor => (2, 0),
+        Op::Nop | Op::Jump(_) | Op::Call(_) | Op::Ret | Op::Halt | Op::Interpret => (0, 0),
+    }
+}
+
+fn decode(inst: &Instruction) -> Op {
+    let target = |addr: &Option<Word>, op: fn(Word) -> Op| match addr {
+        Some(addr) if *addr >= 0 => op(*addr),
+        // Failures are left to the interpreter
+        _ => Op::Interpret,
+    };
+    match inst {
+        Instruction::Nop => Op::Nop,
+        Instruction::Push(w) => Op::Push(*w),
+        Instruction::Dup(n) if *n >= 0 => Op::Dup(*n as usize),
+        Instruction::Swap(n) if *n >= 1 => Op::Swap(*n as usize),
+        Instruction::Drop => Op::Drop,
+        Instruction::Over => Op::Over,
+        Instruction::Rot => Op::Rot,
+        Instruction::Plus => Op::Plus,
+        Instruction::Minus => Op::Minus,
+        Instruction::Mult => Op::Mult,
+        Instruction::Div => Op::Div,
+        Instruction::Eq => Op::Eq,
+        Instruction::Ne => Op::Ne,
+        Instruction::Lt => Op::Lt,
+        Instruction::Gt => Op::Gt,
+        Instruction::Le => Op::Le,
+        Instruction::Ge => Op::Ge,
+        Instruction::And => Op::And,
+        Instruction::Or => Op::Or,
+        Instruction::Xor => Op::Xor,
+        Instruction::Not => Op::Not,
+        Instruction::Jump(addr) => target(addr, Op::Jump),
+        Instruction::JumpIf(addr) => target(addr, Op::JumpIf),
+        Instruction::JumpZero(addr) => target(addr, Op::JumpZero),
+        Instruction::Call(addr) => target(addr, Op::Call),
+        Instruction::Ret => Op::Ret,
+        Instruction::Halt => Op::Halt,
+        _ => Op::Interpret,
+    }
+}
+
+/// Replace the top 2 elements by `f(second, top)`.
+#[inline(always)]
+fn binary(stack: &mut Vec<Word>, f: fn(Word, Word) -> Word) {
+    let top = stack.pop().unwrap_or_default();
+    if let Some(second) = stack.last_mut() {
+        *second = f(*second, top);
+    }
+}
+
+impl BM {
+    /// Decode the loaded program for `BM::execute_compiled`. The program has to pass
+    /// verification; stack checks are only dropped if no native or recursive call makes
+    /// part of it unverifiable.
+    /// ```
+    /// use bm::{Instruction, BM};
+    /// let mut bm: BM = Default::default();
+    /// bm.load_program_from_memory(&[
+    ///     Instruction::Push(0),
+    ///     Instruction::Push(1),
+    ///     Instruction::Plus,
+    ///     Instruction::Dup(0),
+    ///     Instruction::Push(100),
+    ///     Instruction::Lt,
+    ///     Instruction::JumpIf(Some(1)),
+    ///     Instruction::Halt,
+    /// ])
+    /// .unwrap();
+    /// let code = bm.compile().unwrap();
+    /// bm.execute_compiled(&code, None).unwrap();
+    /// assert_eq!(bm.stack(), &[100]);
+    /// ```
+    pub fn compile(&self) -> Result<Compiled, Error> {
+        let report = self.verify();
+        if !report.is_ok() {
+            return Err(Error::Verify(report.errors));
+        }
+        let trusted = report.unverified.is_empty();
+        let steps = self
+            .program
+            .iter()
+            .zip(&report.depths)
+            .map(|(inst, depth)| {
+                let op = decode(inst);
+                let (need, grow) = stack_effect(op);
+                Step {
+                    op,
+                    // Only instructions of the main program have a known depth
+                    guarded: !(trusted && depth.is_some()) && (need, grow) != (0, 0),
+                    need,
+                    grow,
+                }
+            })
+            .collect();
+        Ok(Compiled {
+            steps,
+            program: self.program.clone(),
+            depths: report.depths,
+        })
+    }
+
+    /// Execute the program compiled by `BM::compile`, with the same results as
+    /// `BM::execute_program`. Accepts `limit` as the number of max instructions to be
+    /// executed, like `BM::execute_program`.
+    ///
+    /// Stack checks are only dropped when the execution starts in a state the
+    /// verification covers, i.e. outside of any call with the stack depth it proved for
+    /// the current instruction. The program runs on the reference interpreter otherwise.
+    pub fn execute_compiled(&mut self, code: &Compiled, limit: Option<usize>) -> Result<(), Error> {
+        if code.program != self.program {
+            return Err(LoadErr::CodeMismatch.into());
+        }
+        let verified = usize::try_from(self.ip)
+            .ok()
+            .and_then(|ip| code.depths.get(ip).copied().flatten());
+        if !self.call_stack.is_empty() || verified != Some(self.stack.len() as i64) {
+            return self.execute_program(limit);
+        }
+        if self.halt {
+            return Ok(());
+        }
+
+        // `execute_program` runs `limit - 1` instructions
+        let steps = limit.map_or(usize::MAX, |l| l.saturating_sub(1));
+        let budget = self
+            .config
+            .instruction_budget
+            .map_or(usize::MAX, |b| b.saturating_sub(self.executed));
+        let fuel = steps.min(budget);
+        let (executed, result) = self.run_steps(&code.steps, fuel);
+        self.executed += executed;
+        result?;
+        if executed == fuel && !self.halt && budget < steps {
+            return Err(InterpreterErr::BudgetExhausted.into());
+        }
+        Ok(())
+    }
+
+    /// Execute up to `fuel` steps, returning how many succeeded and the error of the
+    /// failing one if any.
+    fn run_steps(&mut self, steps: &[Step], fuel: usize) -> (usize, Result<(), InterpreterErr>) {
+        let capacity = self.config.stack_capacity;
+        let mut ip = self.ip;
+        let mut executed = 0;
+        while executed < fuel {
+            let step = match steps.get(ip as usize) {
+                Some(step)
+                    if !step.guarded
+                        || (self.stack.len() >= step.need
+                            && self.stack.len() + step.grow <= capacity) =>
+                {
+                    step
+                }
+                // Out of the program or failing checks
+                _ => &INTERPRET,
+            };
+            let stack = &mut self.stack;
+            match step.op {
+                Op::Nop => ip += 1,
+                Op::Push(w) => {
+                    stack.push(w);
+                    ip += 1;
+                }
+                Op::Dup(n) => {
+                    stack.push(stack[stack.len() - 1 - n]);
+                    ip += 1;
+                }
+                Op::Swap(n) => {
+                    let len = stack.len();
+                    stack.swap(len - 1, len - 1 - n);
+                    ip += 1;
+                }
+                Op::Drop => {
+                    stack.pop();
+                    ip += 1;
+                }
+                Op::Over => {
+                    stack.push(stack[stack.len() - 2]);
+                    ip += 1;
+                }
+                Op::Rot => {
+                    let len = stack.len();
+                    stack[len - 3..].rotate_left(1);
+                    ip += 1;
+                }
+                Op::Plus => {
+                    binary(stack, Word::wrapping_add);
+                    ip += 1;
+                }
+                Op::Minus => {
+                    binary(stack, Word::wrapping_sub);
+                    ip += 1;
+                }
+                Op::Mult => {
+                    binary(stack, Word::wrapping_mul);
+                    ip += 1;
+                }
+                Op::Div if stack[stack.len() - 1] != 0 => {
+                    binary(stack, Word::wrapping_div);
+                    ip += 1;
+                }
+                Op::Eq => {
+                    binary(stack, |a, b| (a == b) as Word);
+                    ip += 1;
+                }
+                Op::Ne => {
+                    binary(stack, |a, b| (a != b) as Word);
+                    ip += 1;
+                }
+                Op::Lt => {
+                    binary(stack, |a, b| (a < b) as Word);
+                    ip += 1;
+                }
+                Op::Gt => {
+                    binary(stack, |a, b| (a > b) as Word);
+                    ip += 1;
+                }
+                Op::Le => {
+                    binary(stack, |a, b| (a <= b) as Word);
+                    ip += 1;
+                }
+                Op::Ge => {
+                    binary(stack, |a, b| (a >= b) as Word);
+                    ip += 1;
+                }
+                Op::And => {
+                    binary(stack, |a, b| (a != 0 && b != 0) as Word);
+                    ip += 1;
+                }
+                Op::Or => {
+                    binary(stack, |a, b| (a != 0 || b != 0) as Word);
+                    ip += 1;
+                }
+                Op::Xor => {
+                    binary(stack, |a, b| ((a != 0) != (b != 0)) as Word);
+                    ip += 1;
+                }
+                Op::Not => {
+                    let len = stack.len();
+                    stack[len - 1] = (stack[len - 1] == 0) as Word;
+                    ip += 1;
+                }
+                Op::Jump(addr) => ip = addr,
+                Op::JumpIf(addr) => {
+                    ip = if stack.pop() != Some(0) { addr } else { ip + 1 };
+                }
+                Op::JumpZero(addr) => {
+                    ip = if stack.pop() == Some(0) { addr } else { ip + 1 };
+                }
+                Op::Call(addr) if self.call_stack.len() < self.config.call_depth => {
+                    self.call_stack.push(ip + 1);
+                    ip = addr;
+                }
+                Op::Ret if !self.call_stack.is_empty() => {
+                    ip = self.call_stack.pop().unwrap_or_default();
+                }
+                Op::Halt => {
+                    self.halt = true;
+                    self.ip = ip;
+                    return (executed + 1, Ok(()));
+                }
+                // Everything else, including the failing divisions, calls and returns
+                _ => {
+                    self.ip = ip;
+                    if let Err(e) = self.interpret() {
+                        return (executed, Err(e));
+                    }
+                    ip = self.ip;
+                    if self.halt {
+                        return (executed + 1, Ok(()));
+                    }
+                }
+            }
+            executed += 1;
+        }
+        self.ip = ip;
+        (executed, Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::serialize_deserialize::BasmCtx;
+    use crate::stream::SharedBuffer;
+    use crate::{BMBuilder, BMConfig, Error, Instruction, BM};
+
+    /// Limits every program runs with, the last ones interrupting it or running out.
+    fn configs() -> Vec<BMConfig> {
+        vec![
+            BMConfig::default(),
+            BMBuilder::new().stack_capacity(3).config(),
+            BMBuilder::new().call_depth(1).config(),
+            BMBuilder::new().instruction_budget(4).config(),
+            BMBuilder::new().instruction_budget(30).config(),
+        ]
+    }
+
+    const LIMITS: [Option<usize>; 6] = [None, Some(1), Some(2), Some(4), Some(10), Some(100)];
+
+    /// A machine running `program`, with natives to shrink and grow the stack.
+    fn machine(program: &[Instruction], config: BMConfig) -> (BM, SharedBuffer) {
+        let mut bm = BM::with_config(config);
+        bm.register_native("drop", |stack| stack.pop().map(|_| ()));
+        bm.register_native("one", |stack| stack.push(1));
+        let output = SharedBuffer::default();
+        bm.set_output(output.clone());
+        bm.load_program_from_memory(program).unwrap();
+        (bm, output)
+    }
+
+    /// Run `program` on the reference interpreter and on the threaded code, twice in a
+    /// row so the second run resumes wherever `limit` interrupted the first one.
+    fn assert_engines_agree(program: &[Instruction]) {
+        for config in configs() {
+            for limit in LIMITS {
+                let (mut reference, expected_output) = machine(program, config);
+                let (mut threaded, output) = machine(program, config);
+                let code = threaded.compile().unwrap();
+                for run in 0..2 {
+                    let expected = reference.execute_program(limit);
+                    let actual = threaded.execute_compiled(&code, limit);
+                    let case = format!(
+                        "{:?}, run {} with limit {:?} and {:?}",
+                        program, run, limit, config
+                    );
+                    assert_eq!(
+                        format!("{:?}", actual),
+                        format!("{:?}", expected),
+                        "{}",
+                        case
+                    );
+                    assert_eq!(
+                        threaded.snapshot().state,
+                        reference.snapshot().state,
+                        "{}",
+                        case
+                    );
+                    assert_eq!(output.contents(), expected_output.contents(), "{}", case);
+                }
+            }
+        }
+    }
+
+    fn assemble(path: &Path) -> Result<Vec<Instruction>, Error> {
+        let mut bm: BM = Default::default();
+        bm.program_from_asm_file(path, &mut BasmCtx::default())?;
+        Ok(bm.program)
+    }
+
+    #[test]
+    fn matches_interpreter() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples");
+        let mut paths: Vec<_> = ["", "tests"]
+            .iter()
+            .flat_map(|sub| std::fs::read_dir(dir.join(sub)).unwrap())
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "basm"))
+            .collect();
+        paths.sort();
+        let mut rejected = Vec::new();
+        for path in &paths {
+            let program = assemble(path).unwrap();
+            let (bm, _) = machine(&program, BMConfig::default());
+            match bm.compile() {
+                Ok(_) => assert_engines_agree(&program),
+                // Programs the verifier rejects can only run on the interpreter
+                Err(Error::Verify(_)) => rejected.push(path.file_name().unwrap().to_owned()),
+                Err(e) => panic!("{}: {}", path.display(), e),
+            }
+        }
+        // The stack of fib grows with every iteration, underflow underflows
+        assert_eq!(rejected, ["fib.basm", "underflow.basm"]);
+    }
+
+    #[test]
+    fn matches_interpreter_on_edge_cases() {
+        use Instruction::*;
+        let programs = [
+            // Stack underflow the verifier can not see past a native
+            vec![Push(1), Native("drop".to_string()), Plus, Halt],
+            vec![Native("drop".to_string()), Halt],
+            // Stack overflow through a native and after it
+            vec![Push(1), Push(2), Native("one".to_string()), Push(3), Halt],
+            // Division by zero falls back to the interpreter
+            vec![Push(1), Push(0), Div, Halt],
+            // Loops running out of budget or limit
+            vec![Push(10), Push(1), Minus, Dup(0), JumpIf(Some(1)), Halt],
+            // Calls and returns, nested beyond the call depth
+            vec![Push(3), Call(Some(3)), Halt, Dup(0), Mult, Ret],
+            vec![
+                Push(2),
+                Call(Some(4)),
+                Call(Some(4)),
+                Halt,
+                Call(Some(6)),
+                Ret,
+                Push(1),
+                Plus,
+                Ret,
+            ],
+            // A recursive countdown, which is left unverified
+            vec![
+                Push(3),
+                Call(Some(3)),
+                Halt,
+                Push(1),
+                Minus,
+                Dup(0),
+                JumpZero(Some(8)),
+                Call(Some(3)),
+                Ret,
+            ],
+        ];
+        for program in programs {
+            assert_engines_agree(&program);
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use std::io::Write;
+
+use serde::Serialize;
+
+use crate::{Error, Word, BM};
+
+/// How trace events are written out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// One aligned line of text per step
+    Human,
+    /// One JSON object per line
+    JsonLines,
+}
+
+/// Record of a single executed instruction.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TraceEvent {
+    /// Number of the step, starting from 1
+    pub step: usize,
+    /// Address of the executed instruction
+    pub ip: Word,
+    /// The executed instruction as basm
+    pub instruction: String,
+    pub stack_before: Vec<Word>,
+    pub stack_after: Vec<Word>,
+    /// Set if executing the instruction failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Streams trace events into a Writer.
+/// Writing errors do not interrupt the traced program; the first one is kept and
+/// returned by `Tracer::finish`.
+pub struct Tracer<W> {
+    w: W,
+    format: TraceFormat,
+    error: Option<std::io::Error>,
+}
+
+impl<W> Tracer<W>
+where
+    W: Write,
+{
+    pub fn new(w: W, format: TraceFormat) -> Self {
+        Self {
+            w,
+            format,
+            error: None,
+        }
+    }
+
+    /// Write a single event.
+    pub fn record(&mut self, event: &TraceEvent) {
+        if self.error.is_some() {
+            return;
+        }
+        let result = match self.format {
+            TraceFormat::Human => {
+                let error = match &event.error {
+                    Some(e) => format!("  !! {}", e),
+                    None => String::new(),
+                };
+                writeln!(
+                    self.w,
+                    "[{:>6}] {:>5}: {:<16} {:?} -> {:?}{}",
+                    event.step,
+                    event.ip,
+                    event.instruction,
+                    event.stack_before,
+                    event.stack_after,
+                    error
+                )
+            }
+            TraceFormat::JsonLines => serde_json::to_writer(&mut self.w, event)
+                .map_err(std::io::Error::from)
+                .and_then(|_| writeln!(self.w)),
+        };
+        if let Err(e) = result {
+            self.error = Some(e);
+        }
+    }
+
+    /// Flush the underlying Writer and report the first error encountered while tracing.
+    pub fn finish(mut self) -> std::io::Result<W> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.w.flush()?;
+        Ok(self.w)
+    }
+}
+
+impl BM {
+    /// Same as `BM::execute_program` but records every executed instruction into `tracer`.
+    pub fn execute_program_traced<W>(
+        &mut self,
+        limit: Option<usize>,
+        tracer: &mut Tracer<W>,
+    ) -> Result<(), Error>
+    where
+        W: Write,
+    {
+        let mut i = 1;
+        while !self.is_halted() {
+            match limit {
+                Some(l) if l <= i => break,
+                _ => {}
+            }
+            let ip = self.ip;
+            let instruction = self
+                .current_instruction()
+                .map_or_else(|| "<outside of the program>".to_string(), |i| i.to_string());
+            let stack_before = self.stack.clone();
+            let result = self.execute_instruction();
+            tracer.record(&TraceEvent {
+                step: i,
+                ip,
+                instruction,
+                stack_before,
+                stack_after: self.stack.clone(),
+                error: result.as_ref().err().map(|e| e.to_string()),
+            });
+            result?;
+            i += 1;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,552 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+
+use crate::{Instruction, Word, BM};
+
+/// Problems found by the verifier. `addr` is the address of the offending instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyErr {
+    /// Two paths reach the same instruction with different stack depths
+    InconsistentDepth {
+        addr: Word,
+        expected: i64,
+        found: i64,
+    },
+    /// A subroutine returns with different stack depths depending on the path taken
+    InconsistentReturn {
+        addr: Word,
+        expected: i64,
+        found: i64,
+    },
+    /// The instruction needs more elements than the stack holds
+    StackUnderflow {
+        addr: Word,
+        depth: i64,
+        required: i64,
+    },
+    /// The instruction grows the stack past its capacity
+    StackOverflow {
+        addr: Word,
+        depth: i64,
+        capacity: usize,
+    },
+    /// The instruction jumps or calls outside of the program
+    JumpOutOfRange { addr: Word, target: Word },
+    /// The execution runs past the last instruction
+    FallsOffEnd { addr: Word },
+    /// The address operand was never resolved to a number
+    UnresolvedAddress { addr: Word },
+    /// `ret` is reachable without any call in progress
+    ReturnOutsideCall { addr: Word },
+    /// The operand can never be valid
+    IllegalOperand { addr: Word },
+}
+
+impl Display for VerifyErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyErr::InconsistentDepth {
+                addr,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{}: stack depth {} differs from depth {} on another path",
+                addr, found, expected
+            ),
+            VerifyErr::InconsistentReturn {
+                addr,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{}: subroutine returns with stack effect {} instead of {}",
+                addr, found, expected
+            ),
+            VerifyErr::StackUnderflow {
+                addr,
+                depth,
+                required,
+            } => write!(
+                f,
+                "{}: needs {} element(s) but the stack holds {}",
+                addr, required, depth
+            ),
+            VerifyErr::StackOverflow {
+                addr,
+                depth,
+                capacity,
+            } => write!(
+                f,
+                "{}: stack depth {} exceeds the capacity of {}",
+                addr, depth, capacity
+            ),
+            VerifyErr::JumpOutOfRange { addr, target } => {
+                write!(f, "{}: target {} is outside of the program", addr, target)
+            }
+            VerifyErr::FallsOffEnd { addr } => {
+                write!(f, "{}: execution runs past the end of the program", addr)
+            }
+            VerifyErr::UnresolvedAddress { addr } => write!(f, "{}: unresolved address", addr),
+            VerifyErr::ReturnOutsideCall { addr } => {
+                write!(f, "{}: return without a call in progress", addr)
+            }
+            VerifyErr::IllegalOperand { addr } => write!(f, "{}: illegal operand", addr),
+        }
+    }
+}
+
+/// Result of verifying a program.
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    /// Stack depth before every instruction reachable from the entry point,
+    /// `None` for the unreachable ones.
+    pub depths: Vec<Option<i64>>,
+    pub errors: Vec<VerifyErr>,
+    /// Instructions whose stack effect can not be known statically (native calls
+    /// and recursive calls). Paths going through them are not verified further.
+    pub unverified: Vec<Word>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// Stack effect of a subroutine, relative to the depth at which it is called.
+#[derive(Debug, Clone, Copy)]
+struct Summary {
+    /// Number of elements of the caller the subroutine consumes
+    required: i64,
+    /// Highest depth reached
+    max: i64,
+    /// Depth change once it returns; `None` if it never does
+    delta: Option<i64>,
+}
+
+/// How an instruction uses the stack and where the execution goes next.
+struct Effect {
+    required: i64,
+    /// Successors along with the depth change on each edge
+    next: Vec<(Word, i64)>,
+}
+
+struct Verifier<'a> {
+    program: &'a [Instruction],
+    stack_capacity: usize,
+    /// Summaries of the subroutines; `None` while one is being analysed
+    summaries: HashMap<Word, Option<Summary>>,
+    errors: Vec<VerifyErr>,
+    unverified: Vec<Word>,
+}
+
+impl<'a> Verifier<'a> {
+    fn in_range(&self, target: Word) -> bool {
+        target >= 0 && (target as usize) < self.program.len()
+    }
+
+    fn error(&mut self, e: VerifyErr) {
+        if !self.errors.contains(&e) {
+            self.errors.push(e);
+        }
+    }
+
+    /// Stack usage of a plain (non call) instruction; `None` stops the path.
+    fn effect(&mut self, addr: Word) -> Option<Effect> {
+        let next = addr + 1;
+        let simple = |required, delta| Effect {
+            required,
+            next: vec![(next, delta)],
+        };
+        let effect = match &self.program[addr as usize] {
+            Instruction::Nop => simple(0, 0),
+            Instruction::Push(_) => simple(0, 1),
+            Instruction::Dup(n) => {
+                if *n < 0 {
+                    self.error(VerifyErr::IllegalOperand { addr });
+                    return None;
+                }
+                simple(n + 1, 1)
+            }
+            Instruction::Swap(n) => {
+                if *n < 1 {
+                    self.error(VerifyErr::IllegalOperand { addr });
+                    return None;
+                }
+                simple(n + 1, 0)
+            }
+            Instruction::Drop => simple(1, -1),
+            Instruction::Over => simple(2, 1),
+            Instruction::Rot => simple(3, 0),
+            // The index is only known at runtime, which checks it
+            Instruction::Pick => simple(1, 0),
+            Instruction::Roll => simple(1, -1),
+            Instruction::Plus
+            | Instruction::Minus
+            | Instruction::Mult
+            | Instruction::Div
+            | Instruction::Eq
+            | Instruction::Lt
+            | Instruction::Gt
+            | Instruction::Le
+            | Instruction::Ge
+            | Instruction::Ne
+            | Instruction::And
+            | Instruction::Or
+            | Instruction::Xor
+            | Instruction::FPlus
+            | Instruction::FMinus
+            | Instruction::FMult
+            | Instruction::FDiv
+            | Instruction::FEq
+            | Instruction::FLt
+            | Instruction::FGt
+            | Instruction::FLe
+            | Instruction::FGe => simple(2, -1),
+            Instruction::Not | Instruction::I2F | Instruction::F2I => simple(1, 0),
+            Instruction::Jump(Some(target)) => Effect {
+                required: 0,
+                next: vec![(*target, 0)],
+            },
+            Instruction::JumpIf(Some(target)) | Instruction::JumpZero(Some(target)) => Effect {
+                required: 1,
+                next: vec![(*target, -1), (next, -1)],
+            },
+            Instruction::Jump(None)
+            | Instruction::JumpIf(None)
+            | Instruction::JumpZero(None)
+            | Instruction::Call(None) => {
+                self.error(VerifyErr::UnresolvedAddress { addr });
+                return None;
+            }
+            Instruction::Load8
+            | Instruction::Load16
+            | Instruction::Load32
+            | Instruction::Load64 => simple(1, 0),
+            Instruction::Store8
+            | Instruction::Store16
+            | Instruction::Store32
+            | Instruction::Store64 => simple(2, -2),
+            Instruction::PrintDebug | Instruction::WriteChar | Instruction::WriteInt => {
+                simple(1, -1)
+            }
+            Instruction::ReadChar | Instruction::ReadInt => simple(0, 1),
+            Instruction::Halt => Effect {
+                required: 0,
+                next: vec![],
+            },
+            Instruction::Native(_) => {
+                self.unverified.push(addr);
+                return None;
+            }
+            Instruction::Call(Some(_)) | Instruction::Ret => {
+                unreachable!("calls are handled by the caller")
+            }
+        };
+        Some(effect)
+    }
+
+    /// Walk every path from `entry`, computing the depth before each instruction.
+    /// In the main program depths are absolute, in subroutines they are relative to the call.
+    fn walk(&mut self, entry: Word, main: bool) -> (Summary, HashMap<Word, i64>) {
+        let mut depths: HashMap<Word, i64> = HashMap::new();
+        let mut summary = Summary {
+            required: 0,
+            max: 0,
+            delta: None,
+        };
+        let mut ret_at: Option<Word> = None;
+        let mut worklist = vec![(entry, 0i64)];
+
+        while let Some((addr, depth)) = worklist.pop() {
+            if !self.in_range(addr) {
+                self.error(VerifyErr::FallsOffEnd { addr: addr - 1 });
+                continue;
+            }
+            if let Some(&expected) = depths.get(&addr) {
+                if expected != depth {
+                    self.error(VerifyErr::InconsistentDepth {
+                        addr,
+                        expected,
+                        found: depth,
+                    });
+                }
+                continue;
+            }
+            depths.insert(addr, depth);
+
+            let (required, max, next) = match &self.program[addr as usize] {
+                Instruction::Call(Some(target)) => {
+                    let target = *target;
+                    if !self.in_range(target) {
+                        self.error(VerifyErr::JumpOutOfRange { addr, target });
+                        continue;
+                    }
+                    let callee = match self.summary(target) {
+                        Some(callee) => callee,
+                        None => {
+                            self.unverified.push(addr);
+                            continue;
+                        }
+                    };
+                    let next = match callee.delta {
+                        Some(delta) => vec![(addr + 1, delta)],
+                        None => vec![],
+                    };
+                    (callee.required, callee.max, next)
+                }
+                Instruction::Ret => {
+                    if main {
+                        self.error(VerifyErr::ReturnOutsideCall { addr });
+                        continue;
+                    }
+                    match (summary.delta, ret_at) {
+                        (Some(expected), Some(_)) if expected != depth => {
+                            self.error(VerifyErr::InconsistentReturn {
+                                addr,
+                                expected,
+                                found: depth,
+                            })
+                        }
+                        _ => {
+                            summary.delta = Some(depth);
+                            ret_at = Some(addr);
+                        }
+                    }
+                    continue;
+                }
+                _ => match self.effect(addr) {
+                    Some(effect) => {
+                        let grows = effect.next.iter().map(|(_, d)| *d).max().unwrap_or(0);
+                        (effect.required, grows.max(0), effect.next)
+                    }
+                    None => continue,
+                },
+            };
+
+            if main && depth < required {
+                self.error(VerifyErr::StackUnderflow {
+                    addr,
+                    depth,
+                    required,
+                });
+                continue;
+            }
+            summary.required = summary.required.max(required - depth);
+            summary.max = summary.max.max(depth + max);
+            if main && depth + max > self.stack_capacity as i64 {
+                self.error(VerifyErr::StackOverflow {
+                    addr,
+                    depth: depth + max,
+                    capacity: self.stack_capacity,
+                });
+                continue;
+            }
+
+            for (target, delta) in next {
+                if target != addr + 1 && !self.in_range(target) {
+                    self.error(VerifyErr::JumpOutOfRange { addr, target });
+                    continue;
+                }
+                worklist.push((target, depth + delta));
+            }
+        }
+        (summary, depths)
+    }
+
+    /// Summary of the subroutine starting at `entry`; `None` for recursive calls.
+    fn summary(&mut self, entry: Word) -> Option<Summary> {
+        match self.summaries.get(&entry) {
+            Some(summary) => return *summary,
+            None => self.summaries.insert(entry, None),
+        };
+        let (summary, _) = self.walk(entry, false);
+        self.summaries.insert(entry, Some(summary));
+        Some(summary)
+    }
+}
+
+impl BM {
+    /// Statically verify the loaded program, starting from address 0 with an empty stack.
+    /// Builds the control flow graph out of jumps and calls and computes the stack depth
+    /// before every instruction.
+    /// ```
+    /// use bm::{BM, Instruction};
+    /// let mut bm: BM = Default::default();
+    /// bm.push_inst(Instruction::Push(1)).unwrap();
+    /// bm.push_inst(Instruction::Plus).unwrap();
+    /// bm.push_inst(Instruction::Halt).unwrap();
+    /// let report = bm.verify();
+    /// assert!(!report.is_ok());
+    /// ```
+    pub fn verify(&self) -> VerifyReport {
+        let mut verifier = Verifier {
+            program: &self.program,
+            stack_capacity: self.config.stack_capacity,
+            summaries: HashMap::new(),
+            errors: Vec::new(),
+            unverified: Vec::new(),
+        };
+        let mut depths = vec![None; self.program.len()];
+        if !self.program.is_empty() {
+            let (_, main_depths) = verifier.walk(0, true);
+            for (addr, depth) in main_depths {
+                depths[addr as usize] = Some(depth);
+            }
+        }
+        let mut errors = verifier.errors;
+        errors.sort_by_key(|e| match e {
+            VerifyErr::InconsistentDepth { addr, .. }
+            | VerifyErr::InconsistentReturn { addr, .. }
+            | VerifyErr::StackUnderflow { addr, .. }
+            | VerifyErr::StackOverflow { addr, .. }
+            | VerifyErr::JumpOutOfRange { addr, .. }
+            | VerifyErr::FallsOffEnd { addr }
+            | VerifyErr::UnresolvedAddress { addr }
+            | VerifyErr::ReturnOutsideCall { addr }
+            | VerifyErr::IllegalOperand { addr } => *addr,
+        });
+        let mut unverified = verifier.unverified;
+        unverified.sort();
+        unverified.dedup();
+        VerifyReport {
+            depths,
+            errors,
+            unverified,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Instruction::*;
+    use crate::BM_STACK_CAPACITY;
+
+    fn verify(program: &[Instruction]) -> VerifyReport {
+        let mut bm: BM = Default::default();
+        bm.load_program_from_memory(program).unwrap();
+        bm.verify()
+    }
+
+    #[test]
+    fn straight_line_depths() {
+        let report = verify(&[Push(1), Push(2), Plus, PrintDebug, Halt]);
+        assert!(report.is_ok());
+        assert_eq!(
+            report.depths,
+            vec![Some(0), Some(1), Some(2), Some(1), Some(0)]
+        );
+    }
+
+    #[test]
+    fn underflow() {
+        let report = verify(&[Push(1), Plus, Halt]);
+        assert_eq!(
+            report.errors,
+            vec![VerifyErr::StackUnderflow {
+                addr: 1,
+                depth: 1,
+                required: 2
+            }]
+        );
+    }
+
+    #[test]
+    fn overflow() {
+        // Every call to the subroutine at 12 leaves 100 more elements
+        let mut program = vec![Call(Some(12)); 11];
+        program.push(Halt);
+        program.extend(std::iter::repeat_n(Push(0), 100));
+        program.push(Ret);
+        let report = verify(&program);
+        assert_eq!(
+            report.errors,
+            vec![VerifyErr::StackOverflow {
+                addr: 10,
+                depth: 1100,
+                capacity: BM_STACK_CAPACITY
+            }]
+        );
+    }
+
+    #[test]
+    fn join_point_depth_mismatch() {
+        // The taken branch reaches 4 with an empty stack, the fall through does not
+        let report = verify(&[Push(1), JumpIf(Some(4)), Push(2), Push(3), Halt]);
+        assert_eq!(report.errors.len(), 1, "{:?}", report.errors);
+        assert!(matches!(
+            report.errors[0],
+            VerifyErr::InconsistentDepth {
+                addr: 4,
+                found: 0,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn loop_with_balanced_depth() {
+        let report = verify(&[Push(3), Push(1), Minus, Dup(0), JumpIf(Some(1)), Halt]);
+        assert!(report.is_ok(), "{:?}", report.errors);
+        assert_eq!(report.depths[1], Some(1));
+    }
+
+    #[test]
+    fn subroutine_summary_applies_at_the_call() {
+        // The subroutine at 4 consumes two elements and leaves one
+        let report = verify(&[Push(1), Push(2), Call(Some(4)), Halt, Plus, Ret]);
+        assert!(report.is_ok(), "{:?}", report.errors);
+        assert_eq!(report.depths[3], Some(1));
+        // Subroutine bodies are not part of the main program depths
+        assert_eq!(report.depths[4], None);
+    }
+
+    #[test]
+    fn subroutine_requirements_underflow_the_caller() {
+        let report = verify(&[Push(1), Call(Some(3)), Halt, Plus, Ret]);
+        assert_eq!(
+            report.errors,
+            vec![VerifyErr::StackUnderflow {
+                addr: 1,
+                depth: 1,
+                required: 2
+            }]
+        );
+    }
+
+    #[test]
+    fn inconsistent_return() {
+        // Returns without the argument when it is not zero, with one more element otherwise
+        let report = verify(&[
+            Push(0),
+            Call(Some(3)),
+            Halt,
+            JumpIf(Some(6)),
+            Push(1),
+            Ret,
+            Ret,
+        ]);
+        assert!(report
+            .errors
+            .iter()
+            .any(|e| matches!(e, VerifyErr::InconsistentReturn { .. })));
+    }
+
+    #[test]
+    fn recursive_calls_are_unverified() {
+        let report = verify(&[Call(Some(2)), Halt, Call(Some(2)), Ret]);
+        assert!(report.is_ok(), "{:?}", report.errors);
+        assert_eq!(report.unverified, vec![2]);
+    }
+
+    #[test]
+    fn return_outside_call() {
+        let report = verify(&[Ret]);
+        assert_eq!(
+            report.errors,
+            vec![VerifyErr::ReturnOutsideCall { addr: 0 }]
+        );
+    }
+}